@@ -0,0 +1,343 @@
+/*
+ANN index section of an SST
+
+A flush or a compaction of a collection with an HNSW or IVF index builds the same kind
+of index over the live records of every file it writes, so that searching flushed data
+does not degrade to scoring every vector. Nodes and list entries are rows of the file:
+vectors are read from its vector section and never copied into the index.
+
+- HNSW: the adjacency lists of every layer, built by inserting the live rows in order.
+- IVF: k-means centroids and the rows assigned to each of them.
+*/
+
+use crate::collection::{DistanceType, IndexConfig, IndexType};
+use crate::document::Document;
+use crate::hnsw::{Candidate, HnswGraph, HnswParams, LayeredGraph};
+use crate::ivf::{
+    IvfParams, closest_centroid, closest_centroids, effective_nlist, train_centroids,
+};
+use crate::search::TopK;
+use crate::table::VectorColumns;
+use rand::seq::index::sample;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Which index the SSTs of a collection carry, following its `IndexConfig`.
+#[derive(Debug, Clone)]
+pub enum AnnConfig {
+    Hnsw(HnswParams, DistanceType),
+    Ivf(IvfParams, DistanceType),
+}
+
+impl AnnConfig {
+    /// `None` for flat collections, their SSTs are scanned.
+    pub fn new(index_config: &IndexConfig, distance: &DistanceType) -> Option<Self> {
+        // Params are validated when the IndexConfig is built, fall back to defaults just in case
+        match index_config.index_type() {
+            IndexType::Flat => None,
+            IndexType::HNSW => Some(AnnConfig::Hnsw(
+                HnswParams::from_params(&index_config.params).unwrap_or_default(),
+                distance.clone(),
+            )),
+            IndexType::IVF => Some(AnnConfig::Ivf(
+                IvfParams::from_params(&index_config.params).unwrap_or_default(),
+                distance.clone(),
+            )),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum AnnIndex {
+    Hnsw(HnswSection),
+    Ivf(IvfSection),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HnswSection {
+    ef_search: u32,
+    entry_point: u32,
+    neighbors: Vec<Vec<Vec<u32>>>, // row -> layer -> rows, empty for tombstones
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IvfSection {
+    nprobe: u32,
+    centroids: Vec<Vec<f32>>,
+    lists: Vec<Vec<u32>>, // rows assigned to each centroid
+}
+
+impl AnnIndex {
+    /// Index over `live`, the live documents of a file and their rows. `None` when there
+    /// is nothing to index.
+    pub fn build(config: &AnnConfig, rows: usize, live: &[(u32, Arc<Document>)]) -> Option<Self> {
+        if live.is_empty() {
+            return None;
+        }
+
+        match config {
+            AnnConfig::Hnsw(params, distance) => {
+                let mut graph = HnswGraph::new(params.clone(), distance.clone());
+                for (_, doc) in live.iter() {
+                    graph.insert(doc.clone());
+                }
+
+                // Graph nodes are the live documents in insertion order
+                let mut neighbors = vec![Vec::new(); rows];
+                for (node, (row, _)) in live.iter().enumerate() {
+                    neighbors[*row as usize] = graph
+                        .node_layers(node)
+                        .iter()
+                        .map(|layer| layer.iter().map(|n| live[*n].0).collect())
+                        .collect();
+                }
+                Some(AnnIndex::Hnsw(HnswSection {
+                    ef_search: params.ef_search as u32,
+                    entry_point: live[graph.entry_point()?].0,
+                    neighbors,
+                }))
+            }
+            AnnConfig::Ivf(params, distance) => {
+                let nlist = effective_nlist(params.nlist, live.len());
+                let training = sample(
+                    &mut rand::rng(),
+                    live.len(),
+                    params.training_size.min(live.len()),
+                );
+                let vectors: Vec<&[f32]> = training
+                    .into_iter()
+                    .map(|i| live[i].1.vector.as_slice())
+                    .collect();
+                let centroids = train_centroids(&vectors, nlist, distance);
+
+                let mut lists = vec![Vec::new(); centroids.len()];
+                for (row, doc) in live.iter() {
+                    lists[closest_centroid(&centroids, &doc.vector, distance)].push(*row);
+                }
+                Some(AnnIndex::Ivf(IvfSection {
+                    nprobe: params.nprobe.min(centroids.len()) as u32,
+                    centroids,
+                    lists,
+                }))
+            }
+        }
+    }
+
+    /// Up to `top_k` rows closest to `query` among the ones `accept` lets through, as
+    /// (distance, row) closest first. Rejected rows do not count towards `top_k`: the
+    /// search widens until enough rows are accepted or the whole index has been visited.
+    pub fn search(
+        &self,
+        columns: &VectorColumns,
+        distance: &DistanceType,
+        query: &[f32],
+        top_k: usize,
+        accept: impl Fn(usize) -> bool,
+    ) -> Vec<(f32, usize)> {
+        match self {
+            AnnIndex::Hnsw(section) => HnswView {
+                section,
+                columns,
+                distance,
+            }
+            .search(query, top_k, accept),
+            AnnIndex::Ivf(section) => {
+                let mut top = TopK::new(top_k);
+                let lists =
+                    closest_centroids(&section.centroids, query, section.lists.len(), distance);
+
+                // Probe nprobe lists, then twice as many, until top_k rows are accepted
+                let (mut probed, mut nprobe, mut accepted) = (0, section.nprobe.max(1) as usize, 0);
+                while probed < lists.len() && accepted < top_k {
+                    let end = nprobe.min(lists.len());
+                    for list in lists[probed..end].iter() {
+                        for row in section.lists[*list].iter().map(|row| *row as usize) {
+                            let score = distance.distance(query, columns.vector(row));
+                            if score < top.threshold() && accept(row) {
+                                top.push(score, row);
+                                accepted += 1;
+                            }
+                        }
+                    }
+                    probed = end;
+                    nprobe *= 2;
+                }
+                top.into_sorted_vec()
+            }
+        }
+    }
+}
+
+/// An HNSW section walked over the vectors of its file.
+struct HnswView<'a> {
+    section: &'a HnswSection,
+    columns: &'a VectorColumns<'a>,
+    distance: &'a DistanceType,
+}
+
+impl HnswView<'_> {
+    fn search(
+        &self,
+        query: &[f32],
+        top_k: usize,
+        accept: impl Fn(usize) -> bool,
+    ) -> Vec<(f32, usize)> {
+        let entry_point = self.section.entry_point as usize;
+        let Some(max_layer) = self.section.neighbors[entry_point].len().checked_sub(1) else {
+            return Vec::new();
+        };
+        if top_k == 0 {
+            return Vec::new();
+        }
+
+        let mut entry = Candidate {
+            distance: self.node_distance(query, entry_point),
+            node: entry_point,
+        };
+        for lc in (1..=max_layer).rev() {
+            entry = self.greedy_closest(query, entry, lc);
+        }
+
+        // Rejected rows occupy slots in the beam like deleted nodes do in memory, widen
+        // it until enough accepted ones show up
+        let mut ef = (self.section.ef_search as usize).max(top_k);
+        loop {
+            let result: Vec<(f32, usize)> = self
+                .search_layer(query, &[entry], ef, 0)
+                .into_iter()
+                .filter(|c| accept(c.node))
+                .take(top_k)
+                .map(|c| (c.distance, c.node))
+                .collect();
+
+            if result.len() >= top_k || ef >= self.columns.len() {
+                return result;
+            }
+            ef *= 2;
+        }
+    }
+}
+
+impl LayeredGraph for HnswView<'_> {
+    fn neighbors(&self, node: usize, layer: usize) -> impl Iterator<Item = usize> + '_ {
+        self.section.neighbors[node][layer]
+            .iter()
+            .map(|row| *row as usize)
+    }
+
+    fn node_distance(&self, query: &[f32], node: usize) -> f32 {
+        self.distance.distance(query, self.columns.vector(node))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constant::DEFAULT_MEMTABLE_SIZE;
+    use crate::ivf::TrainedCentroids;
+    use crate::memtable::get_memtable;
+    use crate::sst::SSTManager;
+    use crate::test_utils::bulk_random_documents;
+    use std::collections::HashMap;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_flushed_sections_find_the_nearest_rows() {
+        let mut ivf_params = HashMap::new();
+        ivf_params.insert("nlist".to_string(), "8".to_string());
+        ivf_params.insert("nprobe".to_string(), "8".to_string());
+        let configs = [
+            (IndexConfig::new_with_default_config("flat").unwrap(), 1.0),
+            (IndexConfig::new_with_default_config("hnsw").unwrap(), 0.9),
+            // Probing every list is an exact search
+            (IndexConfig::new("ivf", ivf_params).unwrap(), 1.0),
+        ];
+        let distance = DistanceType::L2;
+        let docs = bulk_random_documents(8, 500);
+
+        for (seq_no, (config, min_recall)) in configs.iter().enumerate() {
+            let dir = tempdir().expect("Failed to create temp dir");
+            let sst_manager = SSTManager::new(dir.path().to_path_buf());
+            let mut memtable = get_memtable(
+                config,
+                &distance,
+                DEFAULT_MEMTABLE_SIZE,
+                &TrainedCentroids::default(),
+            );
+            for doc in docs.iter() {
+                memtable.upsert(doc.clone());
+            }
+            memtable.delete(&docs[0].id);
+            let metadata = sst_manager
+                .write_memtable("test_collection", seq_no as u64, 0, memtable.as_ref())
+                .unwrap();
+
+            let table = sst_manager.table(&metadata).unwrap();
+            let Some(ann) = table.ann_index().unwrap() else {
+                assert!(matches!(config.index_type(), IndexType::Flat));
+                continue;
+            };
+            let columns = table.vector_columns().unwrap();
+            let live = |row: usize| !columns.is_tombstone(row);
+
+            let mut hits = 0;
+            for query in docs.iter().skip(1).take(20) {
+                let mut exact: Vec<(f32, usize)> = (0..columns.len())
+                    .filter(|row| live(*row))
+                    .map(|row| (distance.distance(&query.vector, columns.vector(row)), row))
+                    .collect();
+                exact.sort_by(|a, b| a.0.total_cmp(&b.0));
+                exact.truncate(10);
+
+                let result = ann.search(&columns, &distance, &query.vector, 10, live);
+                assert_eq!(result.len(), 10);
+                assert!(result.windows(2).all(|w| w[0].0 <= w[1].0));
+                hits += result.iter().filter(|r| exact.contains(r)).count();
+
+                // Rejected rows are never returned, the search widens past them
+                let even = ann.search(&columns, &distance, &query.vector, 10, |row| {
+                    live(row) && row % 2 == 0
+                });
+                assert_eq!(even.len(), 10);
+                assert!(even.iter().all(|(_, row)| row % 2 == 0));
+            }
+            let recall = hits as f64 / 200.0;
+            assert!(recall >= *min_recall, "recall too low: {}", recall);
+        }
+    }
+
+    #[test]
+    fn test_ivf_probes_more_lists_when_rows_are_rejected() {
+        let mut params = HashMap::new();
+        params.insert("nlist".to_string(), "8".to_string());
+        params.insert("nprobe".to_string(), "1".to_string());
+        let config = IndexConfig::new("ivf", params).unwrap();
+        let distance = DistanceType::L2;
+        let docs = bulk_random_documents(8, 500);
+
+        let dir = tempdir().expect("Failed to create temp dir");
+        let sst_manager = SSTManager::new(dir.path().to_path_buf());
+        let mut memtable = get_memtable(
+            &config,
+            &distance,
+            DEFAULT_MEMTABLE_SIZE,
+            &TrainedCentroids::default(),
+        );
+        for doc in docs.iter() {
+            memtable.upsert(doc.clone());
+        }
+        let metadata = sst_manager
+            .write_memtable("test_collection", 0, 0, memtable.as_ref())
+            .unwrap();
+        let table = sst_manager.table(&metadata).unwrap();
+        let ann = table.ann_index().unwrap().unwrap();
+        let columns = table.vector_columns().unwrap();
+
+        // 20 rows are accepted, spread over more lists than the single one probed
+        for query in docs.iter().take(20) {
+            let result = ann.search(&columns, &distance, &query.vector, 10, |row| row % 25 == 0);
+            assert_eq!(result.len(), 10);
+            assert!(result.iter().all(|(_, row)| row % 25 == 0));
+        }
+    }
+}
@@ -0,0 +1,386 @@
+/*
+Block cache
+
+Decoded SST blocks shared by every collection of a database, within the byte budget of
+`DatabaseOptions::block_cache_size`:
+- index: the block index, bloom filter and id column of a file, and its ANN index
+- documents: the records of one data block, checked and decoded
+- vectors: the vector columns of a file older than v3, which have to be decoded. Newer
+  files are searched in place from their mapping.
+
+The cache is split in shards, each an LRU over its share of the budget, so that readers
+of different blocks rarely wait on each other. Index blocks of pinned collections count
+against the budget but are never evicted.
+*/
+
+use crate::constant::BLOCK_CACHE_SHARDS;
+use crate::sst::SSTError;
+use std::any::Any;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum BlockKind {
+    Index,
+    Documents,
+    Vectors,
+}
+
+/// A block of an open table, `table` is unique per opened file so a rewritten file never
+/// sees the blocks of its predecessor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct BlockKey {
+    pub(crate) table: u64,
+    pub(crate) kind: BlockKind,
+    pub(crate) offset: u64,
+}
+
+struct Entry {
+    block: Arc<dyn Any + Send + Sync>,
+    charge: usize,
+    collection: Arc<str>,
+    last_used: Option<u64>, // key of the entry in the LRU order, None while pinned
+}
+
+#[derive(Default)]
+struct Shard {
+    entries: HashMap<BlockKey, Entry>,
+    lru: BTreeMap<u64, BlockKey>, // unpinned entries by last use
+    tick: u64,
+    usage: usize,
+    pinned_usage: usize,
+}
+
+impl Shard {
+    fn get(&mut self, key: &BlockKey) -> Option<Arc<dyn Any + Send + Sync>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.get_mut(key)?;
+        // Pinned entries are not in the LRU order
+        if let Some(last_used) = entry.last_used {
+            self.lru.remove(&last_used);
+            self.lru.insert(tick, *key);
+            entry.last_used = Some(tick);
+        }
+        Some(entry.block.clone())
+    }
+
+    fn insert(&mut self, key: BlockKey, mut entry: Entry, pinned: bool, capacity: usize) {
+        self.remove(&key);
+        if !pinned && entry.charge > capacity {
+            return;
+        }
+
+        self.usage += entry.charge;
+        if pinned {
+            self.pinned_usage += entry.charge;
+        } else {
+            self.tick += 1;
+            entry.last_used = Some(self.tick);
+            self.lru.insert(self.tick, key);
+        }
+        self.entries.insert(key, entry);
+        self.evict(capacity);
+    }
+
+    fn remove(&mut self, key: &BlockKey) {
+        let Some(entry) = self.entries.remove(key) else {
+            return;
+        };
+        self.usage -= entry.charge;
+        match entry.last_used {
+            Some(last_used) => {
+                self.lru.remove(&last_used);
+            }
+            None => self.pinned_usage -= entry.charge,
+        }
+    }
+
+    /// Drops the least recently used unpinned entries until the shard fits `capacity`.
+    fn evict(&mut self, capacity: usize) {
+        while self.usage > capacity {
+            let Some((_, key)) = self.lru.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&key) {
+                self.usage -= entry.charge;
+            }
+        }
+    }
+
+    fn set_pinned(&mut self, collection: &str, pinned: bool) {
+        for (key, entry) in self.entries.iter_mut() {
+            if key.kind != BlockKind::Index || &*entry.collection != collection {
+                continue;
+            }
+            match (entry.last_used, pinned) {
+                (Some(last_used), true) => {
+                    self.lru.remove(&last_used);
+                    entry.last_used = None;
+                    self.pinned_usage += entry.charge;
+                }
+                (None, false) => {
+                    self.tick += 1;
+                    entry.last_used = Some(self.tick);
+                    self.lru.insert(self.tick, *key);
+                    self.pinned_usage -= entry.charge;
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Hits and misses of one kind of block since the database was opened.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheCounters {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BlockCacheStats {
+    pub capacity: usize, // bytes
+    pub usage: usize,    // bytes charged, pinned blocks included
+    pub pinned_usage: usize,
+    pub entries: usize,
+    pub index: CacheCounters,
+    pub documents: CacheCounters,
+    pub vectors: CacheCounters,
+}
+
+pub struct BlockCache {
+    capacity: usize,
+    shards: Vec<Mutex<Shard>>,
+    pinned_collections: RwLock<HashSet<String>>,
+    hits: [AtomicU64; 3], // per BlockKind
+    misses: [AtomicU64; 3],
+}
+
+impl BlockCache {
+    /// A cache holding up to `capacity` bytes of blocks, 0 disables it.
+    pub fn new(capacity: usize) -> Self {
+        BlockCache {
+            capacity,
+            shards: (0..BLOCK_CACHE_SHARDS)
+                .map(|_| Mutex::new(Shard::default()))
+                .collect(),
+            pinned_collections: RwLock::new(HashSet::new()),
+            hits: Default::default(),
+            misses: Default::default(),
+        }
+    }
+
+    fn shard_capacity(&self) -> usize {
+        self.capacity / self.shards.len()
+    }
+
+    fn shard(&self, key: &BlockKey) -> MutexGuard<'_, Shard> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let shard = hasher.finish() as usize % self.shards.len();
+        self.shards[shard]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The block at `key`, loaded on a miss. `load` returns the block and the bytes it
+    /// is charged for. With `fill` false a miss is not cached, for reads of every block
+    /// that would push the hot ones out.
+    pub(crate) fn get_or_load<T: Any + Send + Sync>(
+        &self,
+        key: BlockKey,
+        collection: &Arc<str>,
+        fill: bool,
+        load: impl FnOnce() -> Result<(T, usize), SSTError>,
+    ) -> Result<Arc<T>, SSTError> {
+        let cached = self.shard(&key).get(&key);
+        if let Some(block) = cached.and_then(|block| block.downcast::<T>().ok()) {
+            self.hits[key.kind as usize].fetch_add(1, Ordering::Relaxed);
+            return Ok(block);
+        }
+        self.misses[key.kind as usize].fetch_add(1, Ordering::Relaxed);
+
+        // Loaded outside the lock, two readers racing on a miss both load the block
+        let (block, charge) = load()?;
+        let block = Arc::new(block);
+        if fill && self.capacity > 0 {
+            // Held while inserting, so that a concurrent `set_pinned` sees the entry
+            let pinned_collections = self
+                .pinned_collections
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            let pinned = key.kind == BlockKind::Index && pinned_collections.contains(&**collection);
+            let entry = Entry {
+                block: block.clone(),
+                charge,
+                collection: collection.clone(),
+                last_used: None,
+            };
+            self.shard(&key)
+                .insert(key, entry, pinned, self.shard_capacity());
+        }
+        Ok(block)
+    }
+
+    /// Keeps the index blocks of `collection` cached whatever the other reads do, or
+    /// lets them be evicted again.
+    pub fn set_pinned(&self, collection: &str, pinned: bool) {
+        let mut pinned_collections = self
+            .pinned_collections
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        if pinned {
+            pinned_collections.insert(collection.to_string());
+        } else {
+            pinned_collections.remove(collection);
+        }
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().unwrap_or_else(PoisonError::into_inner);
+            shard.set_pinned(collection, pinned);
+            shard.evict(self.shard_capacity());
+        }
+    }
+
+    pub(crate) fn is_pinned(&self, collection: &str) -> bool {
+        self.pinned_collections
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains(collection)
+    }
+
+    /// Drops every block of `table`, called once the table is closed.
+    pub(crate) fn forget_table(&self, table: u64) {
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().unwrap_or_else(PoisonError::into_inner);
+            let keys: Vec<BlockKey> = shard
+                .entries
+                .keys()
+                .filter(|key| key.table == table)
+                .copied()
+                .collect();
+            for key in keys.iter() {
+                shard.remove(key);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> BlockCacheStats {
+        let mut stats = BlockCacheStats {
+            capacity: self.capacity,
+            ..BlockCacheStats::default()
+        };
+        for shard in self.shards.iter() {
+            let shard = shard.lock().unwrap_or_else(PoisonError::into_inner);
+            stats.usage += shard.usage;
+            stats.pinned_usage += shard.pinned_usage;
+            stats.entries += shard.entries.len();
+        }
+        let counters = |kind: BlockKind| CacheCounters {
+            hits: self.hits[kind as usize].load(Ordering::Relaxed),
+            misses: self.misses[kind as usize].load(Ordering::Relaxed),
+        };
+        stats.index = counters(BlockKind::Index);
+        stats.documents = counters(BlockKind::Documents);
+        stats.vectors = counters(BlockKind::Vectors);
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(table: u64, kind: BlockKind, offset: u64) -> BlockKey {
+        BlockKey {
+            table,
+            kind,
+            offset,
+        }
+    }
+
+    fn load(cache: &BlockCache, key: BlockKey, collection: &str, charge: usize) -> Arc<u64> {
+        cache
+            .get_or_load(key, &Arc::from(collection), true, || {
+                Ok((key.offset, charge))
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_budget_evicts_least_recently_used() {
+        // One shard's share of the budget is 100 bytes
+        let cache = BlockCache::new(100 * BLOCK_CACHE_SHARDS);
+        let blocks: Vec<BlockKey> = (0..1000)
+            .map(|offset| key(1, BlockKind::Documents, offset))
+            .collect();
+        for block in blocks.iter() {
+            load(&cache, *block, "a", 40);
+        }
+
+        let stats = cache.stats();
+        assert!(stats.usage <= stats.capacity);
+        assert!(stats.entries <= 2 * BLOCK_CACHE_SHARDS);
+        assert_eq!(
+            stats.documents,
+            CacheCounters {
+                hits: 0,
+                misses: 1000
+            }
+        );
+
+        // The last block loaded is still there, the first one is long gone
+        load(&cache, blocks[999], "a", 40);
+        load(&cache, blocks[0], "a", 40);
+        assert_eq!(
+            cache.stats().documents,
+            CacheCounters {
+                hits: 1,
+                misses: 1001
+            }
+        );
+
+        // Blocks above a shard's share are never cached
+        load(&cache, key(2, BlockKind::Documents, 0), "a", 1000);
+        load(&cache, key(2, BlockKind::Documents, 0), "a", 1000);
+        assert_eq!(cache.stats().documents.hits, 1);
+
+        cache.forget_table(1);
+        assert_eq!(cache.stats().entries, 0);
+        assert_eq!(cache.stats().usage, 0);
+    }
+
+    #[test]
+    fn test_pinned_index_blocks_survive_eviction() {
+        let cache = BlockCache::new(100 * BLOCK_CACHE_SHARDS);
+        cache.set_pinned("hot", true);
+        let hot: Vec<BlockKey> = (0..50)
+            .map(|table| key(table, BlockKind::Index, 0))
+            .collect();
+        for block in hot.iter() {
+            load(&cache, *block, "hot", 30);
+        }
+        load(&cache, key(100, BlockKind::Index, 0), "cold", 30);
+        for offset in 0..1000 {
+            load(&cache, key(100, BlockKind::Documents, offset), "hot", 30);
+        }
+        assert_eq!(cache.stats().pinned_usage, 50 * 30);
+
+        for block in hot.iter() {
+            load(&cache, *block, "hot", 30);
+        }
+        assert_eq!(cache.stats().index.hits, 50);
+
+        // Unpinned, they are evicted like any other block
+        cache.set_pinned("hot", false);
+        assert_eq!(cache.stats().pinned_usage, 0);
+        assert!(cache.stats().usage <= cache.stats().capacity);
+        for table in 0..50 {
+            cache.forget_table(table);
+        }
+        assert!(cache.stats().entries <= 3 * BLOCK_CACHE_SHARDS);
+    }
+}
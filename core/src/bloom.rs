@@ -0,0 +1,77 @@
+/*
+Bloom filter over document ids, stored in every v2 SST so that a lookup of an id the
+file does not hold can skip it without reading any block.
+
+Ids are UUIDs but also small caller-chosen integers, so both halves are mixed before
+deriving the probes by double hashing.
+*/
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    probes: u32,
+}
+
+impl BloomFilter {
+    /// Sized for `keys` ids, `bits_per_key` = 10 gives about 1% false positives.
+    pub fn new(keys: usize, bits_per_key: usize) -> Self {
+        let words = (keys * bits_per_key).div_ceil(64).max(1);
+        // ln(2) * bits per key minimises false positives
+        let probes = ((bits_per_key as f64 * 0.69) as u32).clamp(1, 30);
+        BloomFilter {
+            bits: vec![0; words],
+            probes,
+        }
+    }
+
+    pub fn insert(&mut self, id: u128) {
+        for bit in probe_bits(id, self.bits.len() * 64, self.probes) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// False means the id is certainly absent.
+    pub fn may_contain(&self, id: u128) -> bool {
+        probe_bits(id, self.bits.len() * 64, self.probes)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+}
+
+fn probe_bits(id: u128, len: usize, probes: u32) -> impl Iterator<Item = usize> {
+    let h1 = mix(id as u64 ^ mix((id >> 64) as u64));
+    let h2 = mix(h1 ^ (id >> 64) as u64) | 1;
+    (0..probes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len as u64) as usize)
+}
+
+/// splitmix64 finaliser.
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_false_negatives_and_few_false_positives() {
+        let mut bloom = BloomFilter::new(1000, 10);
+        for id in 0..1000u128 {
+            bloom.insert(id);
+        }
+        assert!((0..1000u128).all(|id| bloom.may_contain(id)));
+
+        let false_positives = (1000..101_000u128)
+            .filter(|id| bloom.may_contain(*id))
+            .count();
+        assert!(
+            false_positives < 2000,
+            "{} false positives",
+            false_positives
+        );
+    }
+}
@@ -0,0 +1,295 @@
+/**
+ * On-disk list of the collections of a database, so they can be rebuilt on open.
+ *
+ * The whole catalog is rewritten on every change: it is written to a temp file,
+ * fsynced and renamed over the previous one, so a crash leaves either the old
+ * or the new catalog but never a torn one.
+ */
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::collection::{DistanceType, IndexConfig};
+use crate::error::DatabaseError;
+
+const CATALOG_FILE: &str = "CATALOG";
+const CATALOG_TEMP_FILE: &str = "CATALOG.tmp";
+const CATALOG_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CollectionDescriptor {
+    pub name: String,
+    pub dimension: i32,
+    pub distance: DistanceType,
+    pub index_config: IndexConfig,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CatalogFile {
+    version: u32,
+    collections: Vec<CollectionDescriptor>,
+}
+
+/// Version 1 catalogs, written before collections could declare payload indexes.
+mod v1 {
+    use super::*;
+    use crate::collection::IndexType;
+    use std::collections::HashMap;
+
+    #[derive(Deserialize)]
+    pub struct IndexConfig {
+        index: IndexType,
+        params: HashMap<String, String>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct CollectionDescriptor {
+        name: String,
+        dimension: i32,
+        distance: DistanceType,
+        index_config: IndexConfig,
+    }
+
+    #[derive(Deserialize)]
+    pub struct CatalogFile {
+        pub collections: Vec<CollectionDescriptor>,
+    }
+
+    impl From<CollectionDescriptor> for super::CollectionDescriptor {
+        fn from(descriptor: CollectionDescriptor) -> Self {
+            super::CollectionDescriptor {
+                name: descriptor.name,
+                dimension: descriptor.dimension,
+                distance: descriptor.distance,
+                index_config: super::IndexConfig::from_parts(
+                    descriptor.index_config.index,
+                    descriptor.index_config.params,
+                    Default::default(),
+                ),
+            }
+        }
+    }
+}
+
+fn decode(bytes: &[u8]) -> Result<Vec<CollectionDescriptor>, DatabaseError> {
+    let corrupted = |e: bincode::Error| DatabaseError::CatalogError(Some(e.to_string()));
+
+    // Every version starts with the version number
+    let version: u32 = bincode::deserialize(bytes).map_err(corrupted)?;
+    let mut collections: Vec<CollectionDescriptor> = match version {
+        1 => {
+            let file: v1::CatalogFile = bincode::deserialize(&bytes[4..]).map_err(corrupted)?;
+            file.collections.into_iter().map(Into::into).collect()
+        }
+        CATALOG_VERSION => {
+            let file: CatalogFile = bincode::deserialize(bytes).map_err(corrupted)?;
+            file.collections
+        }
+        _ => {
+            return Err(DatabaseError::CatalogError(Some(format!(
+                "Unsupported catalog version: {}",
+                version
+            ))));
+        }
+    };
+
+    // Older builds defaulted IVF collections to a `quantization` they never applied
+    for descriptor in collections.iter_mut() {
+        descriptor.index_config.params.remove("quantization");
+    }
+    Ok(collections)
+}
+
+pub struct Catalog {
+    path: PathBuf,
+    collections: Mutex<BTreeMap<String, CollectionDescriptor>>,
+}
+
+impl Catalog {
+    pub fn open(db_path: &Path) -> Result<Self, DatabaseError> {
+        let path = db_path.join(CATALOG_FILE);
+
+        // Leftover of a write that crashed before the rename, the catalog itself is intact
+        fs::remove_file(db_path.join(CATALOG_TEMP_FILE)).ok();
+
+        let mut collections = BTreeMap::new();
+        if path.exists() {
+            let bytes =
+                fs::read(&path).map_err(|e| DatabaseError::CatalogError(Some(e.to_string())))?;
+            for descriptor in decode(&bytes)? {
+                collections.insert(descriptor.name.clone(), descriptor);
+            }
+        }
+
+        Ok(Catalog {
+            path,
+            collections: Mutex::new(collections),
+        })
+    }
+
+    pub fn collections(&self) -> Vec<CollectionDescriptor> {
+        self.collections.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Returns false without touching the disk if the name is already taken.
+    pub fn add(&self, descriptor: CollectionDescriptor) -> std::io::Result<bool> {
+        let mut collections = self.collections.lock().unwrap();
+        if collections.contains_key(&descriptor.name) {
+            return Ok(false);
+        }
+
+        let name = descriptor.name.clone();
+        collections.insert(name.clone(), descriptor);
+        if let Err(e) = self.persist(&collections) {
+            collections.remove(&name);
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Returns false without touching the disk if there is no such collection.
+    pub fn remove(&self, name: &str) -> std::io::Result<bool> {
+        let mut collections = self.collections.lock().unwrap();
+        let Some(descriptor) = collections.remove(name) else {
+            return Ok(false);
+        };
+
+        if let Err(e) = self.persist(&collections) {
+            collections.insert(name.to_string(), descriptor);
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    fn persist(&self, collections: &BTreeMap<String, CollectionDescriptor>) -> std::io::Result<()> {
+        let bytes = bincode::serialize(&CatalogFile {
+            version: CATALOG_VERSION,
+            collections: collections.values().cloned().collect(),
+        })
+        .map_err(std::io::Error::other)?;
+
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        let temp_path = dir.join(CATALOG_TEMP_FILE);
+        {
+            let mut file = File::create(&temp_path)?;
+            file.write_all(&bytes)?;
+            file.sync_all()?;
+        }
+        fs::rename(&temp_path, &self.path)?;
+
+        // Make the rename itself durable
+        File::open(dir)?.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn descriptor(name: &str) -> CollectionDescriptor {
+        CollectionDescriptor {
+            name: name.to_string(),
+            dimension: 8,
+            distance: DistanceType::Cosine,
+            index_config: IndexConfig::new_with_default_config("ivf").unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_catalog_survives_reopen() {
+        let dir = tempdir().expect("Failed to create temp dir");
+
+        {
+            let catalog = Catalog::open(dir.path()).unwrap();
+            assert!(catalog.collections().is_empty());
+            assert!(catalog.add(descriptor("a")).unwrap());
+            assert!(catalog.add(descriptor("b")).unwrap());
+            assert!(!catalog.add(descriptor("a")).unwrap());
+        }
+
+        let catalog = Catalog::open(dir.path()).unwrap();
+        let collections = catalog.collections();
+        assert_eq!(collections.len(), 2);
+        assert_eq!(collections[0].name, "a");
+        assert_eq!(collections[0].dimension, 8);
+        assert!(matches!(collections[0].distance, DistanceType::Cosine));
+        assert_eq!(collections[0].index_config.params["nlist"], "1024");
+        assert!(!dir.path().join(CATALOG_TEMP_FILE).exists());
+
+        assert!(catalog.remove("a").unwrap());
+        assert!(!catalog.remove("a").unwrap());
+        let catalog = Catalog::open(dir.path()).unwrap();
+        let collections = catalog.collections();
+        assert_eq!(collections.len(), 1);
+        assert_eq!(collections[0].name, "b");
+    }
+
+    #[test]
+    fn test_catalog_upgrades_version_1() {
+        #[derive(Serialize)]
+        struct IndexConfigV1 {
+            index: crate::collection::IndexType,
+            params: std::collections::HashMap<String, String>,
+        }
+
+        #[derive(Serialize)]
+        struct CollectionDescriptorV1 {
+            name: String,
+            dimension: i32,
+            distance: DistanceType,
+            index_config: IndexConfigV1,
+        }
+
+        let dir = tempdir().expect("Failed to create temp dir");
+        let legacy = (
+            1u32,
+            vec![CollectionDescriptorV1 {
+                name: "a".to_string(),
+                dimension: 4,
+                distance: DistanceType::L2,
+                index_config: IndexConfigV1 {
+                    index: crate::collection::IndexType::IVF,
+                    params: [("nlist", "16"), ("quantization", "PQ16")]
+                        .into_iter()
+                        .map(|(k, v)| (k.to_string(), v.to_string()))
+                        .collect(),
+                },
+            }],
+        );
+        fs::write(
+            dir.path().join(CATALOG_FILE),
+            bincode::serialize(&legacy).unwrap(),
+        )
+        .unwrap();
+
+        let catalog = Catalog::open(dir.path()).unwrap();
+        let collections = catalog.collections();
+        assert_eq!(collections.len(), 1);
+        assert_eq!(collections[0].dimension, 4);
+        assert!(collections[0].index_config.payload_indexes.is_empty());
+        let params = &collections[0].index_config.params;
+        assert_eq!(params["nlist"], "16");
+        assert!(!params.contains_key("quantization"));
+
+        // The next write stores the current version
+        assert!(catalog.add(descriptor("b")).unwrap());
+        let reopened = Catalog::open(dir.path()).unwrap();
+        assert_eq!(reopened.collections().len(), 2);
+    }
+
+    #[test]
+    fn test_catalog_rejects_garbage() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        fs::write(dir.path().join(CATALOG_FILE), b"garbage").unwrap();
+
+        assert!(matches!(
+            Catalog::open(dir.path()),
+            Err(DatabaseError::CatalogError(_))
+        ));
+    }
+}
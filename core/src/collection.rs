@@ -1,16 +1,30 @@
-use crate::compact::CompactTask;
+use crate::ann::AnnConfig;
+use crate::catalog::CollectionDescriptor;
+use crate::compact::{CompactTask, CompactionPolicy};
+use crate::constant::{FLUSH_ATTEMPTS_BEFORE_READ_ONLY, STALL_RECHECK_INTERVAL};
 use crate::context::BackgroundContext;
-use crate::document::Document;
-use crate::error::CollectionError;
-use crate::index::{IndexManager, SSTMetadata};
+use crate::document::{Document, ExternalId, Record};
+use crate::error::{CollectionError, WalError};
+use crate::hnsw::HnswParams;
+use crate::index::{IndexManager, SSTEvent, SSTMetadata};
+use crate::ivf::{IvfParams, TrainedCentroids};
 use crate::memtable::{MemTable, get_memtable};
-use crate::search::SearchManager;
-use crate::wal::Operation;
-use crate::wal::WalManager;
+use crate::options::{DatabaseOptions, WriteStallConfig, WriteStallPolicy, WriteStallStats};
+use crate::payload::Filter;
+use crate::payload_index::{PayloadIndexConfig, PayloadIndexType};
+use crate::search::{ScoredDocument, SearchManager};
+use crate::sst::SSTManager;
+use crate::wal::{WalManager, remove_segments_below};
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError, RwLock};
+use std::thread;
+use std::time::Instant;
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum IndexType {
     HNSW,
     IVF,
@@ -33,20 +47,55 @@ impl FromStr for IndexType {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IndexConfig {
     index: IndexType,
     pub params: HashMap<String, String>,
+    /// Payload fields with a secondary index, used to pre-filter searches.
+    pub payload_indexes: PayloadIndexConfig,
 }
 
 impl IndexConfig {
     pub fn new(index: &str, params: HashMap<String, String>) -> Result<Self, CollectionError> {
         let index_type = IndexType::from_str(index)?;
+        match index_type {
+            IndexType::HNSW => {
+                HnswParams::from_params(&params)?;
+            }
+            IndexType::IVF => {
+                IvfParams::from_params(&params)?;
+            }
+            IndexType::Flat => {}
+        }
         Ok(IndexConfig {
             index: index_type,
             params,
+            payload_indexes: PayloadIndexConfig::new(),
         })
     }
 
+    /// Indexes `field` so that filters on it can skip non-matching documents.
+    pub fn with_payload_index(mut self, field: &str, kind: PayloadIndexType) -> Self {
+        self.payload_indexes.insert(field.to_string(), kind);
+        self
+    }
+
+    pub(crate) fn from_parts(
+        index: IndexType,
+        params: HashMap<String, String>,
+        payload_indexes: PayloadIndexConfig,
+    ) -> Self {
+        IndexConfig {
+            index,
+            params,
+            payload_indexes,
+        }
+    }
+
+    pub(crate) fn index_type(&self) -> &IndexType {
+        &self.index
+    }
+
     pub fn new_with_default_config(index: &str) -> Result<Self, CollectionError> {
         let index_type = IndexType::from_str(index)?;
         match index_type {
@@ -58,22 +107,24 @@ impl IndexConfig {
                 return Ok(IndexConfig {
                     index: index_type,
                     params: default_params,
+                    payload_indexes: PayloadIndexConfig::new(),
                 });
             }
             IndexType::IVF => {
                 let mut default_params = HashMap::new();
                 default_params.insert("nlist".to_string(), "1024".to_string());
-                default_params.insert("quantization".to_string(), "PQ16".to_string());
                 default_params.insert("nprobe".to_string(), "10".to_string());
                 return Ok(IndexConfig {
                     index: index_type,
                     params: default_params,
+                    payload_indexes: PayloadIndexConfig::new(),
                 });
             }
             IndexType::Flat => {
                 return Ok(IndexConfig {
                     index: index_type,
                     params: HashMap::new(),
+                    payload_indexes: PayloadIndexConfig::new(),
                 });
             }
         }
@@ -89,11 +140,12 @@ impl Default for IndexConfig {
         IndexConfig {
             index: IndexType::HNSW,
             params: default_params,
+            payload_indexes: PayloadIndexConfig::new(),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum DistanceType {
     Cosine,
     L2,
@@ -119,110 +171,651 @@ impl FromStr for DistanceType {
 pub struct Collection {
     name: String,
     memtable_size: usize,
+    write_stall: WriteStallConfig,
     dimension: i32,
     distance: DistanceType,
     index_config: IndexConfig,
+    ivf_centroids: TrainedCentroids, // trained by the first IVF memtable to fill up
     memtable: RwLock<Box<dyn MemTable>>,
     wal_manager: WalManager,
-    frozen_memtable_list: VecDeque<Arc<dyn MemTable>>, // Note: this might grow infinity. Need a bound on it, and force write both write to disk and stop accepting new write
-    background_context: BackgroundContext,
-    index_manager: Arc<IndexManager>,
     search_manager: SearchManager,
+    state: Arc<CollectionState>,
+}
+
+/// How background work of a collection is doing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CollectionHealth {
+    Healthy,
+    /// Merges keep failing. Reads and writes still work, but L0 grows until write stalls
+    /// kick in.
+    Degraded(String),
+    /// A flush keeps failing, writes are rejected until it goes through. Nothing is lost,
+    /// the frozen memtables stay readable and their records are still in the WAL.
+    ReadOnly(String),
+    /// A WAL write or fsync failed, writes are rejected until the database is reopened.
+    /// Records logged since the last good fsync may or may not be on disk, the reopen
+    /// replays whatever made it.
+    Failed(String),
+}
+
+/// The part of a collection that background SST events update. The `CollectionManager`
+/// holds it next to the collection, so applying an event never waits on the collection
+/// lock, which callers may hold across many writes.
+pub(crate) struct CollectionState {
+    name: String,
+    wal_dir: PathBuf,
+    frozen_memtable_list: RwLock<VecDeque<(u64, Arc<dyn MemTable>)>>, // (flush seq_no, memtable), oldest first, bounded by `WriteStallConfig`
+    index_manager: Arc<IndexManager>,
+    sst_manager: Arc<SSTManager>,
+    background_context: BackgroundContext,
+    compaction_policy: CompactionPolicy,
+    merge_state: Mutex<MergeState>,
+    pending_compactions: AtomicUsize, // tasks sent to the compaction manager and not answered yet
+    backlog_changed: (Mutex<()>, Condvar), // signaled on every SST event, wakes stalled writers
+    stall_counters: StallCounters,
+    closed: AtomicBool, // set by `Collection::close`, rejects writes and stops new merges
+    health: RwLock<CollectionHealth>,
+}
+
+#[derive(Default)]
+struct StallCounters {
+    slowdowns: AtomicU64,
+    stalls: AtomicU64,
+    rejected: AtomicU64,
+    stalled_micros: AtomicU64,
+}
+
+struct MergeState {
+    in_flight: bool, // at most one merge per collection, its inputs must stay untouched
+    obsolete_ssts: Vec<ObsoleteSSTs>,
+}
+
+/// Inputs of a committed compaction, deleted once no search holds them anymore.
+struct ObsoleteSSTs {
+    files: Vec<Arc<SSTMetadata>>,
+    log_path: PathBuf,
 }
 
 impl Collection {
     pub fn new(
-        name: &str,
-        dimension: i32,
-        distance: &str,
-        index_config: IndexConfig,
+        descriptor: &CollectionDescriptor,
         wal_manager: WalManager,
-        memtable_size: usize,
+        options: &DatabaseOptions,
         background_context: BackgroundContext,
+        sst_manager: Arc<SSTManager>,
     ) -> Result<Self, CollectionError> {
         let index_manager = Arc::new(IndexManager::default());
-
-        let distance_type: DistanceType = distance.parse()?;
+        let distance = descriptor.distance.clone();
+        let ivf_centroids = TrainedCentroids::default();
 
         Ok(Collection {
-            name: name.to_string(),
-            dimension: dimension,
-            distance: distance_type.clone(),
-            memtable: RwLock::new(get_memtable(&index_config.index)),
-            index_config: index_config,
-            wal_manager: wal_manager,
-            memtable_size: memtable_size,
-            frozen_memtable_list: VecDeque::with_capacity(10),
-            background_context: background_context,
-            search_manager: SearchManager::new(index_manager.clone(), distance_type),
-            index_manager: index_manager,
+            name: descriptor.name.clone(),
+            dimension: descriptor.dimension,
+            memtable: RwLock::new(get_memtable(
+                &descriptor.index_config,
+                &distance,
+                options.memtable_size,
+                &ivf_centroids,
+            )),
+            ivf_centroids,
+            index_config: descriptor.index_config.clone(),
+            memtable_size: options.memtable_size,
+            write_stall: options.write_stall.clone(),
+            search_manager: SearchManager::new(
+                index_manager.clone(),
+                sst_manager.clone(),
+                distance.clone(),
+            ),
+            state: Arc::new(CollectionState {
+                name: descriptor.name.clone(),
+                wal_dir: wal_manager.dir().to_path_buf(),
+                frozen_memtable_list: RwLock::new(VecDeque::with_capacity(10)),
+                index_manager,
+                sst_manager,
+                background_context,
+                compaction_policy: CompactionPolicy {
+                    payload_indexes: descriptor.index_config.payload_indexes.clone(),
+                    ann: AnnConfig::new(&descriptor.index_config, &distance),
+                    ..CompactionPolicy::default()
+                },
+                merge_state: Mutex::new(MergeState {
+                    in_flight: false,
+                    obsolete_ssts: Vec::new(),
+                }),
+                pending_compactions: AtomicUsize::new(0),
+                backlog_changed: (Mutex::new(()), Condvar::new()),
+                stall_counters: StallCounters::default(),
+                closed: AtomicBool::new(false),
+                health: RwLock::new(CollectionHealth::Healthy),
+            }),
+            wal_manager,
+            distance,
         })
     }
-    pub fn upsert(&mut self, document: Document) -> Result<(), CollectionError> {
+    /// Inserts or replaces the document with the same id. A document with an external id
+    /// is keyed by it, whatever its `id` field says.
+    ///
+    /// Writes only need `&self`: concurrent writers hold the memtable lock just long enough
+    /// to log and apply their record, and wait for the disk after releasing it, so that
+    /// group commit can cover them with a shared fsync. A write is therefore visible to
+    /// readers before it is durable: if the fsync fails, the caller gets an error but the
+    /// write may still be read, and flushed. The collection then turns
+    /// `CollectionHealth::Failed` and rejects every later write.
+    pub fn upsert(&self, mut document: Document) -> Result<(), CollectionError> {
+        if let Some(external_id) = &document.external_id {
+            document.id = external_id.internal_id();
+        }
         if document.dimension() != self.dimension {
             Err(CollectionError::InvalidDimension(Some(
                 "Dimension mismatch".to_string(),
             )))
         } else {
+            self.state.admit_write(&self.write_stall)?;
+            let lsn = {
+                let mut memtable = self.memtable.write()?;
+                let lsn = self.log(self.wal_manager.write_insert(&document))?;
+                memtable.upsert(document);
+                lsn
+            };
+            self.log(self.wal_manager.wait_durable(lsn))?;
+            self.freeze_if_full()
+        }
+    }
+
+    /// Upserts `documents` as one write: a single WAL record and fsync, applied to the
+    /// memtable in one go so searches see all of them or none. Every dimension is checked
+    /// before anything is written, a later document with the same id wins.
+    ///
+    /// The batch never straddles a memtable rotation, its WAL record has to stay in the
+    /// segment of the memtable holding it. A batch that does not fit the active memtable
+    /// starts a fresh one, and a batch larger than `memtable_size` gets a memtable to itself.
+    pub fn upsert_batch(&self, mut documents: Vec<Document>) -> Result<(), CollectionError> {
+        for document in documents.iter_mut() {
+            if let Some(external_id) = &document.external_id {
+                document.id = external_id.internal_id();
+            }
+            if document.dimension() != self.dimension {
+                return Err(CollectionError::InvalidDimension(Some(format!(
+                    "Dimension mismatch for document {}",
+                    document.id
+                ))));
+            }
+        }
+        if documents.is_empty() {
+            return Ok(());
+        }
+
+        self.state.admit_write(&self.write_stall)?;
+        let lsn = {
+            let mut memtable = self.memtable.write()?;
+            let size = memtable.size();
+            if size > 0 && size + documents.len() > self.memtable_size {
+                self.freeze(&mut memtable)?;
+            }
+            let lsn = self.log(self.wal_manager.write_batch(&documents))?;
+            for document in documents {
+                memtable.upsert(document);
+            }
+            lsn
+        };
+        self.log(self.wal_manager.wait_durable(lsn))?;
+        self.freeze_if_full()
+    }
+
+    /// Deletes are logged and kept as tombstones, they hide older versions in SSTs
+    /// until compaction drops both.
+    pub fn delete(&self, id: &u128) -> Result<(), CollectionError> {
+        self.state.admit_write(&self.write_stall)?;
+        let lsn = {
             let mut memtable = self.memtable.write()?;
+            let lsn = self.log(self.wal_manager.write_delete(id))?;
+            memtable.delete(id);
+            lsn
+        };
+        self.log(self.wal_manager.wait_durable(lsn))?;
+        self.freeze_if_full()
+    }
+
+    pub fn delete_by_external_id(&self, id: &ExternalId) -> Result<(), CollectionError> {
+        self.delete(&id.internal_id())
+    }
+
+    fn freeze_if_full(&self) -> Result<(), CollectionError> {
+        let mut memtable = self.memtable.write()?;
+        if memtable.size() < self.memtable_size {
+            return Ok(());
+        }
+        self.freeze(&mut memtable)
+    }
 
-            self.wal_manager.write(Operation::Insert, &document)?;
+    /// Swaps the active memtable for an empty one and hands it to compaction. Runs under
+    /// the memtable write lock, a write logged in the old WAL segment always lands in the
+    /// old memtable.
+    fn freeze(&self, memtable: &mut Box<dyn MemTable>) -> Result<(), CollectionError> {
+        // Once flushed, the SST covers every WAL segment before the one we rotate to.
+        // Rotate before the swap, a failed rotation leaves the memtable in place.
+        self.log(self.wal_manager.rotate())?;
 
-            memtable.upsert(document);
-            if memtable.size() >= self.memtable_size {
-                let old_memtable =
-                    std::mem::replace(&mut *memtable, get_memtable(&self.index_config.index));
+        let empty = get_memtable(
+            &self.index_config,
+            &self.distance,
+            self.memtable_size,
+            &self.ivf_centroids,
+        );
+        let old_memtable = std::mem::replace(memtable, empty);
 
-                let arc_memtable: Arc<dyn MemTable> = Arc::from(old_memtable);
+        let arc_memtable: Arc<dyn MemTable> = Arc::from(old_memtable);
+        let seq_no = self.wal_manager.get_seq_no();
+        self.state
+            .frozen_memtable_list
+            .write()?
+            .push_back((seq_no, arc_memtable.clone()));
 
-                self.frozen_memtable_list.push_back(arc_memtable.clone());
-                self.wal_manager.rotate()?;
+        self.state.send_task(CompactTask::new_default_layer(
+            self.name.clone(),
+            seq_no,
+            arc_memtable,
+        ))
+    }
 
-                self.background_context
-                    .compact_task_sender
-                    .send(CompactTask::new_default_layer(
-                        self.name.clone(),
-                        self.wal_manager.get_seq_no(),
-                        arc_memtable.clone(),
-                    ))
-                    .map_err(|e| CollectionError::InternalError(Some(e.to_string())))?;
+    /// Passes on the result of a WAL call. Once the WAL has failed, the collection is
+    /// marked `CollectionHealth::Failed`: it cannot tell which of its writes are durable.
+    fn log<T>(&self, result: Result<T, WalError>) -> Result<T, CollectionError> {
+        if result.is_err()
+            && let Some(reason) = self.wal_manager.failure()
+        {
+            self.state.fail(reason);
+        }
+        Ok(result?)
+    }
+
+    /// Stops accepting writes and hands the active memtable to compaction. The flush
+    /// itself completes in the background, see `CollectionState::wait_for_idle`.
+    pub(crate) fn close(&self) -> Result<(), CollectionError> {
+        self.state.closed.store(true, Ordering::SeqCst);
+        let mut memtable = self.memtable.write()?;
+        if memtable.size() > 0 {
+            self.freeze(&mut memtable)?;
+        }
+        drop(memtable);
+        self.log(self.wal_manager.sync())
+    }
+
+    /// Applies records recovered from the WAL, they are already durable so nothing is logged again.
+    pub(crate) fn replay(&mut self, records: Vec<Record>) -> Result<(), CollectionError> {
+        let mut memtable = self.memtable.write()?;
+        for record in records {
+            match record {
+                Record::Live(document) => memtable.upsert(Arc::unwrap_or_clone(document)),
+                Record::Tombstone(id) => memtable.delete(&id),
             }
+        }
+        Ok(())
+    }
+
+    /// Registers an SST that already exists on disk, used when reopening a database.
+    pub(crate) fn add_sst_metadata(&self, sst_metadata: SSTMetadata) {
+        self.state.index_manager.add_sst_metadata(sst_metadata);
+    }
 
-            Ok(())
+    pub(crate) fn state(&self) -> Arc<CollectionState> {
+        self.state.clone()
+    }
+
+    pub fn write_stall_stats(&self) -> WriteStallStats {
+        self.state.write_stall_stats()
+    }
+
+    pub fn health(&self) -> CollectionHealth {
+        self.state.health()
+    }
+
+    /// Top-k closest documents across the active memtable, the frozen memtables and the SSTs.
+    pub fn search(
+        &self,
+        vector: &[f32],
+        top_k: i32,
+    ) -> Result<Vec<ScoredDocument>, CollectionError> {
+        self.search_matching(vector, top_k, None)
+    }
+
+    /// Top-k closest documents among those whose payload matches `filter`.
+    pub fn search_with_filter(
+        &self,
+        vector: &[f32],
+        top_k: i32,
+        filter: &Filter,
+    ) -> Result<Vec<ScoredDocument>, CollectionError> {
+        self.search_matching(vector, top_k, Some(filter))
+    }
+
+    fn search_matching(
+        &self,
+        vector: &[f32],
+        top_k: i32,
+        filter: Option<&Filter>,
+    ) -> Result<Vec<ScoredDocument>, CollectionError> {
+        if vector.len() as i32 != self.dimension {
+            return Err(CollectionError::InvalidDimension(Some(
+                "Dimension mismatch".to_string(),
+            )));
         }
+
+        let memtable = self.memtable.read()?;
+        let frozen = self.state.frozen_newest_first()?;
+        let memtables = memtables_newest_first(memtable.as_ref(), &frozen);
+        self.search_manager
+            .search(vector, top_k, filter, &memtables)
     }
 
-    pub fn search(&self, vector: &Vec<f32>, top_k: i32) -> Vec<Arc<Document>> {
-        self.memtable.read().unwrap().search(vector, top_k)
+    pub fn fetch(&self, id: &u128) -> Result<Option<Arc<Document>>, CollectionError> {
+        let memtable = self.memtable.read()?;
+        let frozen = self.state.frozen_newest_first()?;
+        let memtables = memtables_newest_first(memtable.as_ref(), &frozen);
+        self.search_manager.fetch(id, &memtables)
     }
 
-    pub fn fetch(&self, id: &u128) -> Option<Arc<Document>> {
-        // I hope search manager is capable of fetching from memtables
-        // this will be way better than the current implementation
-        if let Some(doc) = self.memtable.read().unwrap().get(id) {
-            return Some(doc.clone());
+    pub fn fetch_by_external_id(
+        &self,
+        id: &ExternalId,
+    ) -> Result<Option<Arc<Document>>, CollectionError> {
+        self.fetch(&id.internal_id())
+    }
+}
+
+fn memtables_newest_first<'a>(
+    active: &'a dyn MemTable,
+    frozen: &'a [Arc<dyn MemTable>],
+) -> Vec<&'a dyn MemTable> {
+    let mut memtables: Vec<&dyn MemTable> = Vec::with_capacity(frozen.len() + 1);
+    memtables.push(active);
+    memtables.extend(frozen.iter().map(|m| m.as_ref()));
+    memtables
+}
+
+impl CollectionState {
+    /// Snapshot of the frozen memtables, most recently frozen first.
+    ///
+    /// Readers must take it before looking at the SSTs: a flushed memtable is only
+    /// dropped from the list after its SST is registered, so a memtable missing from the
+    /// snapshot is guaranteed to be found in the index.
+    fn frozen_newest_first(&self) -> Result<Vec<Arc<dyn MemTable>>, CollectionError> {
+        let frozen = self.frozen_memtable_list.read()?;
+        Ok(frozen.iter().rev().map(|(_, m)| m.clone()).collect())
+    }
+
+    /// Applies the outcome of a background task, then schedules the next merge if a layer
+    /// is over budget.
+    pub(crate) fn on_sst_event(&self, event: SSTEvent) -> Result<(), CollectionError> {
+        let completes_task = event.completes_task();
+        let result = self.apply_sst_event(event);
+
+        if completes_task {
+            self.pending_compactions.fetch_sub(1, Ordering::SeqCst);
         }
+        let (lock, condvar) = &self.backlog_changed;
+        let _guard = lock.lock()?;
+        condvar.notify_all();
+
+        result
+    }
+
+    fn apply_sst_event(&self, event: SSTEvent) -> Result<(), CollectionError> {
+        let mut merge_state = self.merge_state.lock()?;
+        match event {
+            SSTEvent::Flushed(metadata) => {
+                let seq_no = metadata.seq_no;
+                self.index_manager.add_sst_metadata(metadata);
+
+                // Flushes of a collection complete in order, so everything frozen before
+                // this seq_no is on disk now, and so are the WAL segments behind it
+                let mut frozen = self.frozen_memtable_list.write()?;
+                while frozen
+                    .front()
+                    .is_some_and(|(frozen_seq_no, _)| *frozen_seq_no <= seq_no)
+                {
+                    frozen.pop_front();
+                }
+                drop(frozen);
 
-        for memtable in self.frozen_memtable_list.iter() {
-            if let Some(doc) = memtable.get(id) {
-                return Some(doc.clone());
+                if matches!(*self.health.read()?, CollectionHealth::ReadOnly(_)) {
+                    *self.health.write()? = CollectionHealth::Healthy;
+                }
+                remove_segments_below(&self.wal_dir, &self.name, seq_no)?;
+            }
+            SSTEvent::Compacted {
+                added,
+                removed,
+                log_path,
+                ..
+            } => {
+                self.index_manager.replace_sst_metadata(added, &removed);
+                merge_state.obsolete_ssts.push(ObsoleteSSTs {
+                    files: removed,
+                    log_path,
+                });
+                merge_state.in_flight = false;
+
+                if matches!(*self.health.read()?, CollectionHealth::Degraded(_)) {
+                    *self.health.write()? = CollectionHealth::Healthy;
+                }
+            }
+            SSTEvent::CompactionFailed { error, .. } => {
+                // Planned again on the next event rather than right away against the same error
+                merge_state.in_flight = false;
+
+                let mut health = self.health.write()?;
+                if matches!(
+                    *health,
+                    CollectionHealth::Healthy | CollectionHealth::Degraded(_)
+                ) {
+                    *health = CollectionHealth::Degraded(error);
+                }
+                return Ok(());
+            }
+            SSTEvent::FlushRetrying {
+                seq_no,
+                attempts,
+                error,
+                ..
+            } => {
+                if attempts >= FLUSH_ATTEMPTS_BEFORE_READ_ONLY {
+                    self.make_read_only(format!(
+                        "flush of WAL segment {} failed {} times: {}",
+                        seq_no, attempts, error
+                    ))?;
+                }
+                return Ok(());
+            }
+            SSTEvent::FlushFailed { seq_no, error, .. } => {
+                // The frozen memtable stays, WAL replay recovers it on the next open
+                self.make_read_only(format!("flush of WAL segment {} failed: {}", seq_no, error))?;
+                return Ok(());
             }
         }
 
-        if let Some(doc) = self.search_manager.fetch(id) {
-            return Some(doc);
+        self.purge_obsolete_ssts(&mut merge_state);
+        self.schedule_merge_locked(&mut merge_state)
+    }
+
+    pub(crate) fn schedule_merge(&self) -> Result<(), CollectionError> {
+        let mut merge_state = self.merge_state.lock()?;
+        self.schedule_merge_locked(&mut merge_state)
+    }
+
+    fn schedule_merge_locked(&self, merge_state: &mut MergeState) -> Result<(), CollectionError> {
+        // A closing collection only finishes what is queued, the next open resumes merging
+        if merge_state.in_flight || self.closed.load(Ordering::SeqCst) {
+            return Ok(());
         }
+        let Some(plan) = self.compaction_policy.plan(&self.index_manager.levels()) else {
+            return Ok(());
+        };
 
-        None
+        merge_state.in_flight = true;
+        self.send_task(CompactTask::new_merge(self.name.clone(), plan))
     }
 
-    pub fn delete(&mut self, id: &u128) {
-        self.memtable.write().unwrap().delete(id);
+    fn send_task(&self, task: CompactTask) -> Result<(), CollectionError> {
+        self.pending_compactions.fetch_add(1, Ordering::SeqCst);
+        self.background_context
+            .compact_task_sender
+            .send(task)
+            .map_err(|e| {
+                self.pending_compactions.fetch_sub(1, Ordering::SeqCst);
+                CollectionError::InternalError(Some(e.to_string()))
+            })
+    }
+
+    /// Why writes have to wait, if the backlog is over one of the hard limits.
+    fn stall_reason(&self, config: &WriteStallConfig) -> Result<Option<String>, CollectionError> {
+        let frozen = self.frozen_memtable_list.read()?.len();
+        if frozen >= config.max_frozen_memtables {
+            return Ok(Some(format!(
+                "{} frozen memtables waiting for flush",
+                frozen
+            )));
+        }
+        let pending = self.pending_compactions.load(Ordering::SeqCst);
+        if pending >= config.max_pending_compactions {
+            return Ok(Some(format!("{} pending compaction tasks", pending)));
+        }
+        Ok(None)
+    }
+
+    /// Applies the write stall policy before a write is logged, so a rejected write has
+    /// no effect at all.
+    fn admit_write(&self, config: &WriteStallConfig) -> Result<(), CollectionError> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(CollectionError::Closed(Some(self.name.clone())));
+        }
+        self.check_writable()?;
+        if let WriteStallPolicy::SlowDown(delay) = config.policy
+            && self.frozen_memtable_list.read()?.len() >= config.slowdown_trigger
+        {
+            self.stall_counters
+                .slowdowns
+                .fetch_add(1, Ordering::Relaxed);
+            thread::sleep(delay);
+        }
+
+        let Some(reason) = self.stall_reason(config)? else {
+            return Ok(());
+        };
+        self.stall_counters.stalls.fetch_add(1, Ordering::Relaxed);
+
+        if let WriteStallPolicy::Error = config.policy {
+            self.stall_counters.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(CollectionError::WriteStall(Some(reason)));
+        }
+
+        let start = Instant::now();
+        let (lock, condvar) = &self.backlog_changed;
+        let mut guard = lock.lock()?;
+        while self.stall_reason(config)?.is_some() {
+            // The timeout only guards against a missed wakeup
+            guard = condvar.wait_timeout(guard, STALL_RECHECK_INTERVAL)?.0;
+            // A flush stuck on a full disk would otherwise block the writer indefinitely
+            self.check_writable()?;
+        }
+        self.stall_counters
+            .stalled_micros
+            .fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<(), CollectionError> {
+        match &*self.health.read()? {
+            CollectionHealth::ReadOnly(reason) | CollectionHealth::Failed(reason) => {
+                Err(CollectionError::ReadOnly(Some(reason.clone())))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Read-only until a flush goes through, unless the WAL has failed for good.
+    fn make_read_only(&self, reason: String) -> Result<(), CollectionError> {
+        let mut health = self.health.write()?;
+        if !matches!(*health, CollectionHealth::Failed(_)) {
+            *health = CollectionHealth::ReadOnly(reason);
+        }
+        Ok(())
+    }
+
+    fn fail(&self, reason: String) {
+        let mut health = self.health.write().unwrap_or_else(PoisonError::into_inner);
+        *health = CollectionHealth::Failed(format!("WAL failed: {}", reason));
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn health(&self) -> CollectionHealth {
+        self.health
+            .read()
+            .map(|health| health.clone())
+            .unwrap_or_else(|e| CollectionHealth::ReadOnly(e.to_string()))
+    }
+
+    /// Blocks until every task sent to the compaction manager has been answered, then
+    /// deletes the compacted SSTs no search holds anymore.
+    pub(crate) fn wait_for_idle(&self) -> Result<(), CollectionError> {
+        {
+            let (lock, condvar) = &self.backlog_changed;
+            let mut guard = lock.lock()?;
+            while self.pending_compactions.load(Ordering::SeqCst) > 0 {
+                guard = condvar.wait_timeout(guard, STALL_RECHECK_INTERVAL)?.0;
+            }
+        }
+
+        let mut merge_state = self.merge_state.lock()?;
+        self.purge_obsolete_ssts(&mut merge_state);
+        Ok(())
+    }
+
+    fn write_stall_stats(&self) -> WriteStallStats {
+        WriteStallStats {
+            slowdowns: self.stall_counters.slowdowns.load(Ordering::Relaxed),
+            stalls: self.stall_counters.stalls.load(Ordering::Relaxed),
+            rejected: self.stall_counters.rejected.load(Ordering::Relaxed),
+            stalled_micros: self.stall_counters.stalled_micros.load(Ordering::Relaxed),
+            frozen_memtables: self
+                .frozen_memtable_list
+                .read()
+                .map(|frozen| frozen.len())
+                .unwrap_or_default(),
+            pending_compactions: self.pending_compactions.load(Ordering::SeqCst),
+        }
+    }
+
+    fn purge_obsolete_ssts(&self, merge_state: &mut MergeState) {
+        let sst_manager = &self.sst_manager;
+        merge_state.obsolete_ssts.retain(|obsolete| {
+            // Searches that started before the swap may still be reading these files
+            if obsolete.files.iter().any(|f| Arc::strong_count(f) > 1) {
+                return true;
+            }
+
+            let paths: Vec<PathBuf> = obsolete.files.iter().map(|f| f.path.clone()).collect();
+            match sst_manager.finish_compaction(&obsolete.log_path, &paths) {
+                Ok(()) => false,
+                Err(e) => {
+                    eprintln!("WARN: failed to delete compacted SSTs: {}", e);
+                    true
+                }
+            }
+        });
     }
 }
 
 pub struct CollectionManager {
-    collections: RwLock<HashMap<String, Arc<RwLock<Collection>>>>,
+    collections: RwLock<HashMap<String, CollectionEntry>>,
+}
+
+struct CollectionEntry {
+    collection: Arc<RwLock<Collection>>,
+    state: Arc<CollectionState>,
 }
 
 impl CollectionManager {
@@ -234,19 +827,33 @@ impl CollectionManager {
 
     pub fn create_collection(&self, collection: Collection) -> Arc<RwLock<Collection>> {
         let name = collection.name.clone();
+        let state = collection.state();
         let arc_collection = Arc::new(RwLock::new(collection));
 
-        self.collections
-            .write()
-            .unwrap()
-            .insert(name, arc_collection.clone());
+        self.collections.write().unwrap().insert(
+            name,
+            CollectionEntry {
+                collection: arc_collection.clone(),
+                state,
+            },
+        );
 
         arc_collection
     }
 
     pub fn get_collection(&self, name: &str) -> Option<Arc<RwLock<Collection>>> {
         let map = self.collections.read().unwrap();
-        map.get(name).cloned()
+        map.get(name).map(|entry| entry.collection.clone())
+    }
+
+    /// Every open collection along with its state.
+    pub(crate) fn entries(&self) -> Vec<(Arc<RwLock<Collection>>, Arc<CollectionState>)> {
+        self.collections
+            .read()
+            .unwrap()
+            .values()
+            .map(|entry| (entry.collection.clone(), entry.state.clone()))
+            .collect()
     }
 
     pub fn delete_collection(&mut self, name: &str) {
@@ -254,14 +861,292 @@ impl CollectionManager {
     }
 
     // TODO: handle unwrap properly
-    pub fn on_sst_created(&self, sst: SSTMetadata) {
-        let collection = self.collections.read().unwrap();
-        collection
-            .get(&sst.collection_name)
+    pub fn on_sst_event(&self, event: SSTEvent) {
+        let Some(state) = self
+            .collections
+            .read()
             .unwrap()
-            .write()
-            .unwrap()
-            .index_manager
-            .add_sst_metadata(sst);
+            .get(event.collection_name())
+            .map(|entry| entry.state.clone())
+        else {
+            return;
+        };
+        if let Err(e) = state.on_sst_event(event) {
+            eprintln!("WARN: failed to apply SST event: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compact::CompactKind;
+    use crate::options::WriteStallPolicy;
+    use crate::test_utils::bulk_random_documents;
+    use crossbeam_channel::{Receiver, unbounded};
+    use std::path::Path;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    fn test_collection(
+        path: &Path,
+        options: &DatabaseOptions,
+    ) -> (Collection, Receiver<CompactTask>, Arc<SSTManager>) {
+        let sst_manager = Arc::new(SSTManager::new(path.to_path_buf()));
+        let wal_manager = WalManager::new(path, "test").unwrap();
+        let (compact_task_sender, compact_task_receiver) = unbounded();
+
+        let descriptor = CollectionDescriptor {
+            name: "test".to_string(),
+            dimension: 8,
+            distance: DistanceType::L2,
+            index_config: IndexConfig::new_with_default_config("flat").unwrap(),
+        };
+        let collection = Collection::new(
+            &descriptor,
+            wal_manager,
+            options,
+            BackgroundContext {
+                compact_task_sender,
+            },
+            sst_manager.clone(),
+        )
+        .unwrap();
+        (collection, compact_task_receiver, sst_manager)
+    }
+
+    /// Runs the next queued flush the way a compaction worker would.
+    fn flush_next(
+        state: &CollectionState,
+        receiver: &Receiver<CompactTask>,
+        sst_manager: &SSTManager,
+    ) {
+        let task = receiver.try_recv().unwrap();
+        let CompactKind::Flush(memtable) = task.kind else {
+            panic!("Expected a flush");
+        };
+        let metadata = sst_manager
+            .write_memtable("test", task.seq_no, task.layer, memtable.as_ref())
+            .unwrap();
+        drop(memtable);
+        state.on_sst_event(SSTEvent::Flushed(metadata)).unwrap();
+    }
+
+    fn stall_options(policy: WriteStallPolicy) -> DatabaseOptions {
+        DatabaseOptions {
+            memtable_size: 2,
+            write_stall: WriteStallConfig {
+                max_frozen_memtables: 1,
+                max_pending_compactions: 8,
+                slowdown_trigger: 1,
+                policy,
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_flush_drops_frozen_memtable_and_wal_segments() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let options = DatabaseOptions {
+            memtable_size: 4,
+            ..Default::default()
+        };
+        let (collection, receiver, sst_manager) = test_collection(dir.path(), &options);
+        let wal_dir = collection.wal_manager.dir().to_path_buf();
+
+        let documents = bulk_random_documents(8, 5);
+        for document in documents.iter() {
+            collection.upsert(document.clone()).unwrap();
+        }
+        assert_eq!(collection.state.frozen_newest_first().unwrap().len(), 1);
+        assert!(segment_exists(&wal_dir, 0));
+
+        flush_next(&collection.state, &receiver, &sst_manager);
+
+        assert!(collection.state.frozen_newest_first().unwrap().is_empty());
+        assert!(!segment_exists(&wal_dir, 0));
+        assert!(segment_exists(&wal_dir, 1));
+        for document in documents.iter() {
+            assert!(collection.fetch(&document.id).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn test_batch_is_validated_first_and_never_split_across_memtables() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let options = DatabaseOptions {
+            memtable_size: 4,
+            ..Default::default()
+        };
+        let (collection, _receiver, _sst_manager) = test_collection(dir.path(), &options);
+
+        let singles = bulk_random_documents(8, 2);
+        for document in singles.iter() {
+            collection.upsert(document.clone()).unwrap();
+        }
+
+        let mut invalid = bulk_random_documents(8, 3);
+        invalid[2].vector.pop();
+        assert!(matches!(
+            collection.upsert_batch(invalid.clone()),
+            Err(CollectionError::InvalidDimension(_))
+        ));
+        assert!(collection.fetch(&invalid[0].id).unwrap().is_none());
+
+        // Does not fit next to the singles, so it starts a fresh memtable
+        let small = bulk_random_documents(8, 3);
+        collection.upsert_batch(small.clone()).unwrap();
+        assert_eq!(collection.state.frozen_newest_first().unwrap().len(), 1);
+        assert_eq!(collection.memtable.read().unwrap().size(), 3);
+
+        // Larger than a memtable, it is frozen on its own
+        let large = bulk_random_documents(8, 6);
+        collection.upsert_batch(large.clone()).unwrap();
+        let frozen = collection.state.frozen_newest_first().unwrap();
+        assert_eq!(frozen.len(), 3);
+        assert_eq!(frozen[0].size(), 6);
+        assert_eq!(collection.memtable.read().unwrap().size(), 0);
+        for document in singles.iter().chain(&small).chain(&large) {
+            assert!(collection.fetch(&document.id).unwrap().is_some());
+        }
+
+        // Each batch is logged in the segment of the memtable that holds it
+        let wal = WalManager::new(dir.path(), "test").unwrap();
+        let segment_ids = |seq_no: u64| -> Vec<u128> {
+            let from = wal.replay(seq_no).unwrap();
+            let after = wal.replay(seq_no + 1).unwrap();
+            from[..from.len() - after.len()]
+                .iter()
+                .map(Record::id)
+                .collect()
+        };
+        let ids =
+            |documents: &[Document]| -> Vec<u128> { documents.iter().map(|d| d.id).collect() };
+        assert_eq!(segment_ids(0), ids(&singles));
+        assert_eq!(segment_ids(1), ids(&small));
+        assert_eq!(segment_ids(2), ids(&large));
+    }
+
+    #[test]
+    fn test_write_stall_error_until_flushed() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let options = stall_options(WriteStallPolicy::Error);
+        let (collection, receiver, sst_manager) = test_collection(dir.path(), &options);
+
+        let documents = bulk_random_documents(8, 3);
+        collection.upsert(documents[0].clone()).unwrap();
+        collection.upsert(documents[1].clone()).unwrap();
+        assert!(matches!(
+            collection.upsert(documents[2].clone()),
+            Err(CollectionError::WriteStall(_))
+        ));
+        // A rejected write never reached the WAL or the memtable
+        assert!(collection.fetch(&documents[2].id).unwrap().is_none());
+
+        let stats = collection.write_stall_stats();
+        assert_eq!((stats.stalls, stats.rejected), (1, 1));
+        assert_eq!(stats.frozen_memtables, 1);
+        assert_eq!(stats.pending_compactions, 1);
+
+        flush_next(&collection.state, &receiver, &sst_manager);
+        collection.upsert(documents[2].clone()).unwrap();
+        assert_eq!(collection.write_stall_stats().pending_compactions, 0);
+    }
+
+    #[test]
+    fn test_write_stall_blocks_until_flushed() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let options = stall_options(WriteStallPolicy::SlowDown(Duration::from_millis(1)));
+        let (collection, receiver, sst_manager) = test_collection(dir.path(), &options);
+
+        let documents = bulk_random_documents(8, 3);
+        collection.upsert(documents[0].clone()).unwrap();
+        collection.upsert(documents[1].clone()).unwrap();
+
+        let state = collection.state();
+        let flusher = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            flush_next(&state, &receiver, &sst_manager);
+        });
+        collection.upsert(documents[2].clone()).unwrap();
+        flusher.join().unwrap();
+
+        let stats = collection.write_stall_stats();
+        assert_eq!((stats.slowdowns, stats.stalls, stats.rejected), (1, 1, 0));
+        assert!(stats.stalled_micros > 0);
+        assert!(collection.fetch(&documents[2].id).unwrap().is_some());
+    }
+
+    fn segment_exists(wal_dir: &Path, seq_no: u64) -> bool {
+        wal_dir.join(format!("test_{:09}.wal", seq_no)).exists()
+    }
+
+    #[test]
+    fn test_wal_failure_rejects_writes_until_reopen() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let options = DatabaseOptions {
+            memtable_size: 2,
+            ..Default::default()
+        };
+        let (collection, receiver, sst_manager) = test_collection(dir.path(), &options);
+
+        let documents = bulk_random_documents(8, 3);
+        collection.upsert(documents[0].clone()).unwrap();
+        collection.upsert(documents[1].clone()).unwrap();
+
+        collection.wal_manager.inject_failure("Input/output error");
+        assert!(matches!(
+            collection.upsert(documents[2].clone()),
+            Err(CollectionError::WalError(_))
+        ));
+        assert!(matches!(collection.health(), CollectionHealth::Failed(_)));
+
+        // Unlike a failing flush, a flush going through does not end it
+        let state = collection.state();
+        flush_next(&state, &receiver, &sst_manager);
+        assert!(matches!(collection.health(), CollectionHealth::Failed(_)));
+        assert!(matches!(
+            collection.delete(&documents[0].id),
+            Err(CollectionError::ReadOnly(_))
+        ));
+        assert!(collection.fetch(&documents[0].id).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_failing_flush_makes_collection_read_only_until_it_succeeds() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let options = stall_options(WriteStallPolicy::Error);
+        let (collection, receiver, sst_manager) = test_collection(dir.path(), &options);
+
+        let documents = bulk_random_documents(8, 3);
+        collection.upsert(documents[0].clone()).unwrap();
+        collection.upsert(documents[1].clone()).unwrap();
+
+        let retrying = |attempts| SSTEvent::FlushRetrying {
+            collection_name: "test".to_string(),
+            seq_no: 1,
+            attempts,
+            error: "No space left on device".to_string(),
+        };
+        let state = collection.state();
+        state.on_sst_event(retrying(1)).unwrap();
+        assert_eq!(collection.health(), CollectionHealth::Healthy);
+        state
+            .on_sst_event(retrying(FLUSH_ATTEMPTS_BEFORE_READ_ONLY))
+            .unwrap();
+        assert!(matches!(collection.health(), CollectionHealth::ReadOnly(_)));
+
+        // Retries do not answer the task, the frozen memtable is still readable
+        assert_eq!(collection.write_stall_stats().pending_compactions, 1);
+        assert!(collection.fetch(&documents[0].id).unwrap().is_some());
+        assert!(matches!(
+            collection.upsert(documents[2].clone()),
+            Err(CollectionError::ReadOnly(_))
+        ));
+
+        flush_next(&state, &receiver, &sst_manager);
+        assert_eq!(collection.health(), CollectionHealth::Healthy);
+        collection.upsert(documents[2].clone()).unwrap();
     }
 }
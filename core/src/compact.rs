@@ -1,23 +1,40 @@
-use crate::SSTEvent;
-use crossbeam_channel::{Receiver, Sender, unbounded};
+use crate::{SSTEvent, SSTMetadata};
+use crossbeam_channel::{Receiver, Sender, select, unbounded};
 use dashmap::DashMap;
-use std::collections::VecDeque;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Duration;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
+use crate::ann::AnnConfig;
+use crate::constant::{
+    COMPACTION_MAX_ATTEMPTS, COMPACTION_RETRY_BASE_DELAY, COMPACTION_RETRY_MAX_DELAY,
+    L0_COMPACTION_TRIGGER, L1_MAX_ENTRIES, LEVEL_SIZE_MULTIPLIER, MAX_COMPACTION_WORKERS,
+    MAX_LEVEL, MIN_COMPACTION_WORKERS, SST_TARGET_ENTRIES, WORKER_IDLE_TIMEOUT,
+};
+use crate::document::Record;
 use crate::memtable::MemTable;
-use crate::sst::SSTManager;
+use crate::payload_index::{PayloadIndex, PayloadIndexConfig};
+use crate::sst::{SSTError, SSTManager};
 
 const DEFAULT_SST_LAYER: u64 = 0;
 
+pub enum CompactKind {
+    /// Write a frozen memtable to L0.
+    Flush(Arc<dyn MemTable>),
+    /// Merge SSTs into the next layer.
+    Merge(MergePlan),
+}
+
 pub struct CompactTask {
     pub collection_name: String,
     pub seq_no: u64,
     pub layer: u64,
-    pub memtable: Arc<dyn MemTable>,
+    pub kind: CompactKind,
 }
 
 impl CompactTask {
@@ -30,11 +47,396 @@ impl CompactTask {
             collection_name,
             seq_no,
             layer: DEFAULT_SST_LAYER,
-            memtable,
+            kind: CompactKind::Flush(memtable),
+        }
+    }
+
+    /// The outputs keep the highest seq_no of the inputs, WAL replay starts from there.
+    pub fn new_merge(collection_name: String, plan: MergePlan) -> Self {
+        Self {
+            collection_name,
+            seq_no: plan.inputs.iter().map(|m| m.seq_no).max().unwrap_or(0),
+            layer: plan.output_layer,
+            kind: CompactKind::Merge(plan),
+        }
+    }
+}
+
+/*
+Leveled compaction
+
+L0 holds one file per memtable flush, their id ranges overlap. Every deeper layer is a
+single sorted run: its files do not overlap and each layer holds older data than the
+layers above it.
+
+- Once L0 has `l0_trigger` files, all of them are merged with the L1 files they overlap.
+- Once a layer Ln (n >= 1) holds more than `level_base_entries * level_multiplier^(n-1)`
+  entries, its oldest file is merged with the Ln+1 files it overlaps. The last layer
+  has no budget.
+
+Only the newest version of an id survives a merge. A tombstone is dropped as well when
+no deeper layer may still hold the id, there is nothing left for it to hide.
+*/
+
+#[derive(Debug, Clone)]
+pub struct CompactionPolicy {
+    pub l0_trigger: usize,
+    pub target_file_entries: usize, // entries per output file
+    pub level_base_entries: u64,    // budget of L1
+    pub level_multiplier: u64,
+    pub max_level: u64,
+    pub payload_indexes: PayloadIndexConfig, // rebuilt for every output
+    pub ann: Option<AnnConfig>,              // built for every output
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        CompactionPolicy {
+            l0_trigger: L0_COMPACTION_TRIGGER,
+            target_file_entries: SST_TARGET_ENTRIES,
+            level_base_entries: L1_MAX_ENTRIES,
+            level_multiplier: LEVEL_SIZE_MULTIPLIER,
+            max_level: MAX_LEVEL,
+            payload_indexes: PayloadIndexConfig::new(),
+            ann: None,
+        }
+    }
+}
+
+pub struct MergePlan {
+    pub inputs: Vec<Arc<SSTMetadata>>, // newest data first
+    pub output_layer: u64,
+    pub deeper_ranges: Vec<(u128, u128)>, // id ranges of every file below the output layer
+    pub target_file_entries: usize,
+    pub payload_indexes: PayloadIndexConfig,
+    pub ann: Option<AnnConfig>,
+}
+
+impl CompactionPolicy {
+    /// The next merge to run given the current layers (as returned by `IndexManager::levels`).
+    pub fn plan(&self, levels: &[Vec<Arc<SSTMetadata>>]) -> Option<MergePlan> {
+        let level = |n: u64| levels.get(n as usize).map(Vec::as_slice).unwrap_or(&[]);
+
+        if level(0).len() >= self.l0_trigger.max(1) {
+            // L0 is in flush order, the newest file goes first
+            let sources = level(0).iter().rev().cloned().collect();
+            return Some(self.plan_into(levels, sources, 1));
+        }
+
+        for n in 1..self.max_level {
+            let entries: u64 = level(n).iter().map(|m| m.entry_count).sum();
+            if entries > self.level_budget(n) {
+                // Oldest first, so every part of the id space keeps moving down
+                let source = level(n).iter().min_by_key(|m| (m.seq_no, m.min_id))?;
+                return Some(self.plan_into(levels, vec![source.clone()], n + 1));
+            }
+        }
+        None
+    }
+
+    fn level_budget(&self, level: u64) -> u64 {
+        self.level_multiplier
+            .saturating_pow((level - 1) as u32)
+            .saturating_mul(self.level_base_entries)
+    }
+
+    fn plan_into(
+        &self,
+        levels: &[Vec<Arc<SSTMetadata>>],
+        mut inputs: Vec<Arc<SSTMetadata>>,
+        output_layer: u64,
+    ) -> MergePlan {
+        let min_id = inputs.iter().map(|m| m.min_id).min().unwrap_or(0);
+        let max_id = inputs.iter().map(|m| m.max_id).max().unwrap_or(0);
+
+        // Taking every overlapping file keeps the output layer free of overlaps
+        if let Some(targets) = levels.get(output_layer as usize) {
+            inputs.extend(
+                targets
+                    .iter()
+                    .filter(|m| m.min_id <= max_id && m.max_id >= min_id)
+                    .cloned(),
+            );
+        }
+
+        MergePlan {
+            inputs,
+            output_layer,
+            deeper_ranges: levels
+                .iter()
+                .skip(output_layer as usize + 1)
+                .flatten()
+                .map(|m| (m.min_id, m.max_id))
+                .collect(),
+            target_file_entries: self.target_file_entries.max(1),
+            payload_indexes: self.payload_indexes.clone(),
+            ann: self.ann.clone(),
+        }
+    }
+}
+
+/// K-way merge of sorted runs given newest first, pulling one record of a run at a
+/// time. Only the newest record of an id is kept, and a tombstone only if
+/// `may_hide_older(id)`. A failed read ends the merge with its error.
+struct MergeRecords<I, F> {
+    runs: Vec<I>,
+    heads: Vec<Option<Record>>,
+    heap: BinaryHeap<Reverse<(u128, usize)>>,
+    last_id: Option<u128>,
+    may_hide_older: F,
+}
+
+impl<I, F> MergeRecords<I, F>
+where
+    I: Iterator<Item = Result<Record, SSTError>>,
+    F: Fn(u128) -> bool,
+{
+    fn new(runs: Vec<I>, may_hide_older: F) -> Result<Self, SSTError> {
+        let mut merge = MergeRecords {
+            heads: vec![None; runs.len()],
+            runs,
+            heap: BinaryHeap::new(),
+            last_id: None,
+            may_hide_older,
+        };
+        for run in 0..merge.runs.len() {
+            merge.advance(run)?;
+        }
+        Ok(merge)
+    }
+
+    /// Reads the next record of `run` into its head.
+    fn advance(&mut self, run: usize) -> Result<(), SSTError> {
+        if let Some(record) = self.runs[run].next().transpose()? {
+            // Ties on the id pop the lowest run index first, which is the newest version
+            self.heap.push(Reverse((record.id(), run)));
+            self.heads[run] = Some(record);
+        }
+        Ok(())
+    }
+}
+
+impl<I, F> Iterator for MergeRecords<I, F>
+where
+    I: Iterator<Item = Result<Record, SSTError>>,
+    F: Fn(u128) -> bool,
+{
+    type Item = Result<Record, SSTError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(Reverse((id, run))) = self.heap.pop() {
+            let record = self.heads[run]
+                .take()
+                .expect("heap entries point at a record");
+            if let Err(e) = self.advance(run) {
+                self.heap.clear();
+                return Some(Err(e));
+            }
+
+            if self.last_id == Some(id) {
+                continue;
+            }
+            self.last_id = Some(id);
+
+            if matches!(record, Record::Tombstone(_)) && !(self.may_hide_older)(id) {
+                continue;
+            }
+            return Some(Ok(record));
+        }
+        None
+    }
+}
+
+/// Runs a merge up to its commit, returns the outputs and the compaction log.
+/// On error nothing changed on disk and the inputs are still live.
+///
+/// Inputs are read a block at a time and outputs are cut as they fill, so only the
+/// output being written is held in memory, never the whole merge.
+pub fn run_merge(
+    sst_manager: &SSTManager,
+    collection_name: &str,
+    seq_no: u64,
+    plan: &MergePlan,
+) -> Result<(Vec<SSTMetadata>, PathBuf), SSTError> {
+    let runs = plan
+        .inputs
+        .iter()
+        .map(|metadata| sst_manager.records(metadata))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut merged = MergeRecords::new(runs, |id| {
+        plan.deeper_ranges
+            .iter()
+            .any(|(min_id, max_id)| *min_id <= id && id <= *max_id)
+    })?
+    .peekable();
+    let mut remaining: usize = plan.inputs.iter().map(|m| m.entry_count as usize).sum();
+
+    let mut outputs = Vec::new();
+    let mut indexes = Vec::new();
+    while merged.peek().is_some() {
+        let mut index = PayloadIndex::new(&plan.payload_indexes);
+        let mut read_error = None;
+        let records = merged
+            .by_ref()
+            .take(plan.target_file_entries)
+            .map_while(|record| record.map_err(|e| read_error = Some(e)).ok())
+            .inspect(|record| {
+                if let Record::Live(document) = record {
+                    index.insert(document);
+                }
+            });
+        let written = sst_manager.write_compaction_output(
+            collection_name,
+            seq_no,
+            plan.output_layer,
+            records,
+            plan.target_file_entries.min(remaining),
+            plan.ann.as_ref(),
+        );
+
+        // An output cut short by a failed read is dropped along with the others
+        let error = match written {
+            Ok(metadata) => {
+                remaining = remaining.saturating_sub(metadata.entry_count as usize);
+                outputs.push(metadata);
+                read_error
+            }
+            Err(e) => Some(e.into()),
+        };
+        if let Some(e) = error {
+            let paths: Vec<PathBuf> = outputs.iter().map(|m| m.path.clone()).collect();
+            sst_manager.abort_compaction(&paths);
+            return Err(e);
+        }
+        indexes.push(index);
+    }
+
+    let inputs: Vec<PathBuf> = plan.inputs.iter().map(|m| m.path.clone()).collect();
+    let output_paths: Vec<PathBuf> = outputs.iter().map(|m| m.path.clone()).collect();
+    let log_path = sst_manager.commit_compaction(collection_name, &inputs, &output_paths)?;
+
+    // The merge is already committed, an output without sidecar is scanned after a reopen
+    if !plan.payload_indexes.is_empty() {
+        for (metadata, index) in outputs.iter_mut().zip(indexes) {
+            if let Err(e) = sst_manager.write_payload_index(&metadata.path, &index) {
+                eprintln!(
+                    "WARN: failed to write the payload index of {}: {}",
+                    metadata.path.display(),
+                    e
+                );
+            }
+            metadata.payload_index = Some(Arc::new(index));
+        }
+    }
+    Ok((outputs, log_path))
+}
+
+fn run_task(sst_manager: &SSTManager, task: &CompactTask) -> Result<SSTEvent, SSTError> {
+    match &task.kind {
+        CompactKind::Flush(memtable) => {
+            let metadata = sst_manager.write_memtable(
+                task.collection_name.as_str(),
+                task.seq_no,
+                task.layer,
+                memtable.as_ref(),
+            )?;
+            Ok(SSTEvent::Flushed(metadata))
+        }
+        CompactKind::Merge(plan) => {
+            let (added, log_path) =
+                run_merge(sst_manager, &task.collection_name, task.seq_no, plan)?;
+            Ok(SSTEvent::Compacted {
+                collection_name: task.collection_name.clone(),
+                added,
+                removed: plan.inputs.clone(),
+                log_path,
+            })
         }
     }
 }
 
+/// One try of `task`, a panic is reported like any other error.
+fn attempt_task(sst_manager: &SSTManager, task: &CompactTask) -> Result<SSTEvent, String> {
+    match panic::catch_unwind(AssertUnwindSafe(|| run_task(sst_manager, task))) {
+        Ok(result) => result.map_err(|e| e.to_string()),
+        Err(payload) => Err(match payload.downcast_ref::<&str>() {
+            Some(msg) => format!("panicked: {}", msg),
+            None => match payload.downcast_ref::<String>() {
+                Some(msg) => format!("panicked: {}", msg),
+                None => "panicked".to_string(),
+            },
+        }),
+    }
+}
+
+/// Runs `task` until it succeeds, backing off between attempts, and returns the event
+/// that answers it.
+///
+/// A merge gives up after `COMPACTION_MAX_ATTEMPTS`, its inputs stay live and the
+/// collection plans it again later. A flush cannot be skipped: later flushes of the
+/// collection would produce SSTs whose seq_no hides its WAL segments from replay. It is
+/// retried until the disk recovers, every failure reported through `report`, and only
+/// gives up once `draining` is set, leaving the data to WAL replay.
+fn run_with_retries(
+    sst_manager: &SSTManager,
+    task: &CompactTask,
+    draining: &AtomicBool,
+    mut report: impl FnMut(SSTEvent),
+) -> SSTEvent {
+    let mut attempts = 0;
+    let mut delay = COMPACTION_RETRY_BASE_DELAY;
+    loop {
+        let error = match attempt_task(sst_manager, task) {
+            Ok(event) => return event,
+            Err(error) => error,
+        };
+        attempts += 1;
+        eprintln!(
+            "WARN: compaction task of {} into L{} failed (attempt {}): {}",
+            task.collection_name, task.layer, attempts, error
+        );
+
+        let give_up = attempts >= COMPACTION_MAX_ATTEMPTS;
+        match &task.kind {
+            CompactKind::Merge(_) if give_up => {
+                return SSTEvent::CompactionFailed {
+                    collection_name: task.collection_name.clone(),
+                    error,
+                };
+            }
+            CompactKind::Flush(_) if give_up && draining.load(Ordering::SeqCst) => {
+                return SSTEvent::FlushFailed {
+                    collection_name: task.collection_name.clone(),
+                    seq_no: task.seq_no,
+                    error,
+                };
+            }
+            CompactKind::Flush(_) => report(SSTEvent::FlushRetrying {
+                collection_name: task.collection_name.clone(),
+                seq_no: task.seq_no,
+                attempts,
+                error,
+            }),
+            CompactKind::Merge(_) => {}
+        }
+
+        sleep_unless_draining(delay, draining);
+        delay = (delay * 2).min(COMPACTION_RETRY_MAX_DELAY);
+    }
+}
+
+/// Sleeps for `delay`, cut short once the manager starts draining.
+fn sleep_unless_draining(delay: Duration, draining: &AtomicBool) {
+    let deadline = Instant::now() + delay;
+    while !draining.load(Ordering::SeqCst) {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        thread::sleep((deadline - now).min(COMPACTION_RETRY_BASE_DELAY));
+    }
+}
+
 /*
 Inspired by Go's GPM model
 
@@ -67,25 +469,48 @@ Multi-Lane Executor (Found the name on internet ^ ^)
                       └────────────────────────────────────────────────┘
  */
 
+/*
+Multi-lane executor
+
+Every collection has a lane holding its tasks in submission order. A lane is run by at
+most one worker at a time, so the tasks of a collection never overtake each other, while
+different collections run in parallel.
+
+A lane with tasks and no worker waits in the run queue. A worker takes the lane at the
+front, runs one task and puts the lane back at the end if it still has tasks, so every
+collection gets a turn before any other one gets a second. Idle workers sleep on a
+condvar and are woken when a lane becomes ready. More workers are spawned, up to
+`max_worker_count`, while ready lanes outnumber idle workers, and the extra ones exit
+after `WORKER_IDLE_TIMEOUT` without work.
+*/
+
 pub struct Lane {
-    pub task_queue: Mutex<VecDeque<CompactTask>>,
-    pub is_processing: AtomicBool,
+    state: Mutex<LaneState>,
+}
+
+struct LaneState {
+    tasks: VecDeque<CompactTask>,
+    scheduled: bool, // waiting in the run queue or held by a worker
 }
 
 impl Lane {
     pub fn new() -> Self {
-        Lane {
-            task_queue: Mutex::new(VecDeque::with_capacity(10)),
-            is_processing: AtomicBool::new(false),
-        }
+        Self::with_capacity(10)
     }
 
     pub fn with_capacity(capacity: usize) -> Self {
         Lane {
-            task_queue: Mutex::new(VecDeque::with_capacity(capacity)),
-            is_processing: AtomicBool::new(false),
+            state: Mutex::new(LaneState {
+                tasks: VecDeque::with_capacity(capacity),
+                scheduled: false,
+            }),
         }
     }
+
+    fn lock(&self) -> MutexGuard<'_, LaneState> {
+        // Tasks run outside the lock, a poisoned lane still holds consistent state
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
 }
 
 impl Default for Lane {
@@ -94,120 +519,766 @@ impl Default for Lane {
     }
 }
 
-// NOTE: We should have a dynamic worker count, scale with the number of tasks
+/// Drops the flushes queued behind one that failed for good, answering each of them.
+/// Merges stay queued, they only touch SSTs that are already on disk.
+fn skip_queued_flushes(lane: &Lane) -> Vec<SSTEvent> {
+    let mut events = Vec::new();
+    lane.lock().tasks.retain(|task| {
+        if !matches!(task.kind, CompactKind::Flush(_)) {
+            return true;
+        }
+        events.push(SSTEvent::FlushFailed {
+            collection_name: task.collection_name.clone(),
+            seq_no: task.seq_no,
+            error: "skipped after an earlier flush failed".to_string(),
+        });
+        false
+    });
+    events
+}
+
+struct RunQueue {
+    ready: VecDeque<Arc<Lane>>, // lanes with tasks and no worker, served in order
+    workers: usize,
+    idle_workers: usize,
+    shutdown: bool,
+}
 
-pub struct CompactionManager {
-    lanes: Arc<DashMap<String, Lane>>,
+struct Scheduler {
+    lanes: DashMap<String, Arc<Lane>>,
+    run_queue: Mutex<RunQueue>,
+    lane_ready: Condvar,
     min_worker_count: usize,
     max_worker_count: usize,
     default_lane_capacity: usize,
     sst_manager: Arc<SSTManager>,
     sst_event_sender: Sender<SSTEvent>,
+    draining: AtomicBool, // set once the database is closing, failed flushes stop retrying
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Scheduler {
+    fn lock_run_queue(&self) -> MutexGuard<'_, RunQueue> {
+        self.run_queue
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn enqueue(self: &Arc<Self>, task: CompactTask) {
+        let lane = self
+            .lanes
+            .entry(task.collection_name.clone())
+            .or_insert_with(|| Arc::new(Lane::with_capacity(self.default_lane_capacity)))
+            .clone();
+
+        let mut state = lane.lock();
+        state.tasks.push_back(task);
+        if state.scheduled {
+            return;
+        }
+        state.scheduled = true;
+        drop(state);
+        self.make_ready(lane);
+    }
+
+    /// Queues a lane for the next free worker, spawning one if every worker is busy.
+    fn make_ready(self: &Arc<Self>, lane: Arc<Lane>) {
+        let mut run_queue = self.lock_run_queue();
+        run_queue.ready.push_back(lane);
+        if run_queue.ready.len() > run_queue.idle_workers
+            && run_queue.workers < self.max_worker_count
+            && !run_queue.shutdown
+        {
+            self.spawn_worker(&mut run_queue);
+        }
+        self.lane_ready.notify_one();
+    }
+
+    /// Must be called with the run queue locked, so that shutdown sees every worker.
+    fn spawn_worker(self: &Arc<Self>, run_queue: &mut RunQueue) {
+        run_queue.workers += 1;
+        let scheduler = self.clone();
+        let handle = thread::spawn(move || scheduler.run_worker());
+
+        let mut threads = self.threads.lock().unwrap_or_else(PoisonError::into_inner);
+        threads.retain(|handle| !handle.is_finished());
+        threads.push(handle);
+    }
+
+    fn run_worker(self: Arc<Self>) {
+        let _exit = WorkerExit { scheduler: &self };
+        while let Some(lane) = self.next_lane() {
+            let turn = LaneTurn {
+                scheduler: &self,
+                lane,
+            };
+            turn.run();
+        }
+    }
+
+    /// Blocks until a lane is ready. `None` tells the worker to exit: the executor shuts
+    /// down with nothing left to run, or the worker is surplus and has been idle.
+    fn next_lane(&self) -> Option<Arc<Lane>> {
+        let mut run_queue = self.lock_run_queue();
+        loop {
+            if let Some(lane) = run_queue.ready.pop_front() {
+                return Some(lane);
+            }
+            if run_queue.shutdown {
+                run_queue.workers -= 1;
+                return None;
+            }
+
+            run_queue.idle_workers += 1;
+            let (guard, timeout) = self
+                .lane_ready
+                .wait_timeout(run_queue, WORKER_IDLE_TIMEOUT)
+                .unwrap_or_else(PoisonError::into_inner);
+            run_queue = guard;
+            run_queue.idle_workers -= 1;
+
+            if timeout.timed_out()
+                && run_queue.ready.is_empty()
+                && run_queue.workers > self.min_worker_count
+            {
+                run_queue.workers -= 1;
+                return None;
+            }
+        }
+    }
+}
+
+/// Gives up the worker slot of a thread that panicked outside its task, a worker that
+/// returns normally has already done so in `next_lane`.
+struct WorkerExit<'a> {
+    scheduler: &'a Scheduler,
+}
+
+impl Drop for WorkerExit<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.scheduler.lock_run_queue().workers -= 1;
+        }
+    }
+}
+
+/// One task of a lane. Dropping it ends the turn, also while unwinding: the lane goes
+/// back to the run queue if it has more tasks and is released otherwise.
+struct LaneTurn<'a> {
+    scheduler: &'a Arc<Scheduler>,
+    lane: Arc<Lane>,
+}
+
+impl LaneTurn<'_> {
+    fn run(&self) {
+        let Some(task) = self.lane.lock().tasks.pop_front() else {
+            return;
+        };
+        let scheduler = self.scheduler;
+
+        let event = run_with_retries(&scheduler.sst_manager, &task, &scheduler.draining, |e| {
+            send_event(&scheduler.sst_event_sender, e);
+        });
+        let flush_failed = matches!(event, SSTEvent::FlushFailed { .. });
+        if !send_event(&scheduler.sst_event_sender, event) {
+            return;
+        }
+
+        // Later flushes must not overtake the failed one
+        if flush_failed {
+            for event in skip_queued_flushes(&self.lane) {
+                send_event(&scheduler.sst_event_sender, event);
+            }
+        }
+    }
+}
+
+/// Hands an event to the dispatcher. Once it is gone, while the database is closing,
+/// nobody is left to apply the event: it is dropped with a warning rather than taking
+/// the worker down. Returns whether the event was sent.
+fn send_event(sender: &Sender<SSTEvent>, event: SSTEvent) -> bool {
+    match sender.send(event) {
+        Ok(()) => true,
+        Err(_) => {
+            eprintln!("WARN: dropping SST event, the event receiver is gone");
+            false
+        }
+    }
+}
+
+impl Drop for LaneTurn<'_> {
+    fn drop(&mut self) {
+        let mut state = self.lane.lock();
+        if state.tasks.is_empty() {
+            state.scheduled = false;
+            return;
+        }
+        drop(state);
+        self.scheduler.make_ready(self.lane.clone());
+    }
+}
+
+pub struct CompactionManager {
+    scheduler: Arc<Scheduler>,
+    shutdown_sender: Mutex<Option<Sender<()>>>, // dropped on shutdown, which wakes the dispatcher
+    shutdown_receiver: Receiver<()>,
 }
 
 impl CompactionManager {
-    pub fn new(path: PathBuf, sst_event_sender: Sender<SSTEvent>) -> Self {
+    pub fn new(sst_manager: Arc<SSTManager>, sst_event_sender: Sender<SSTEvent>) -> Self {
+        Self::with_worker_count(
+            sst_manager,
+            sst_event_sender,
+            MIN_COMPACTION_WORKERS,
+            MAX_COMPACTION_WORKERS,
+        )
+    }
+
+    pub fn with_worker_count(
+        sst_manager: Arc<SSTManager>,
+        sst_event_sender: Sender<SSTEvent>,
+        min_worker_count: usize,
+        max_worker_count: usize,
+    ) -> Self {
+        let (shutdown_sender, shutdown_receiver) = unbounded();
         CompactionManager {
-            lanes: Arc::new(DashMap::new()),
-            min_worker_count: 4,
-            max_worker_count: 16,
-            default_lane_capacity: 50,
-            sst_manager: Arc::new(SSTManager::new(path)),
-            sst_event_sender: sst_event_sender,
+            scheduler: Arc::new(Scheduler {
+                lanes: DashMap::new(),
+                run_queue: Mutex::new(RunQueue {
+                    ready: VecDeque::new(),
+                    workers: 0,
+                    idle_workers: 0,
+                    shutdown: false,
+                }),
+                lane_ready: Condvar::new(),
+                min_worker_count,
+                max_worker_count: max_worker_count.max(min_worker_count),
+                default_lane_capacity: 50,
+                sst_manager,
+                sst_event_sender,
+                draining: AtomicBool::new(false),
+                threads: Mutex::new(Vec::new()),
+            }),
+            shutdown_sender: Mutex::new(Some(shutdown_sender)),
+            shutdown_receiver,
         }
     }
 
     pub fn spin_up_dispatcher(&self) -> Sender<CompactTask> {
         let (sx, rx): (Sender<CompactTask>, Receiver<CompactTask>) = unbounded();
-        let lanes = self.lanes.clone();
-        let default_lane_capacity = self.default_lane_capacity;
+        let scheduler = self.scheduler.clone();
+        let shutdown_receiver = self.shutdown_receiver.clone();
 
-        thread::spawn(move || {
-            while let Ok(task) = rx.recv() {
-                let lane = lanes
-                    .entry(task.collection_name.clone())
-                    .or_insert_with(|| Lane::with_capacity(default_lane_capacity));
-
-                lane.task_queue.lock().unwrap().push_back(task);
+        // Collections keep a sender around, so the channel may never disconnect on its own
+        let handle = thread::spawn(move || {
+            loop {
+                select! {
+                    recv(rx) -> task => {
+                        let Ok(task) = task else {
+                            break;
+                        };
+                        scheduler.enqueue(task);
+                    }
+                    recv(shutdown_receiver) -> _ => break,
+                }
             }
         });
+        self.scheduler.threads.lock().unwrap().push(handle);
         sx
     }
 
     pub fn spin_up_workers(&self) {
-        for _ in 0..self.min_worker_count {
-            let lanes = self.lanes.clone();
-            let sst_manager = self.sst_manager.clone();
-            let sst_event_sender = self.sst_event_sender.clone();
-            thread::spawn(move || {
-                loop {
-                    // All workers should check all lanes
-                    // and pick one task to process (if the lane is not processing)
-                    // if no task is found, sleep for a while
-
-                    let mut task_found = false;
-
-                    for lane in lanes.iter() {
-                        if lane
-                            .is_processing
-                            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
-                            .is_ok()
-                        {
-                            // TODO: Use a RAII Guard (LaneProcessingGuard) here to ensure `is_processing` is reset to false
-                            // even if a panic occurs in the critical section (e.g. during disk write).
-                            // Currently, if a panic happens, the flag remains true and the lane becomes dead.
-                            let task_option = match lane.task_queue.lock() {
-                                Ok(mut queue) => queue.pop_front(),
-                                Err(_) => {
-                                    eprintln!("WARN: task_queue lock was poisoned, recovering...");
-                                    None
-                                }
-                            };
-
-                            // TODO: Handle when sst failed to write to disk or something
-                            if let Some(task) = task_option {
-                                task_found = true;
-
-                                let sst_metadata = sst_manager.write_memtable(
-                                    task.collection_name.as_str(),
-                                    task.seq_no,
-                                    task.layer,
-                                    task.memtable.as_ref(),
-                                );
-
-                                match sst_metadata {
-                                    Ok(metadata) => {
-                                        sst_event_sender.send(SSTEvent { metadata }).unwrap();
-                                    }
-                                    Err(e) => {
-                                        panic!(
-                                            "Not implement yet, Need to do this properly, we can add status to SSTEvent and determine by database level: {}",
-                                            e
-                                        );
-                                    }
-                                }
-                            }
-                            lane.is_processing.store(false, Ordering::SeqCst);
-                        }
-
-                        // TODO: Implement Round-Robin Fairness Strategy
-                        // Current linear scan (from start to end) causes starvation for lanes at the end of the map.
-                        // Workers should maintain a `current_offset` and start scanning from there, incrementing it after each check.
-                        // offset = (offset + 1) % distinct_lanes_count
-                        if task_found {
-                            break;
-                        }
-                    }
+        let mut run_queue = self.scheduler.lock_run_queue();
+        while run_queue.workers < self.scheduler.min_worker_count {
+            self.scheduler.spawn_worker(&mut run_queue);
+        }
+    }
 
-                    if !task_found {
-                        // TODO: This is inefficient as it consumes CPU cycles even when there is no work.
-                        // A more efficient, event-driven approach would be to use a notification mechanism
-                        // (like a condition variable, or another crossbeam-channel)
-                        // to wake up idle workers only when new tasks are available.
-                        thread::sleep(Duration::from_millis(100));
-                    }
-                }
+    /// Failed flushes stop retrying forever, so the queued tasks can drain.
+    pub fn begin_shutdown(&self) {
+        self.scheduler.draining.store(true, Ordering::SeqCst);
+    }
+
+    /// Stops the dispatcher and the workers once the run queue is empty and joins them.
+    /// Callers wait for the queued tasks first, see `AetherDB::close`.
+    pub fn shutdown(&self) {
+        self.scheduler.lock_run_queue().shutdown = true;
+        self.scheduler.lane_ready.notify_all();
+        self.shutdown_sender.lock().unwrap().take();
+
+        let threads: Vec<JoinHandle<()>> =
+            self.scheduler.threads.lock().unwrap().drain(..).collect();
+        for handle in threads {
+            if handle.join().is_err() {
+                eprintln!("WARN: a compaction thread panicked");
+            }
+        }
+    }
+
+    /// Workers currently alive, between the min and max worker count.
+    pub fn worker_count(&self) -> usize {
+        self.scheduler.lock_run_queue().workers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collection::{DistanceType, IndexConfig};
+    use crate::constant::DEFAULT_MEMTABLE_SIZE;
+    use crate::document::Document;
+    use crate::index::IndexManager;
+    use crate::ivf::TrainedCentroids;
+    use crate::memtable::get_memtable;
+    use crate::search::SearchManager;
+    use std::io::{Seek, SeekFrom, Write};
+    use tempfile::tempdir;
+
+    fn metadata(seq_no: u64, layer: u64, min_id: u128, max_id: u128) -> Arc<SSTMetadata> {
+        Arc::new(SSTMetadata {
+            collection_name: "test".to_string(),
+            seq_no,
+            layer,
+            min_id,
+            max_id,
+            path: PathBuf::from(format!("L{}/{}-{}", layer, seq_no, min_id)),
+            entry_count: 10,
+            payload_index: None,
+        })
+    }
+
+    /// Writes an SST holding `upserts` as `[id, version]` vectors and tombstones for `deletes`.
+    fn write_sst(
+        sst_manager: &SSTManager,
+        seq_no: u64,
+        layer: u64,
+        upserts: &[(u128, f32)],
+        deletes: &[u128],
+    ) -> SSTMetadata {
+        let mut memtable = get_memtable(
+            &IndexConfig::new_with_default_config("flat").unwrap(),
+            &DistanceType::L2,
+            DEFAULT_MEMTABLE_SIZE,
+            &TrainedCentroids::default(),
+        );
+        for (id, version) in upserts {
+            memtable.upsert(Document {
+                id: *id,
+                vector: vec![*id as f32, *version],
+                content: String::new(),
+                payload: Default::default(),
+                external_id: None,
             });
         }
+        for id in deletes {
+            memtable.delete(id);
+        }
+        sst_manager
+            .write_memtable("test", seq_no, layer, memtable.as_ref())
+            .unwrap()
+    }
+
+    fn policy(l0_trigger: usize, target_file_entries: usize) -> CompactionPolicy {
+        CompactionPolicy {
+            l0_trigger,
+            target_file_entries,
+            level_base_entries: 35,
+            level_multiplier: 10,
+            max_level: 3,
+            payload_indexes: PayloadIndexConfig::new(),
+            ann: None,
+        }
+    }
+
+    #[test]
+    fn test_plan_picks_overlapping_files() {
+        let policy = policy(3, 100);
+        let l1 = vec![
+            metadata(1, 1, 0, 10),
+            metadata(1, 1, 20, 30),
+            metadata(1, 1, 100, 200),
+        ];
+        let l2 = vec![metadata(0, 2, 0, 1000)];
+
+        let mut levels = vec![
+            vec![metadata(2, 0, 5, 8), metadata(3, 0, 22, 25)],
+            l1.clone(),
+            l2,
+        ];
+        assert!(policy.plan(&levels).is_none());
+
+        levels[0].push(metadata(4, 0, 6, 7));
+        let plan = policy.plan(&levels).unwrap();
+        let seq_nos: Vec<u64> = plan.inputs.iter().map(|m| m.seq_no).collect();
+        assert_eq!(seq_nos, vec![4, 3, 2, 1, 1]);
+        assert_eq!(plan.inputs[3].min_id, 0);
+        assert_eq!(plan.inputs[4].min_id, 20);
+        assert_eq!(plan.output_layer, 1);
+        assert_eq!(plan.deeper_ranges, vec![(0, 1000)]);
+
+        // 40 entries in L1 is over its budget of 35, the oldest file moves down
+        let mut l1 = l1;
+        l1.push(metadata(0, 1, 300, 400));
+        let levels = vec![vec![], l1, vec![metadata(0, 2, 350, 360)]];
+        let plan = policy.plan(&levels).unwrap();
+        let ranges: Vec<(u128, u128)> = plan.inputs.iter().map(|m| (m.min_id, m.max_id)).collect();
+        assert_eq!(ranges, vec![(300, 400), (350, 360)]);
+        assert_eq!(plan.output_layer, 2);
+        assert!(plan.deeper_ranges.is_empty());
+    }
+
+    #[test]
+    fn test_merge_keeps_newest_versions() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let sst_manager = Arc::new(SSTManager::new(dir.path().to_path_buf()));
+        let index_manager = Arc::new(IndexManager::default());
+
+        // Only the tombstone of 4 still has something to hide once the merge is done
+        index_manager.add_sst_metadata(write_sst(&sst_manager, 0, 2, &[(4, 0.0)], &[]));
+        index_manager.add_sst_metadata(write_sst(
+            &sst_manager,
+            1,
+            0,
+            &[(1, 0.0), (2, 0.0), (3, 0.0)],
+            &[],
+        ));
+        index_manager.add_sst_metadata(write_sst(&sst_manager, 2, 0, &[(2, 1.0)], &[3]));
+        index_manager.add_sst_metadata(write_sst(&sst_manager, 3, 0, &[(5, 0.0)], &[4]));
+
+        let plan = policy(3, 2).plan(&index_manager.levels()).unwrap();
+        let inputs: Vec<PathBuf> = plan.inputs.iter().map(|m| m.path.clone()).collect();
+        let Ok(SSTEvent::Compacted {
+            added,
+            removed,
+            log_path,
+            ..
+        }) = run_task(
+            &sst_manager,
+            &CompactTask::new_merge("test".to_string(), plan),
+        )
+        else {
+            panic!("Expected the merge to succeed");
+        };
+
+        // 1, 2, tombstone 4 and 5, two entries per file
+        assert_eq!(added.len(), 2);
+        assert!(added.iter().all(|m| m.layer == 1 && m.seq_no == 3));
+        assert_eq!((added[0].min_id, added[0].max_id), (1, 2));
+        assert_eq!((added[1].min_id, added[1].max_id), (4, 5));
+        assert!(added.iter().all(|m| m.path.exists()));
+
+        index_manager.replace_sst_metadata(added, &removed);
+        let levels = index_manager.levels();
+        assert!(levels[0].is_empty());
+        assert_eq!(levels[1].len(), 2);
+
+        let search_manager =
+            SearchManager::new(index_manager, sst_manager.clone(), DistanceType::L2);
+        let fetch = |id: u128| {
+            search_manager
+                .fetch(&id, &[])
+                .unwrap()
+                .map(|document| document.vector[1])
+        };
+        assert_eq!(fetch(1), Some(0.0));
+        assert_eq!(fetch(2), Some(1.0));
+        assert_eq!(fetch(3), None);
+        assert_eq!(fetch(4), None);
+        assert_eq!(fetch(5), Some(0.0));
+
+        sst_manager.finish_compaction(&log_path, &inputs).unwrap();
+        assert!(inputs.iter().all(|path| !path.exists()));
+        assert!(!log_path.exists());
+
+        let reloaded = SSTManager::new(dir.path().to_path_buf())
+            .load_metadata("test")
+            .unwrap();
+        let layers: Vec<u64> = reloaded.iter().map(|m| m.layer).collect();
+        assert_eq!(layers, vec![1, 1, 2]);
+        assert_eq!(reloaded.iter().map(|m| m.seq_no).max(), Some(3));
+    }
+
+    #[test]
+    fn test_committed_merge_is_rolled_forward_on_load() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let sst_manager = SSTManager::new(dir.path().to_path_buf());
+
+        let levels = vec![vec![
+            Arc::new(write_sst(&sst_manager, 1, 0, &[(1, 0.0)], &[])),
+            Arc::new(write_sst(&sst_manager, 2, 0, &[(2, 0.0)], &[])),
+        ]];
+        let plan = policy(2, 10).plan(&levels).unwrap();
+        let (outputs, log_path) = run_merge(&sst_manager, "test", 2, &plan).unwrap();
+
+        // Crash after the commit, plus an output of a merge that never got committed
+        let orphan = sst_manager
+            .write_compaction_output("test", 2, 1, std::iter::empty(), 0, None)
+            .unwrap();
+        drop(levels);
+
+        let reloaded = SSTManager::new(dir.path().to_path_buf())
+            .load_metadata("test")
+            .unwrap();
+        assert_eq!(reloaded.len(), 1);
+        assert_eq!(reloaded[0].path, outputs[0].path);
+        assert_eq!(reloaded[0].entry_count, 2);
+        assert!(plan.inputs.iter().all(|m| !m.path.exists()));
+        assert!(!log_path.exists());
+        assert!(!orphan.path.exists());
+        assert_eq!(
+            std::fs::read_dir(outputs[0].path.parent().unwrap())
+                .unwrap()
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_merge_streams_inputs_into_outputs_cut_as_they_fill() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let sst_manager = SSTManager::new(dir.path().to_path_buf());
+        let older: Vec<(u128, f32)> = (0..1000).map(|id| (id, 0.0)).collect();
+        let newer: Vec<(u128, f32)> = (500..1500).map(|id| (id, 1.0)).collect();
+
+        // Inputs in flush order span many blocks, 1500 ids end up in outputs of 400
+        let levels = vec![vec![
+            Arc::new(write_sst(&sst_manager, 1, 0, &older, &[])),
+            Arc::new(write_sst(&sst_manager, 2, 0, &newer, &[])),
+        ]];
+        assert!(
+            sst_manager
+                .table(&levels[0][0])
+                .unwrap()
+                .block_handles()
+                .len()
+                > 1
+        );
+        let plan = policy(2, 400).plan(&levels).unwrap();
+        let (outputs, _) = run_merge(&sst_manager, "test", 2, &plan).unwrap();
+
+        let counts: Vec<u64> = outputs.iter().map(|m| m.entry_count).collect();
+        assert_eq!(counts, vec![400, 400, 400, 300]);
+        let records: Vec<Record> = outputs
+            .iter()
+            .flat_map(|m| sst_manager.scan(m).unwrap())
+            .collect();
+        assert!(records.iter().map(Record::id).eq(0..1500));
+        assert!(records.iter().all(|record| match record {
+            Record::Live(doc) => doc.vector[1] == if doc.id < 500 { 0.0 } else { 1.0 },
+            Record::Tombstone(_) => false,
+        }));
+
+        // A damaged block of an input fails the merge, outputs written so far are dropped
+        let levels = vec![vec![
+            Arc::new(write_sst(&sst_manager, 3, 0, &older, &[])),
+            Arc::new(write_sst(&sst_manager, 4, 0, &newer, &[])),
+        ]];
+        let table = sst_manager.table(&levels[0][0]).unwrap();
+        let damaged = table.block_handles()[5].offset;
+        drop(table);
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&levels[0][0].path)
+            .unwrap();
+        file.seek(SeekFrom::Start(damaged + 20)).unwrap();
+        file.write_all(&[0xff; 4]).unwrap();
+
+        let plan = policy(2, 400).plan(&levels).unwrap();
+        assert!(matches!(
+            run_merge(&sst_manager, "test", 4, &plan),
+            Err(SSTError::Corrupted(_))
+        ));
+        assert!(plan.inputs.iter().all(|m| m.path.exists()));
+        let l1: Vec<PathBuf> = std::fs::read_dir(outputs[0].path.parent().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(l1.len(), outputs.len());
+    }
+
+    #[test]
+    fn test_merge_rebuilds_payload_indexes() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let sst_manager = SSTManager::new(dir.path().to_path_buf());
+        let config = IndexConfig::new_with_default_config("flat")
+            .unwrap()
+            .with_payload_index("color", crate::PayloadIndexType::Keyword);
+        let sidecar = |path: &PathBuf| path.with_extension("sst.pidx");
+
+        let mut levels = vec![vec![]];
+        for (seq_no, colors) in [(1, ["red", "blue"]), (2, ["blue", "red"])] {
+            let mut memtable = get_memtable(
+                &config,
+                &DistanceType::L2,
+                DEFAULT_MEMTABLE_SIZE,
+                &TrainedCentroids::default(),
+            );
+            for (id, color) in colors.iter().enumerate() {
+                memtable.upsert(
+                    Document {
+                        id: id as u128,
+                        vector: vec![0.0],
+                        content: String::new(),
+                        payload: Default::default(),
+                        external_id: None,
+                    }
+                    .with_payload(crate::Payload::from([(
+                        "color".to_string(),
+                        (*color).into(),
+                    )])),
+                );
+            }
+            let metadata = sst_manager
+                .write_memtable("test", seq_no, 0, memtable.as_ref())
+                .unwrap();
+            assert!(sidecar(&metadata.path).exists());
+            levels[0].push(Arc::new(metadata));
+        }
+
+        let mut policy = policy(2, 10);
+        policy.payload_indexes = config.payload_indexes.clone();
+        let plan = policy.plan(&levels).unwrap();
+        let inputs: Vec<PathBuf> = plan.inputs.iter().map(|m| m.path.clone()).collect();
+        let (outputs, log_path) = run_merge(&sst_manager, "test", 2, &plan).unwrap();
+        sst_manager.finish_compaction(&log_path, &inputs).unwrap();
+        assert!(inputs.iter().all(|path| !sidecar(path).exists()));
+
+        // The newest version of 0 is blue, of 1 red
+        let reloaded = SSTManager::new(dir.path().to_path_buf())
+            .load_metadata("test")
+            .unwrap();
+        assert_eq!(reloaded.len(), 1);
+        assert_eq!(reloaded[0].path, outputs[0].path);
+        let index = reloaded[0].payload_index.as_ref().unwrap();
+        let red = index
+            .candidates(&crate::Filter::eq("color", "red"))
+            .unwrap();
+        assert_eq!(red.into_iter().collect::<Vec<_>>(), vec![1]);
+    }
+
+    #[test]
+    fn test_failed_flush_is_retried_until_the_disk_recovers() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let sst_manager = SSTManager::new(dir.path().to_path_buf());
+        let mut memtable = get_memtable(
+            &IndexConfig::new_with_default_config("flat").unwrap(),
+            &DistanceType::L2,
+            DEFAULT_MEMTABLE_SIZE,
+            &TrainedCentroids::default(),
+        );
+        memtable.upsert(Document::new(vec![1.0, 2.0], "doc".to_string()));
+        let task = CompactTask::new_default_layer("test".to_string(), 1, Arc::from(memtable));
+
+        // A file where the collection directory belongs makes every write fail
+        let blocker = dir.path().join("data/test");
+        std::fs::create_dir_all(blocker.parent().unwrap()).unwrap();
+        std::fs::write(&blocker, b"").unwrap();
+
+        let draining = AtomicBool::new(false);
+        let mut reports = Vec::new();
+        let event = run_with_retries(&sst_manager, &task, &draining, |event| {
+            if reports.len() == 1 {
+                std::fs::remove_file(&blocker).unwrap();
+            }
+            reports.push(event);
+        });
+        assert!(matches!(event, SSTEvent::Flushed(ref m) if m.path.exists()));
+        assert_eq!(reports.len(), 2);
+        assert!(matches!(
+            reports[1],
+            SSTEvent::FlushRetrying { attempts: 2, .. }
+        ));
+
+        // Once the database is closing the flush gives up instead
+        std::fs::remove_dir_all(&blocker).unwrap();
+        std::fs::write(&blocker, b"").unwrap();
+        draining.store(true, Ordering::SeqCst);
+        let mut reports = 0;
+        let event = run_with_retries(&sst_manager, &task, &draining, |_| reports += 1);
+        assert!(matches!(event, SSTEvent::FlushFailed { seq_no: 1, .. }));
+        assert_eq!(reports, COMPACTION_MAX_ATTEMPTS as usize - 1);
+    }
+
+    fn flush_task(collection_name: &str, seq_no: u64) -> CompactTask {
+        let mut memtable = get_memtable(
+            &IndexConfig::new_with_default_config("flat").unwrap(),
+            &DistanceType::L2,
+            DEFAULT_MEMTABLE_SIZE,
+            &TrainedCentroids::default(),
+        );
+        memtable.upsert(Document::new(vec![1.0, 2.0], "doc".to_string()));
+        CompactTask::new_default_layer(collection_name.to_string(), seq_no, Arc::from(memtable))
+    }
+
+    #[test]
+    fn test_lanes_take_turns() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let (sender, _receiver) = unbounded();
+        // Without workers the test plays the worker itself
+        let manager = CompactionManager::with_worker_count(
+            Arc::new(SSTManager::new(dir.path().to_path_buf())),
+            sender,
+            0,
+            0,
+        );
+        let scheduler = &manager.scheduler;
+        for seq_no in 1..=3 {
+            scheduler.enqueue(flush_task("a", seq_no));
+        }
+        for seq_no in 1..=2 {
+            scheduler.enqueue(flush_task("b", seq_no));
+        }
+
+        let mut order = Vec::new();
+        loop {
+            let Some(lane) = scheduler.lock_run_queue().ready.pop_front() else {
+                break;
+            };
+            let turn = LaneTurn { scheduler, lane };
+            let task = turn.lane.lock().tasks.pop_front().unwrap();
+            order.push((task.collection_name, task.seq_no));
+        }
+        let order: Vec<(&str, u64)> = order.iter().map(|(c, s)| (c.as_str(), *s)).collect();
+        assert_eq!(order, [("a", 1), ("b", 1), ("a", 2), ("b", 2), ("a", 3)]);
+        assert!(!scheduler.lanes.get("a").unwrap().lock().scheduled);
+    }
+
+    #[test]
+    fn test_workers_scale_and_keep_collection_order() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let (sender, receiver) = unbounded();
+        let manager = CompactionManager::with_worker_count(
+            Arc::new(SSTManager::new(dir.path().to_path_buf())),
+            sender,
+            1,
+            3,
+        );
+        manager.spin_up_workers();
+        assert_eq!(manager.worker_count(), 1);
+
+        let collections = ["a", "b", "c", "d"];
+        for seq_no in 1..=5 {
+            for collection in collections {
+                manager.scheduler.enqueue(flush_task(collection, seq_no));
+            }
+        }
+
+        let mut flushed: Vec<Vec<u64>> = vec![Vec::new(); collections.len()];
+        for _ in 0..20 {
+            let event = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+            let SSTEvent::Flushed(metadata) = event else {
+                panic!("Expected a flush");
+            };
+            let i = collections
+                .iter()
+                .position(|c| *c == metadata.collection_name)
+                .unwrap();
+            flushed[i].push(metadata.seq_no);
+            assert!(manager.worker_count() <= 3);
+        }
+        assert!(flushed.iter().all(|seq_nos| *seq_nos == [1, 2, 3, 4, 5]));
+
+        manager.shutdown();
+        assert_eq!(manager.worker_count(), 0);
     }
 }
@@ -1,3 +1,42 @@
+use std::time::Duration;
+
 pub const MAX_DIMENSION: i32 = 65332;
 
 pub const DEFAULT_MEMTABLE_SIZE: usize = 5000;
+
+// Leveled compaction, see compact.rs
+pub const L0_COMPACTION_TRIGGER: usize = 4;
+pub const SST_TARGET_ENTRIES: usize = DEFAULT_MEMTABLE_SIZE * 4;
+pub const L1_MAX_ENTRIES: u64 = SST_TARGET_ENTRIES as u64 * 10;
+pub const LEVEL_SIZE_MULTIPLIER: u64 = 10;
+pub const MAX_LEVEL: u64 = 6;
+
+// Write stalls, see options.rs
+pub const DEFAULT_MAX_FROZEN_MEMTABLES: usize = 4;
+pub const DEFAULT_MAX_PENDING_COMPACTIONS: usize = 8;
+pub const DEFAULT_SLOWDOWN_TRIGGER: usize = 3;
+pub const DEFAULT_SLOWDOWN_DELAY: Duration = Duration::from_millis(1);
+pub const STALL_RECHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// Compaction workers, see compact.rs
+pub const MIN_COMPACTION_WORKERS: usize = 4;
+pub const MAX_COMPACTION_WORKERS: usize = 16;
+pub const WORKER_IDLE_TIMEOUT: Duration = Duration::from_secs(30); // surplus workers exit after it
+
+// Failed background tasks, see compact.rs
+pub const COMPACTION_RETRY_BASE_DELAY: Duration = Duration::from_millis(100);
+pub const COMPACTION_RETRY_MAX_DELAY: Duration = Duration::from_secs(5);
+pub const COMPACTION_MAX_ATTEMPTS: u32 = 3; // merges, and flushes once the database is closing
+pub const FLUSH_ATTEMPTS_BEFORE_READ_ONLY: u32 = 3;
+
+// Payload indexes, see search.rs
+pub const PREFILTER_MAX_SELECTIVITY: f64 = 0.1; // share of a tier above which filtering the ANN results is cheaper
+
+// SST layout, see sst.rs
+pub const SST_BLOCK_SIZE: usize = 4096; // a block is closed once its records reach this size
+pub const BLOOM_BITS_PER_KEY: usize = 10;
+pub const TABLE_CACHE_CAPACITY: usize = 256; // open SSTs kept memory-mapped, see table.rs
+
+// Block cache, see block_cache.rs
+pub const DEFAULT_BLOCK_CACHE_SIZE: usize = 64 << 20; // bytes, shared by every collection
+pub const BLOCK_CACHE_SHARDS: usize = 16;
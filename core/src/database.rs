@@ -1,17 +1,22 @@
 use crate::SSTEvent;
-use crate::collection::{Collection, CollectionManager, IndexConfig};
+use crate::block_cache::{BlockCache, BlockCacheStats};
+use crate::catalog::{Catalog, CollectionDescriptor};
+use crate::collection::{Collection, CollectionHealth, CollectionManager, IndexConfig};
 use crate::compact::CompactionManager;
-use crate::constant::{DEFAULT_MEMTABLE_SIZE, MAX_DIMENSION};
+use crate::constant::MAX_DIMENSION;
 use crate::context::BackgroundContext;
 use crate::error::{CollectionError, DatabaseError};
-use crate::wal::WalManager;
-use std::thread;
+use crate::options::DatabaseOptions;
+use crate::sst::SSTManager;
+use crate::wal::{WalManager, remove_segments_below};
+use std::thread::{self, JoinHandle};
 
-use crossbeam_channel::{Receiver, Sender, unbounded};
+use crossbeam_channel::{Receiver, Sender, select, unbounded};
 use fs2::FileExt;
 use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, LazyLock, Mutex, RwLock, Weak};
 static DATABASE_REGISTRY: LazyLock<Mutex<DatabaseRegistery>> =
     LazyLock::new(|| Mutex::new(DatabaseRegistery::new()));
@@ -20,20 +25,32 @@ pub struct AetherDB {
     path: PathBuf,
     collection_manager: Arc<CollectionManager>,
     compact_manager: CompactionManager,
+    sst_manager: Arc<SSTManager>,
     background_context: BackgroundContext,
-    _lock_file: File, // process lock
-    memtable_size: usize,
+    catalog: Catalog,
+    lock_file: Mutex<Option<File>>, // process lock, released by `close`
+    options: DatabaseOptions,
+    closed: AtomicBool,
+    sst_dispatcher: Mutex<Option<(Sender<()>, JoinHandle<()>)>>,
 }
 
-// We need a way to gracefully shutdwon all the threads (database, compaction manager ...etc)
 impl AetherDB {
     pub fn new(path: &str) -> Result<Arc<Self>, DatabaseError> {
+        Self::with_options(path, DatabaseOptions::default())
+    }
+
+    /// Opens the database at `path`. If it is already open in this process the running
+    /// instance is returned and `options` are ignored.
+    ///
+    /// A collection that fails to recover is left out with a warning, the others open
+    /// anyway. It keeps its catalog entry and files, the next open tries it again.
+    pub fn with_options(path: &str, options: DatabaseOptions) -> Result<Arc<Self>, DatabaseError> {
         {
             let registry = DATABASE_REGISTRY.lock().unwrap();
-            if let Some(weak_ref) = registry.get(path) {
-                if let Some(strong_ref) = weak_ref.upgrade() {
-                    return Ok(strong_ref);
-                }
+            if let Some(weak_ref) = registry.get(path)
+                && let Some(strong_ref) = weak_ref.upgrade()
+            {
+                return Ok(strong_ref);
             }
         }
 
@@ -44,16 +61,21 @@ impl AetherDB {
             DatabaseError::InvalidPath(Some("Database is locked by another process".to_string()))
         })?;
 
+        let catalog = Catalog::open(&pathbuf)?;
+
         // setup sst event channel, NOTE: I don't like this design, we can do better
         let (sst_event_sender, sst_event_receiver) = unbounded::<SSTEvent>();
 
         let collection_manager = Arc::new(CollectionManager::new());
 
-        let compact_manager = CompactionManager::new(pathbuf.clone(), sst_event_sender);
+        let block_cache = Arc::new(BlockCache::new(options.block_cache_size));
+        let sst_manager = Arc::new(SSTManager::with_block_cache(pathbuf.clone(), block_cache));
+        let compact_manager = CompactionManager::new(sst_manager.clone(), sst_event_sender);
         let compact_task_sender = compact_manager.spin_up_dispatcher();
         compact_manager.spin_up_workers();
 
-        Self::sst_dispater_loop(sst_event_receiver.clone(), collection_manager.clone());
+        let sst_dispatcher =
+            Self::sst_dispater_loop(sst_event_receiver.clone(), collection_manager.clone());
 
         let db = Arc::new(AetherDB {
             collection_manager: collection_manager,
@@ -61,11 +83,24 @@ impl AetherDB {
             background_context: BackgroundContext {
                 compact_task_sender: compact_task_sender,
             },
-            _lock_file: lock_file,
+            sst_manager,
+            catalog,
+            lock_file: Mutex::new(Some(lock_file)),
             path: pathbuf,
-            memtable_size: DEFAULT_MEMTABLE_SIZE, // I need another constructor to set this
+            options,
+            closed: AtomicBool::new(false),
+            sst_dispatcher: Mutex::new(Some(sst_dispatcher)),
         });
 
+        for descriptor in db.catalog.collections() {
+            if let Err(e) = db.open_collection(&descriptor) {
+                eprintln!(
+                    "WARN: collection {} failed to recover and stays closed: {}",
+                    descriptor.name, e
+                );
+            }
+        }
+
         let mut registry = DATABASE_REGISTRY.lock().unwrap();
         registry.set(path, Arc::downgrade(&db));
 
@@ -79,21 +114,92 @@ impl AetherDB {
         distance: &str,
         index_config: IndexConfig,
     ) -> Result<Arc<RwLock<Collection>>, CollectionError> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(CollectionError::Closed(Some(name.to_string())));
+        }
         if dimension > MAX_DIMENSION || dimension < 1 {
             return Err(CollectionError::InvalidDimension(Some(
                 "Dimension must be between 1 and 65332".to_string(),
             )));
         }
 
-        let collection = Collection::new(
-            name,
+        let descriptor = CollectionDescriptor {
+            name: name.to_string(),
             dimension,
-            distance,
+            distance: distance.parse()?,
             index_config,
-            WalManager::new(&self.path, name)?,
-            self.memtable_size,
+        };
+
+        // The catalog entry goes first, a crash right after it just reopens an empty collection
+        let added = self
+            .catalog
+            .add(descriptor.clone())
+            .map_err(|e| CollectionError::InternalError(Some(e.to_string())))?;
+        if !added {
+            return Err(CollectionError::AlreadyExists(Some(name.to_string())));
+        }
+
+        self.open_collection(&descriptor).inspect_err(|_| {
+            // Otherwise the name stays taken by a collection that never opened
+            if let Err(e) = self.catalog.remove(name) {
+                eprintln!("WARN: failed to drop catalog entry of {}: {}", name, e);
+            }
+        })
+    }
+
+    /// Builds a collection from its catalog entry and recovers whatever it has on disk.
+    fn open_collection(
+        &self,
+        descriptor: &CollectionDescriptor,
+    ) -> Result<Arc<RwLock<Collection>>, CollectionError> {
+        let name = descriptor.name.as_str();
+        let wal_manager =
+            WalManager::with_sync_mode(&self.path, name, self.options.wal_sync.clone())?
+                .with_recovery_policy(self.options.wal_recovery);
+
+        let sst_metadata = self
+            .sst_manager
+            .load_metadata(name)
+            .map_err(|e| CollectionError::InternalError(Some(e.to_string())))?;
+
+        // Segments below the newest SST are already on disk, only the tail of the WAL is replayed
+        let flushed_seq_no = sst_metadata.iter().map(|m| m.seq_no).max().unwrap_or(0);
+        let (records, report) = wal_manager.replay_with_report(flushed_seq_no)?;
+        for torn in report.torn_tails.iter() {
+            eprintln!(
+                "WARN: truncated torn WAL tail of {} at offset {}",
+                torn.segment.display(),
+                torn.offset
+            );
+        }
+        for skipped in report.skipped.iter() {
+            eprintln!(
+                "WARN: skipped {} corrupted WAL bytes of {} at offset {} ({} records)",
+                skipped.len,
+                skipped.segment.display(),
+                skipped.offset,
+                skipped
+                    .records
+                    .map_or("unknown".to_string(), |r| r.to_string())
+            );
+        }
+        // Left behind when the process stopped between a flush and its cleanup
+        remove_segments_below(wal_manager.dir(), name, flushed_seq_no)?;
+
+        let mut collection = Collection::new(
+            descriptor,
+            wal_manager,
+            &self.options,
             self.background_context.clone(),
+            self.sst_manager.clone(),
         )?;
+        collection.replay(records)?;
+        for metadata in sst_metadata {
+            collection.add_sst_metadata(metadata);
+        }
+        // Picks up where compaction stopped before the restart
+        collection.state().schedule_merge()?;
+
         Ok(self.collection_manager.create_collection(collection))
     }
 
@@ -103,26 +209,118 @@ impl AetherDB {
             .ok_or_else(|| CollectionError::NotFound(Some(name.to_string())))
     }
 
+    /// Hit and miss counters and memory use of the block cache.
+    pub fn block_cache_stats(&self) -> BlockCacheStats {
+        self.sst_manager.block_cache().stats()
+    }
+
+    /// Keeps the index blocks of a hot collection's SSTs in the block cache, out of
+    /// reach of eviction, or releases them.
+    pub fn pin_index_blocks(&self, name: &str, pinned: bool) -> Result<(), CollectionError> {
+        self.get_collection(name)?;
+        self.sst_manager.block_cache().set_pinned(name, pinned);
+        Ok(())
+    }
+
     pub fn delete_collection(&self, name: &str) -> Result<(), CollectionError> {
         panic!("Not implemented");
     }
 
+    /// Stops accepting writes, flushes every memtable, waits for the queued compaction
+    /// tasks and joins the background threads before releasing the `.lock` file.
+    /// Calling it again is a no-op, `Drop` calls it as well.
+    pub fn close(&self) -> Result<(), DatabaseError> {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        // Keep going on errors, the threads and the lock must be released regardless
+        let mut errors = Vec::new();
+        let entries = self.collection_manager.entries();
+        for (collection, _) in &entries {
+            // Shared access like any writer, a handle held elsewhere must not block it
+            let result = match collection.read() {
+                Ok(collection) => collection.close(),
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = result {
+                errors.push(e.to_string());
+            }
+        }
+        self.compact_manager.begin_shutdown();
+        for (_, state) in &entries {
+            if let Err(e) = state.wait_for_idle() {
+                errors.push(e.to_string());
+            }
+            if let CollectionHealth::ReadOnly(reason) = state.health() {
+                errors.push(format!(
+                    "{} not flushed, kept in the WAL: {}",
+                    state.name(),
+                    reason
+                ));
+            }
+        }
+
+        self.compact_manager.shutdown();
+        if let Some((shutdown_sender, handle)) = self.sst_dispatcher.lock().unwrap().take() {
+            drop(shutdown_sender);
+            if handle.join().is_err() {
+                errors.push("SST event dispatcher panicked".to_string());
+            }
+        }
+
+        if let Some(lock_file) = self.lock_file.lock().unwrap().take()
+            && let Err(e) = lock_file.unlock()
+        {
+            errors.push(format!("Cannot release lock file: {}", e));
+        }
+        DATABASE_REGISTRY
+            .lock()
+            .unwrap()
+            .remove(self.path.to_str().unwrap_or_default(), self);
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(DatabaseError::ShutdownError(Some(errors.join("; "))))
+        }
+    }
+
     fn sst_dispater_loop(
         sst_event_receiver: Receiver<SSTEvent>,
         collection_manager: Arc<CollectionManager>,
-    ) {
-        thread::spawn(move || {
+    ) -> (Sender<()>, JoinHandle<()>) {
+        let (shutdown_sender, shutdown_receiver) = unbounded::<()>();
+        let handle = thread::spawn(move || {
             loop {
-                match sst_event_receiver.recv() {
-                    Ok(event) => {
-                        collection_manager.on_sst_created(event.metadata);
-                    }
-                    Err(_) => {
+                select! {
+                    recv(sst_event_receiver) -> event => match event {
+                        Ok(event) => {
+                            collection_manager.on_sst_event(event);
+                        }
+                        Err(_) => {
+                            break;
+                        }
+                    },
+                    recv(shutdown_receiver) -> _ => {
+                        // Workers are joined by now, whatever they sent is still applied
+                        for event in sst_event_receiver.try_iter() {
+                            collection_manager.on_sst_event(event);
+                        }
                         break;
                     }
                 }
             }
         });
+        (shutdown_sender, handle)
+    }
+}
+
+impl Drop for AetherDB {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            eprintln!("WARN: failed to close database: {}", e);
+        }
     }
 }
 
@@ -166,6 +364,17 @@ impl DatabaseRegistery {
     pub fn set(&mut self, key: &str, value: Weak<AetherDB>) {
         self.registry.insert(key.to_string(), value);
     }
+
+    /// Forgets `key` if it still refers to `db`, a newer instance may have replaced it.
+    pub fn remove(&mut self, key: &str, db: &AetherDB) {
+        if self
+            .registry
+            .get(key)
+            .is_some_and(|weak_ref| std::ptr::eq(weak_ref.as_ptr(), db))
+        {
+            self.registry.remove(key);
+        }
+    }
 }
 
 #[cfg(test)]
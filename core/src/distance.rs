@@ -0,0 +1,57 @@
+use crate::collection::DistanceType;
+
+/// All distances are "lower is closer", so every index can rank candidates
+/// the same way regardless of the metric the collection was created with.
+impl DistanceType {
+    pub fn distance(&self, a: &[f32], b: &[f32]) -> f32 {
+        match self {
+            DistanceType::L2 => l2_squared(a, b),
+            DistanceType::Dot => -dot(a, b),
+            DistanceType::Cosine => cosine_distance(a, b),
+        }
+    }
+}
+
+pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
+}
+
+pub fn l2_squared(a: &[f32], b: &[f32]) -> f32 {
+    a.iter()
+        .zip(b.iter())
+        .map(|(x, y)| {
+            let d = x - y;
+            d * d
+        })
+        .sum()
+}
+
+pub fn norm(a: &[f32]) -> f32 {
+    dot(a, a).sqrt()
+}
+
+pub fn cosine_distance(a: &[f32], b: &[f32]) -> f32 {
+    let denom = norm(a) * norm(b);
+    if denom == 0.0 {
+        // Zero vectors have no direction, treat them as orthogonal to everything
+        return 1.0;
+    }
+    1.0 - dot(a, b) / denom
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distances() {
+        let a = vec![1.0, 0.0];
+        let b = vec![0.0, 1.0];
+
+        assert_eq!(DistanceType::L2.distance(&a, &b), 2.0);
+        assert_eq!(DistanceType::Dot.distance(&a, &a), -1.0);
+        assert!(DistanceType::Cosine.distance(&a, &a).abs() < 1e-6);
+        assert!((DistanceType::Cosine.distance(&a, &b) - 1.0).abs() < 1e-6);
+        assert_eq!(DistanceType::Cosine.distance(&a, &[0.0, 0.0]), 1.0);
+    }
+}
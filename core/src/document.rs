@@ -1,11 +1,67 @@
+use crate::payload::Payload;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
 use uuid::Uuid;
 
-#[derive(Serialize, Deserialize)]
+// Namespace of the UUIDv5 ids derived from string external ids
+const EXTERNAL_ID_NAMESPACE: Uuid = Uuid::from_u128(0x6ae1_35c4_0d2b_4f3e_9a57_21c8_e0b4_d913);
+
+/// A primary key chosen by the caller, e.g. a SKU or the row id of another database.
+///
+/// The internal id is derived from it rather than looked up, so the mapping needs no
+/// storage of its own and holds in every tier: u64 keys are used as is, string keys are
+/// hashed into a UUIDv5. Neither can collide with the random UUIDv4 ids of
+/// `Document::new`, their version bits differ.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ExternalId {
+    Integer(u64),
+    String(String),
+}
+
+impl ExternalId {
+    pub fn internal_id(&self) -> u128 {
+        match self {
+            ExternalId::Integer(id) => *id as u128,
+            ExternalId::String(id) => Uuid::new_v5(&EXTERNAL_ID_NAMESPACE, id.as_bytes()).as_u128(),
+        }
+    }
+}
+
+impl fmt::Display for ExternalId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExternalId::Integer(id) => write!(f, "{}", id),
+            ExternalId::String(id) => write!(f, "{}", id),
+        }
+    }
+}
+
+impl From<u64> for ExternalId {
+    fn from(id: u64) -> Self {
+        ExternalId::Integer(id)
+    }
+}
+
+impl From<&str> for ExternalId {
+    fn from(id: &str) -> Self {
+        ExternalId::String(id.to_string())
+    }
+}
+
+impl From<String> for ExternalId {
+    fn from(id: String) -> Self {
+        ExternalId::String(id)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Document {
     pub id: u128,
     pub vector: Vec<f32>,
     pub content: String,
+    pub payload: Payload,
+    pub external_id: Option<ExternalId>, // set when the caller chose the id
 }
 
 impl Document {
@@ -14,9 +70,32 @@ impl Document {
             id: Uuid::new_v4().as_u128(),
             vector,
             content,
+            payload: Payload::new(),
+            external_id: None,
+        }
+    }
+
+    /// A document keyed by `external_id`, upserting it again replaces this version.
+    pub fn with_external_id(
+        external_id: impl Into<ExternalId>,
+        vector: Vec<f32>,
+        content: String,
+    ) -> Self {
+        let external_id = external_id.into();
+        Document {
+            id: external_id.internal_id(),
+            vector,
+            content,
+            payload: Payload::new(),
+            external_id: Some(external_id),
         }
     }
 
+    pub fn with_payload(mut self, payload: Payload) -> Self {
+        self.payload = payload;
+        self
+    }
+
     pub fn dimension(&self) -> i32 {
         self.vector.len() as i32
     }
@@ -29,3 +108,47 @@ impl Document {
         bincode::deserialize(&data)
     }
 }
+
+/// A single version of an id as stored by a tier (memtable, WAL or SST),
+/// a tombstone hides every older version of the id.
+#[derive(Debug, Clone)]
+pub enum Record {
+    Live(Arc<Document>),
+    Tombstone(u128),
+}
+
+impl Record {
+    pub fn id(&self) -> u128 {
+        match self {
+            Record::Live(document) => document.id,
+            Record::Tombstone(id) => *id,
+        }
+    }
+
+    /// The document if this record is live, `None` for a tombstone.
+    pub fn into_document(self) -> Option<Arc<Document>> {
+        match self {
+            Record::Live(document) => Some(document),
+            Record::Tombstone(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_external_ids_map_to_stable_disjoint_ids() {
+        let sku = ExternalId::from("sku-123");
+        assert_eq!(sku.internal_id(), ExternalId::from("sku-123").internal_id());
+        assert_ne!(sku.internal_id(), ExternalId::from("sku-124").internal_id());
+        assert_eq!(ExternalId::from(42).internal_id(), 42);
+
+        // The version nibble tells the three kinds of ids apart
+        let version = |id: u128| (id >> 76) & 0xf;
+        assert_eq!(version(sku.internal_id()), 5);
+        assert_eq!(version(ExternalId::from(u64::MAX).internal_id()), 0);
+        assert_eq!(version(Document::new(vec![], String::new()).id), 4);
+    }
+}
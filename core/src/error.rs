@@ -1,3 +1,4 @@
+use crate::sst::SSTError;
 use std::{fmt, sync::PoisonError};
 
 #[derive(Debug)]
@@ -7,7 +8,12 @@ pub enum CollectionError {
     InvalidDistanceType(Option<String>),
     PoisonError(Option<String>),
     WalError(Option<String>),
+    SSTError(Option<String>),
     NotFound(Option<String>),
+    AlreadyExists(Option<String>),
+    WriteStall(Option<String>),
+    Closed(Option<String>),
+    ReadOnly(Option<String>),
     InternalError(Option<String>),
 }
 
@@ -38,12 +44,42 @@ impl fmt::Display for CollectionError {
             CollectionError::WalError(None) => {
                 write!(f, "Collection error from wal")
             }
+            CollectionError::SSTError(Some(msg)) => {
+                write!(f, "Collection error from sst: {}", msg)
+            }
+            CollectionError::SSTError(None) => {
+                write!(f, "Collection error from sst")
+            }
             CollectionError::NotFound(Some(msg)) => {
                 write!(f, "Collection not found: {}", msg)
             }
             CollectionError::NotFound(None) => {
                 write!(f, "Collection not found")
             }
+            CollectionError::AlreadyExists(Some(msg)) => {
+                write!(f, "Collection already exists: {}", msg)
+            }
+            CollectionError::AlreadyExists(None) => {
+                write!(f, "Collection already exists")
+            }
+            CollectionError::WriteStall(Some(msg)) => {
+                write!(f, "Write stalled: {}", msg)
+            }
+            CollectionError::WriteStall(None) => {
+                write!(f, "Write stalled")
+            }
+            CollectionError::Closed(Some(msg)) => {
+                write!(f, "Collection is closed: {}", msg)
+            }
+            CollectionError::Closed(None) => {
+                write!(f, "Collection is closed")
+            }
+            CollectionError::ReadOnly(Some(msg)) => {
+                write!(f, "Collection is read-only: {}", msg)
+            }
+            CollectionError::ReadOnly(None) => {
+                write!(f, "Collection is read-only")
+            }
             CollectionError::PoisonError(Some(msg)) => {
                 write!(f, "Poison error: {}", msg)
             }
@@ -71,6 +107,9 @@ impl<T> From<PoisonError<T>> for CollectionError {
 #[derive(Debug)]
 pub enum DatabaseError {
     InvalidPath(Option<String>),
+    CatalogError(Option<String>),
+    RecoveryError(Option<String>),
+    ShutdownError(Option<String>),
 }
 
 impl fmt::Display for DatabaseError {
@@ -82,6 +121,24 @@ impl fmt::Display for DatabaseError {
             DatabaseError::InvalidPath(None) => {
                 write!(f, "Invalid path")
             }
+            DatabaseError::CatalogError(Some(msg)) => {
+                write!(f, "Catalog error: {}", msg)
+            }
+            DatabaseError::CatalogError(None) => {
+                write!(f, "Catalog error")
+            }
+            DatabaseError::RecoveryError(Some(msg)) => {
+                write!(f, "Recovery error: {}", msg)
+            }
+            DatabaseError::RecoveryError(None) => {
+                write!(f, "Recovery error")
+            }
+            DatabaseError::ShutdownError(Some(msg)) => {
+                write!(f, "Shutdown error: {}", msg)
+            }
+            DatabaseError::ShutdownError(None) => {
+                write!(f, "Shutdown error")
+            }
         }
     }
 }
@@ -94,10 +151,17 @@ impl From<WalError> for CollectionError {
     }
 }
 
+impl From<SSTError> for CollectionError {
+    fn from(err: SSTError) -> Self {
+        CollectionError::SSTError(Some(err.to_string()))
+    }
+}
+
 #[derive(Debug)]
 pub enum WalError {
     InvalidOperation(Option<String>),
     WriteError(Option<String>),
+    Corrupted(Option<String>),
 }
 
 impl fmt::Display for WalError {
@@ -115,6 +179,12 @@ impl fmt::Display for WalError {
             WalError::WriteError(None) => {
                 write!(f, "Write error")
             }
+            WalError::Corrupted(Some(msg)) => {
+                write!(f, "Corrupted wal: {}", msg)
+            }
+            WalError::Corrupted(None) => {
+                write!(f, "Corrupted wal")
+            }
         }
     }
 }
@@ -0,0 +1,456 @@
+/**
+ * In-memory HNSW graph (Malkov & Yashunin, 2016).
+ *
+ * Nodes are never physically removed: deleting or overwriting a document only
+ * marks its node as deleted, the node keeps routing searches through the graph
+ * but is never returned. The memtable is short lived (it gets frozen and flushed),
+ * so the dead nodes are dropped together with it.
+ */
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::sync::Arc;
+
+use rand::Rng;
+
+use crate::collection::DistanceType;
+use crate::document::Document;
+use crate::error::CollectionError;
+use crate::payload::{Filter, accepts};
+
+pub const DEFAULT_M: usize = 16;
+pub const DEFAULT_EF_CONSTRUCTION: usize = 200;
+pub const DEFAULT_EF_SEARCH: usize = 50;
+
+#[derive(Debug, Clone)]
+pub struct HnswParams {
+    pub m: usize,
+    pub ef_construction: usize,
+    pub ef_search: usize,
+}
+
+impl Default for HnswParams {
+    fn default() -> Self {
+        HnswParams {
+            m: DEFAULT_M,
+            ef_construction: DEFAULT_EF_CONSTRUCTION,
+            ef_search: DEFAULT_EF_SEARCH,
+        }
+    }
+}
+
+impl HnswParams {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, CollectionError> {
+        let m = parse_param(params, "m", DEFAULT_M)?;
+        if m < 2 {
+            return Err(CollectionError::InvalidIndexType(Some(
+                "HNSW parameter m must be at least 2".to_string(),
+            )));
+        }
+
+        Ok(HnswParams {
+            m,
+            ef_construction: parse_param(params, "efConstruction", DEFAULT_EF_CONSTRUCTION)?.max(1),
+            ef_search: parse_param(params, "efSearch", DEFAULT_EF_SEARCH)?.max(1),
+        })
+    }
+}
+
+pub fn parse_param(
+    params: &HashMap<String, String>,
+    key: &str,
+    default: usize,
+) -> Result<usize, CollectionError> {
+    match params.get(key) {
+        Some(value) => value.parse::<usize>().map_err(|_| {
+            CollectionError::InvalidIndexType(Some(format!(
+                "Invalid value for parameter {}: {}",
+                key, value
+            )))
+        }),
+        None => Ok(default),
+    }
+}
+
+/// (distance, node) ordered by distance, used by both heaps of the beam search
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) struct Candidate {
+    pub distance: f32,
+    pub node: usize,
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.distance
+            .total_cmp(&other.distance)
+            .then(self.node.cmp(&other.node))
+    }
+}
+
+/// A layered proximity graph a beam search can walk, the in-memory one or one read
+/// back from an SST.
+pub(crate) trait LayeredGraph {
+    fn neighbors(&self, node: usize, layer: usize) -> impl Iterator<Item = usize> + '_;
+
+    fn node_distance(&self, query: &[f32], node: usize) -> f32;
+
+    fn greedy_closest(&self, query: &[f32], mut current: Candidate, layer: usize) -> Candidate {
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for neighbor in self.neighbors(current.node, layer) {
+                let distance = self.node_distance(query, neighbor);
+                if distance < current.distance {
+                    current = Candidate {
+                        distance,
+                        node: neighbor,
+                    };
+                    changed = true;
+                }
+            }
+        }
+        current
+    }
+
+    /// Beam search on a single layer, returns the `ef` closest nodes found, closest first.
+    fn search_layer(
+        &self,
+        query: &[f32],
+        entries: &[Candidate],
+        ef: usize,
+        layer: usize,
+    ) -> Vec<Candidate> {
+        let mut visited: HashSet<usize> = entries.iter().map(|c| c.node).collect();
+        let mut candidates: BinaryHeap<Reverse<Candidate>> =
+            entries.iter().map(|c| Reverse(*c)).collect();
+        let mut found: BinaryHeap<Candidate> = entries.iter().copied().collect();
+        while found.len() > ef {
+            found.pop();
+        }
+
+        while let Some(Reverse(closest)) = candidates.pop() {
+            let furthest = found.peek().map_or(f32::INFINITY, |c| c.distance);
+            if closest.distance > furthest && found.len() >= ef {
+                break;
+            }
+
+            for neighbor in self.neighbors(closest.node, layer) {
+                if !visited.insert(neighbor) {
+                    continue;
+                }
+
+                let distance = self.node_distance(query, neighbor);
+                let furthest = found.peek().map_or(f32::INFINITY, |c| c.distance);
+                if found.len() < ef || distance < furthest {
+                    let candidate = Candidate {
+                        distance,
+                        node: neighbor,
+                    };
+                    candidates.push(Reverse(candidate));
+                    found.push(candidate);
+                    if found.len() > ef {
+                        found.pop();
+                    }
+                }
+            }
+        }
+
+        found.into_sorted_vec()
+    }
+}
+
+struct Node {
+    document: Arc<Document>,
+    // neighbors[layer], layer 0 is the densest
+    neighbors: Vec<Vec<usize>>,
+    deleted: bool,
+}
+
+pub struct HnswGraph {
+    params: HnswParams,
+    distance: DistanceType,
+    nodes: Vec<Node>,
+    entry_point: Option<usize>,
+    max_layer: usize,
+    level_mult: f64,
+    deleted_count: usize,
+}
+
+impl HnswGraph {
+    pub fn new(params: HnswParams, distance: DistanceType) -> Self {
+        let level_mult = 1.0 / (params.m as f64).ln();
+        HnswGraph {
+            params,
+            distance,
+            nodes: Vec::new(),
+            entry_point: None,
+            max_layer: 0,
+            level_mult,
+            deleted_count: 0,
+        }
+    }
+
+    fn max_neighbors(&self, layer: usize) -> usize {
+        if layer == 0 {
+            self.params.m * 2
+        } else {
+            self.params.m
+        }
+    }
+
+    fn random_layer(&self) -> usize {
+        let uniform: f64 = rand::rng().random_range(f64::EPSILON..1.0);
+        (-uniform.ln() * self.level_mult).floor() as usize
+    }
+
+    /// Inserts a document and returns the node it was assigned to.
+    pub fn insert(&mut self, document: Arc<Document>) -> usize {
+        let layer = self.random_layer();
+        let node = self.nodes.len();
+        self.nodes.push(Node {
+            document: document.clone(),
+            neighbors: vec![Vec::new(); layer + 1],
+            deleted: false,
+        });
+
+        let entry_point = match self.entry_point {
+            Some(entry_point) => entry_point,
+            None => {
+                self.entry_point = Some(node);
+                self.max_layer = layer;
+                return node;
+            }
+        };
+
+        let query = &document.vector;
+        let mut entry = Candidate {
+            distance: self.node_distance(query, entry_point),
+            node: entry_point,
+        };
+
+        // Greedy descent through the layers above the new node
+        for lc in (layer + 1..=self.max_layer).rev() {
+            entry = self.greedy_closest(query, entry, lc);
+        }
+
+        let mut entries = vec![entry];
+        for lc in (0..=layer.min(self.max_layer)).rev() {
+            let found = self.search_layer(query, &entries, self.params.ef_construction, lc);
+            let selected = self.select_neighbors(&found, self.params.m);
+
+            for neighbor in selected.iter() {
+                self.nodes[node].neighbors[lc].push(neighbor.node);
+                self.nodes[neighbor.node].neighbors[lc].push(node);
+                self.shrink_neighbors(neighbor.node, lc);
+            }
+
+            entries = found;
+        }
+
+        if layer > self.max_layer {
+            self.max_layer = layer;
+            self.entry_point = Some(node);
+        }
+
+        node
+    }
+
+    pub fn mark_deleted(&mut self, node: usize) {
+        if let Some(node) = self.nodes.get_mut(node)
+            && !node.deleted
+        {
+            node.deleted = true;
+            self.deleted_count += 1;
+        }
+    }
+
+    /// Returns up to `top_k` live documents closest to `query` that `filter` accepts.
+    pub fn search(
+        &self,
+        query: &[f32],
+        top_k: usize,
+        filter: Option<&Filter>,
+    ) -> Vec<(f32, Arc<Document>)> {
+        let entry_point = match self.entry_point {
+            Some(entry_point) if top_k > 0 => entry_point,
+            _ => return Vec::new(),
+        };
+
+        let mut entry = Candidate {
+            distance: self.node_distance(query, entry_point),
+            node: entry_point,
+        };
+        for lc in (1..=self.max_layer).rev() {
+            entry = self.greedy_closest(query, entry, lc);
+        }
+
+        // Deleted and filtered out nodes still occupy slots in the beam, widen it until
+        // enough accepted ones show up. How many pass the filter is unknown, with one the
+        // beam may grow to the whole graph.
+        let wanted = match filter {
+            Some(_) => top_k,
+            None => top_k.min(self.nodes.len() - self.deleted_count),
+        };
+        let mut ef = self.params.ef_search.max(top_k);
+        loop {
+            let result: Vec<(f32, Arc<Document>)> = self
+                .search_layer(query, &[entry], ef, 0)
+                .into_iter()
+                .filter(|c| {
+                    let node = &self.nodes[c.node];
+                    !node.deleted && accepts(filter, &node.document)
+                })
+                .take(top_k)
+                .map(|c| (c.distance, self.nodes[c.node].document.clone()))
+                .collect();
+
+            if result.len() >= wanted || ef >= self.nodes.len() {
+                return result;
+            }
+            ef *= 2;
+        }
+    }
+
+    /// Neighbor selection heuristic (algorithm 4 of the paper): a candidate is kept only
+    /// if it is closer to the base node than to every neighbor already selected, which
+    /// keeps links pointing in diverse directions instead of into a single cluster.
+    fn select_neighbors(&self, sorted_candidates: &[Candidate], m: usize) -> Vec<Candidate> {
+        let mut selected: Vec<Candidate> = Vec::with_capacity(m);
+        let mut pruned: Vec<Candidate> = Vec::new();
+
+        for candidate in sorted_candidates {
+            if selected.len() >= m {
+                break;
+            }
+            let vector = &self.nodes[candidate.node].document.vector;
+            let diverse = selected
+                .iter()
+                .all(|s| self.node_distance(vector, s.node) > candidate.distance);
+            if diverse {
+                selected.push(*candidate);
+            } else {
+                pruned.push(*candidate);
+            }
+        }
+
+        // Keep the degree up when the heuristic was too aggressive
+        for candidate in pruned {
+            if selected.len() >= m {
+                break;
+            }
+            selected.push(candidate);
+        }
+        selected
+    }
+
+    pub fn entry_point(&self) -> Option<usize> {
+        self.entry_point
+    }
+
+    /// Neighbors of `node` on every layer it belongs to, layer 0 first.
+    pub fn node_layers(&self, node: usize) -> &[Vec<usize>] {
+        &self.nodes[node].neighbors
+    }
+
+    fn shrink_neighbors(&mut self, node: usize, layer: usize) {
+        let max_neighbors = self.max_neighbors(layer);
+        if self.nodes[node].neighbors[layer].len() <= max_neighbors {
+            return;
+        }
+
+        let vector = self.nodes[node].document.vector.clone();
+        let mut candidates: Vec<Candidate> = self.nodes[node].neighbors[layer]
+            .iter()
+            .map(|&neighbor| Candidate {
+                distance: self.node_distance(&vector, neighbor),
+                node: neighbor,
+            })
+            .collect();
+        candidates.sort();
+
+        let selected = self.select_neighbors(&candidates, max_neighbors);
+        self.nodes[node].neighbors[layer] = selected.into_iter().map(|c| c.node).collect();
+    }
+}
+
+impl LayeredGraph for HnswGraph {
+    fn neighbors(&self, node: usize, layer: usize) -> impl Iterator<Item = usize> + '_ {
+        self.nodes[node].neighbors[layer].iter().copied()
+    }
+
+    fn node_distance(&self, query: &[f32], node: usize) -> f32 {
+        self.distance
+            .distance(query, &self.nodes[node].document.vector)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::bulk_random_documents;
+
+    #[test]
+    fn test_params_from_config() {
+        let mut params = HashMap::new();
+        params.insert("m".to_string(), "8".to_string());
+        params.insert("efSearch".to_string(), "100".to_string());
+
+        let parsed = HnswParams::from_params(&params).unwrap();
+        assert_eq!(parsed.m, 8);
+        assert_eq!(parsed.ef_construction, DEFAULT_EF_CONSTRUCTION);
+        assert_eq!(parsed.ef_search, 100);
+
+        params.insert("m".to_string(), "abc".to_string());
+        assert!(HnswParams::from_params(&params).is_err());
+    }
+
+    #[test]
+    fn test_hnsw_recall() {
+        let distance = DistanceType::L2;
+        let mut graph = HnswGraph::new(HnswParams::default(), distance.clone());
+        let docs = bulk_random_documents(16, 2000);
+        for doc in docs.iter() {
+            graph.insert(Arc::new(doc.clone()));
+        }
+
+        let top_k = 10;
+        let mut hits = 0;
+        for query in docs.iter().take(50) {
+            let mut exact: Vec<(f32, u128)> = docs
+                .iter()
+                .map(|d| (distance.distance(&query.vector, &d.vector), d.id))
+                .collect();
+            exact.sort_by(|a, b| a.0.total_cmp(&b.0));
+            let exact: HashSet<u128> = exact.iter().take(top_k).map(|e| e.1).collect();
+
+            let result = graph.search(&query.vector, top_k, None);
+            assert_eq!(result.len(), top_k);
+            hits += result.iter().filter(|r| exact.contains(&r.1.id)).count();
+        }
+
+        let recall = hits as f64 / (50 * top_k) as f64;
+        assert!(recall >= 0.9, "recall too low: {}", recall);
+    }
+
+    #[test]
+    fn test_deleted_nodes_are_skipped() {
+        let mut graph = HnswGraph::new(HnswParams::default(), DistanceType::Cosine);
+        let docs = bulk_random_documents(8, 100);
+        let nodes: Vec<usize> = docs
+            .iter()
+            .map(|d| graph.insert(Arc::new(d.clone())))
+            .collect();
+
+        graph.mark_deleted(nodes[0]);
+        let result = graph.search(&docs[0].vector, 5, None);
+        assert_eq!(result.len(), 5);
+        assert!(result.iter().all(|r| r.1.id != docs[0].id));
+    }
+}
@@ -2,13 +2,65 @@
  * This should be thread safe, as there are multiple threads
  * that will be updating the index.
  */
+use crate::payload_index::PayloadIndex;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::RwLock;
 
-pub struct SSTEvent {
-    pub metadata: SSTMetadata,
-    // NOTE: we can add status later if needed, which handles any write fail
+pub enum SSTEvent {
+    /// A frozen memtable was written to L0.
+    Flushed(SSTMetadata),
+    /// A compaction replaced `removed` by `added`, the removed files and the compaction
+    /// log can be deleted once no reader holds them anymore.
+    Compacted {
+        collection_name: String,
+        added: Vec<SSTMetadata>,
+        removed: Vec<Arc<SSTMetadata>>,
+        log_path: PathBuf,
+    },
+    /// A merge gave up, nothing changed on disk and the inputs are still live.
+    CompactionFailed {
+        collection_name: String,
+        error: String,
+    },
+    /// A flush failed and is retried, the task is still running.
+    FlushRetrying {
+        collection_name: String,
+        seq_no: u64,
+        attempts: u32,
+        error: String,
+    },
+    /// A flush gave up while the database was closing, its records are only in the WAL.
+    FlushFailed {
+        collection_name: String,
+        seq_no: u64,
+        error: String,
+    },
+}
+
+impl SSTEvent {
+    pub fn collection_name(&self) -> &str {
+        match self {
+            SSTEvent::Flushed(metadata) => &metadata.collection_name,
+            SSTEvent::Compacted {
+                collection_name, ..
+            }
+            | SSTEvent::CompactionFailed {
+                collection_name, ..
+            }
+            | SSTEvent::FlushRetrying {
+                collection_name, ..
+            }
+            | SSTEvent::FlushFailed {
+                collection_name, ..
+            } => collection_name,
+        }
+    }
+
+    /// Whether the event is the final answer to a compaction task.
+    pub fn completes_task(&self) -> bool {
+        !matches!(self, SSTEvent::FlushRetrying { .. })
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -20,11 +72,12 @@ pub struct SSTMetadata {
     pub max_id: u128,
     pub path: PathBuf,
     pub entry_count: u64,
+    pub payload_index: Option<Arc<PayloadIndex>>, // None when the SST has no sidecar, it is scanned
 }
 
 // Optimize this lock, it is too wide
 pub struct SSTIndex {
-    layer_bucket: RwLock<Vec<Vec<Arc<SSTMetadata>>>>, // NOTE: We believe that the compactor will always send the seq_no in ascending order, so we can just append to the end of the vector
+    layer_bucket: RwLock<Vec<Vec<Arc<SSTMetadata>>>>, // NOTE: We believe that the compactor will always send the seq_no in ascending order, so we can just append to the end of the L0 vector
 }
 
 pub struct IndexManager {
@@ -41,29 +94,62 @@ impl IndexManager {
     }
 
     pub fn add_sst_metadata(&self, sst_metadata: SSTMetadata) {
-        let layer = sst_metadata.layer;
+        let mut bucket = self.sst_index.layer_bucket.write().unwrap();
+        Self::insert(&mut bucket, Arc::new(sst_metadata));
+    }
 
+    /// Swaps the outputs of a compaction in for its inputs under a single lock,
+    /// readers see either every input or every output.
+    pub fn replace_sst_metadata(&self, added: Vec<SSTMetadata>, removed: &[Arc<SSTMetadata>]) {
         let mut bucket = self.sst_index.layer_bucket.write().unwrap();
+        for layer in bucket.iter_mut() {
+            layer.retain(|metadata| !removed.iter().any(|r| r.path == metadata.path));
+        }
+        for metadata in added {
+            Self::insert(&mut bucket, Arc::new(metadata));
+        }
+    }
+
+    fn insert(bucket: &mut Vec<Vec<Arc<SSTMetadata>>>, sst_metadata: Arc<SSTMetadata>) {
+        let layer = sst_metadata.layer as usize;
+        if bucket.len() <= layer + 1 {
+            let bucket_len = (bucket.len() * 2).max(layer + 2);
+            bucket.resize(bucket_len, Vec::new());
+        }
 
-        if bucket.len() <= (layer + 1) as usize {
-            let bucket_len = bucket.len();
-            bucket.resize(bucket_len * 2, Vec::new());
+        let files = &mut bucket[layer];
+        files.push(sst_metadata);
+        // L0 stays in flush order, deeper layers do not overlap and are kept by id range
+        if layer > 0 {
+            files.sort_by_key(|metadata| metadata.min_id);
         }
+    }
+
+    /// Snapshot of the registered SSTs per layer, see `insert` for the order within a layer.
+    pub fn levels(&self) -> Vec<Vec<Arc<SSTMetadata>>> {
+        self.sst_index.layer_bucket.read().unwrap().clone()
+    }
 
-        bucket[layer as usize].push(Arc::new(sst_metadata));
+    /// Every registered SST ordered from the most recent data to the oldest:
+    /// lower layers first, and within L0 the highest seq_no first. Deeper layers do not
+    /// overlap, so their order does not matter.
+    pub fn sst_metadata_newest_first(&self) -> Vec<Arc<SSTMetadata>> {
+        let bucket = self.sst_index.layer_bucket.read().unwrap();
+        bucket
+            .iter()
+            .flat_map(|layer| layer.iter().rev().cloned())
+            .collect()
     }
 
     // NOTE: This is the downside of LSM Tree design
     // but we can optimize this by using a BTreeMap
-    pub fn get_sst_metadata(&self, id: u128) -> Option<Arc<SSTMetadata>> {
-        for bucket in self.sst_index.layer_bucket.read().unwrap().iter() {
-            for sst_metadata in bucket.iter() {
-                if sst_metadata.min_id <= id && sst_metadata.max_id >= id {
-                    return Some(sst_metadata.clone());
-                }
-            }
-        }
-        None
+    /// Every SST whose id range covers `id`, newest first. Ranges of L0 files overlap,
+    /// so the first match is not necessarily the one holding the latest version.
+    pub fn get_sst_metadata(&self, id: u128) -> Vec<Arc<SSTMetadata>> {
+        self.sst_metadata_newest_first()
+            .into_iter()
+            .filter(|sst_metadata| sst_metadata.min_id <= id && sst_metadata.max_id >= id)
+            .collect()
     }
 }
 
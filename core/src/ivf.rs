@@ -0,0 +1,204 @@
+/**
+ * Inverted file index: vectors are bucketed by their closest k-means centroid and a
+ * query only scans the `nprobe` buckets whose centroids are closest to it, and the next
+ * closest ones while fewer than top_k documents pass its filter.
+ *
+ * Centroids need data to be trained on, so until `trainingSize` vectors have been
+ * inserted the index behaves like a flat table and scans everything. They are trained
+ * once per collection and shared by all of its memtables, see `TrainedCentroids`.
+ *
+ * Lists hold full precision vectors, `quantization` may only be `none`.
+ */
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+
+use rand::seq::index::sample;
+
+use crate::collection::DistanceType;
+use crate::error::CollectionError;
+use crate::hnsw::parse_param;
+use crate::search::TopK;
+
+pub const DEFAULT_NLIST: usize = 1024;
+pub const DEFAULT_NPROBE: usize = 10;
+
+const KMEANS_MAX_ITERATIONS: usize = 10;
+const IVF_MIN_LIST_SIZE: usize = 32; // fewer lists rather than near empty ones
+
+#[derive(Debug, Clone)]
+pub struct IvfParams {
+    pub nlist: usize,
+    pub nprobe: usize,
+    pub training_size: usize,
+}
+
+impl Default for IvfParams {
+    fn default() -> Self {
+        IvfParams {
+            nlist: DEFAULT_NLIST,
+            nprobe: DEFAULT_NPROBE,
+            training_size: DEFAULT_NLIST * 4,
+        }
+    }
+}
+
+impl IvfParams {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, CollectionError> {
+        let nlist = parse_param(params, "nlist", DEFAULT_NLIST)?;
+        if nlist == 0 {
+            return Err(CollectionError::InvalidIndexType(Some(
+                "IVF parameter nlist must be at least 1".to_string(),
+            )));
+        }
+
+        if let Some(quantization) = params.get("quantization")
+            && !quantization.eq_ignore_ascii_case("none")
+        {
+            return Err(CollectionError::InvalidIndexType(Some(format!(
+                "IVF quantization {} is not supported",
+                quantization
+            ))));
+        }
+
+        Ok(IvfParams {
+            nlist,
+            nprobe: parse_param(params, "nprobe", DEFAULT_NPROBE)?.clamp(1, nlist),
+            // A few vectors per centroid, otherwise k-means just memorises the sample
+            training_size: parse_param(params, "trainingSize", nlist * 4)?.max(nlist),
+        })
+    }
+}
+
+/// Lists worth building over `vectors` vectors, `nlist` at most.
+pub fn effective_nlist(nlist: usize, vectors: usize) -> usize {
+    nlist.min(vectors / IVF_MIN_LIST_SIZE).max(1)
+}
+
+/// Centroids of one collection, trained by the first of its memtables to fill up and
+/// reused by every later one, so that writers wait on k-means once, not on every memtable.
+#[derive(Clone, Default)]
+pub struct TrainedCentroids(Arc<OnceLock<Vec<Vec<f32>>>>);
+
+impl TrainedCentroids {
+    pub fn get(&self) -> Option<&[Vec<f32>]> {
+        self.0.get().map(Vec::as_slice)
+    }
+
+    /// The centroids, trained by `train` if no memtable did yet.
+    pub fn get_or_train(&self, train: impl FnOnce() -> Vec<Vec<f32>>) -> &[Vec<f32>] {
+        self.0.get_or_init(train)
+    }
+}
+
+/// Lloyd's k-means, returns at most `k` centroids.
+pub fn train_centroids(vectors: &[&[f32]], k: usize, distance: &DistanceType) -> Vec<Vec<f32>> {
+    let k = k.min(vectors.len());
+    if k == 0 {
+        return Vec::new();
+    }
+
+    let mut rng = rand::rng();
+    let mut centroids: Vec<Vec<f32>> = sample(&mut rng, vectors.len(), k)
+        .into_iter()
+        .map(|i| vectors[i].to_vec())
+        .collect();
+    let dimension = centroids[0].len();
+    let mut assignments = vec![usize::MAX; vectors.len()];
+
+    for _ in 0..KMEANS_MAX_ITERATIONS {
+        let mut changed = false;
+        for (i, vector) in vectors.iter().enumerate() {
+            let closest = closest_centroid(&centroids, vector, distance);
+            if assignments[i] != closest {
+                assignments[i] = closest;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+
+        let mut sums = vec![vec![0.0f32; dimension]; k];
+        let mut counts = vec![0usize; k];
+        for (vector, &list) in vectors.iter().zip(assignments.iter()) {
+            counts[list] += 1;
+            for (sum, value) in sums[list].iter_mut().zip(vector.iter()) {
+                *sum += value;
+            }
+        }
+
+        for (list, (sum, count)) in sums.into_iter().zip(counts).enumerate() {
+            // Empty clusters keep their previous centroid
+            if count > 0 {
+                centroids[list] = sum.into_iter().map(|v| v / count as f32).collect();
+            }
+        }
+    }
+
+    centroids
+}
+
+pub fn closest_centroid(centroids: &[Vec<f32>], vector: &[f32], distance: &DistanceType) -> usize {
+    closest_centroids(centroids, vector, 1, distance)
+        .first()
+        .copied()
+        .unwrap_or(0)
+}
+
+/// Indexes of the `n` centroids closest to `vector`, closest first.
+pub fn closest_centroids(
+    centroids: &[Vec<f32>],
+    vector: &[f32],
+    n: usize,
+    distance: &DistanceType,
+) -> Vec<usize> {
+    let mut top = TopK::new(n);
+    for (i, centroid) in centroids.iter().enumerate() {
+        top.push(distance.distance(vector, centroid), i);
+    }
+    top.into_sorted_vec().into_iter().map(|(_, i)| i).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_params_from_config() {
+        let mut params = HashMap::new();
+        params.insert("nlist".to_string(), "16".to_string());
+        params.insert("nprobe".to_string(), "32".to_string());
+
+        let parsed = IvfParams::from_params(&params).unwrap();
+        assert_eq!(parsed.nlist, 16);
+        assert_eq!(parsed.nprobe, 16);
+        assert_eq!(parsed.training_size, 64);
+
+        params.insert("quantization".to_string(), "none".to_string());
+        assert!(IvfParams::from_params(&params).is_ok());
+        params.insert("quantization".to_string(), "PQ16".to_string());
+        assert!(IvfParams::from_params(&params).is_err());
+
+        params.remove("quantization");
+        params.insert("nlist".to_string(), "0".to_string());
+        assert!(IvfParams::from_params(&params).is_err());
+    }
+
+    #[test]
+    fn test_train_centroids_separates_clusters() {
+        let vectors: Vec<Vec<f32>> = (0..100)
+            .map(|i| {
+                let offset = if i % 2 == 0 { 0.0 } else { 100.0 };
+                vec![offset + (i % 7) as f32 * 0.1, offset]
+            })
+            .collect();
+        let refs: Vec<&[f32]> = vectors.iter().map(|v| v.as_slice()).collect();
+
+        // Random init can put both seeds in one cluster, Lloyd's still has to split them
+        let centroids = train_centroids(&refs, 2, &DistanceType::L2);
+        assert_eq!(centroids.len(), 2);
+        let a = closest_centroid(&centroids, &[0.0, 0.0], &DistanceType::L2);
+        let b = closest_centroid(&centroids, &[100.0, 100.0], &DistanceType::L2);
+        assert_ne!(a, b);
+    }
+}
@@ -1,27 +1,48 @@
+mod ann;
+mod block_cache;
+mod bloom;
+mod catalog;
+mod checksum;
 mod collection;
 mod compact;
 mod constant;
 mod context;
 mod database;
+mod distance;
 mod document;
 mod error;
+mod hnsw;
 mod index;
+mod ivf;
 mod memtable;
+mod options;
+mod payload;
+mod payload_index;
 mod search;
 mod sst;
+mod table;
 mod utils;
 mod wal;
 
-pub use collection::{Collection, DistanceType, IndexConfig};
+pub use ann::AnnConfig;
+pub use block_cache::{BlockCache, BlockCacheStats, CacheCounters};
+pub use collection::{Collection, CollectionHealth, DistanceType, IndexConfig};
 pub use compact::CompactionManager;
 pub use database::AetherDB;
-pub use document::Document;
-pub use error::{CollectionError, WalError};
+pub use document::{Document, ExternalId};
+pub use error::{CollectionError, DatabaseError, WalError};
 pub use index::{IndexManager, SSTEvent, SSTMetadata};
-pub use search::SearchManager;
-pub use sst::{Footer, IndexEntry, SSTManager};
+pub use options::{
+    DatabaseOptions, WalRecoveryPolicy, WalSyncMode, WriteStallConfig, WriteStallPolicy,
+    WriteStallStats,
+};
+pub use payload::{Filter, Payload, PayloadValue};
+pub use payload_index::PayloadIndexType;
+pub use search::{ScoredDocument, SearchManager};
+pub use sst::{Footer, IndexEntry, SSTError, SSTManager};
+pub use table::{Table, VectorColumns};
 pub use utils::*;
-pub use wal::Operation;
+pub use wal::{Operation, SkippedRange, TornTail, WalRecoveryReport};
 
 #[cfg(test)]
 mod test_utils;
@@ -1,108 +1,732 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
-use crate::collection::IndexType;
-use crate::document::Document;
+use crate::ann::AnnConfig;
+use crate::collection::{DistanceType, IndexConfig, IndexType};
+use crate::document::{Document, Record};
+use crate::hnsw::{HnswGraph, HnswParams};
+use crate::ivf::{
+    IvfParams, TrainedCentroids, closest_centroid, closest_centroids, effective_nlist,
+    train_centroids,
+};
+use crate::payload::{Filter, accepts};
+use crate::payload_index::{PayloadIndex, PayloadIndexConfig};
+use crate::search::{ScoredDocument, TopK};
 
 pub trait MemTable: Send + Sync {
     fn upsert(&mut self, _doc: Document) {
         panic!("Not implemented");
     }
 
-    fn get(&self, _id: &u128) -> Option<Arc<Document>> {
+    /// `Some(Record::Tombstone)` when the id was deleted in this memtable
+    fn get(&self, _id: &u128) -> Option<Record> {
         panic!("Not implemented");
     }
 
-    fn search(&self, _vector: &Vec<f32>, _top_k: i32) -> Vec<Arc<Document>> {
+    fn search(&self, vector: &[f32], top_k: i32) -> Vec<ScoredDocument> {
+        self.search_filtered(vector, top_k, None)
+    }
+
+    /// Top-k among the documents accepted by `filter`, every document without one.
+    fn search_filtered(
+        &self,
+        _vector: &[f32],
+        _top_k: i32,
+        _filter: Option<&Filter>,
+    ) -> Vec<ScoredDocument> {
         panic!("Not implemented");
     }
 
+    /// Leaves a tombstone behind, the id may still live in an older tier
     fn delete(&mut self, _id: &u128) {
         panic!("Not implemented");
     }
 
+    /// Live documents and tombstones
     fn size(&self) -> usize {
         panic!("Not implemented");
     }
 
-    fn sorted_iter(&self) -> Box<dyn Iterator<Item = Arc<Document>> + '_> {
+    fn sorted_iter(&self) -> Box<dyn Iterator<Item = Record> + '_> {
+        panic!("Not implemented")
+    }
+
+    /// Payload indexes of the live documents
+    fn payload_index(&self) -> &PayloadIndex {
         panic!("Not implemented")
     }
+
+    /// ANN index the SST flushed from this memtable carries, `None` for a flat one
+    fn ann_config(&self) -> Option<&AnnConfig> {
+        None
+    }
+}
+
+fn lookup(live: Option<&Arc<Document>>, tombstones: &HashSet<u128>, id: &u128) -> Option<Record> {
+    match live {
+        Some(doc) => Some(Record::Live(doc.clone())),
+        None if tombstones.contains(id) => Some(Record::Tombstone(*id)),
+        None => None,
+    }
+}
+
+fn sorted_records<'a>(
+    live: impl Iterator<Item = &'a Arc<Document>>,
+    tombstones: &HashSet<u128>,
+) -> Box<dyn Iterator<Item = Record> + 'a> {
+    let mut records: Vec<Record> = live
+        .map(|doc| Record::Live(doc.clone()))
+        .chain(tombstones.iter().map(|id| Record::Tombstone(*id)))
+        .collect();
+    records.sort_unstable_by_key(|record| record.id());
+    Box::new(records.into_iter())
 }
 
 struct FlatMemTable {
     table: HashMap<u128, Arc<Document>>,
+    tombstones: HashSet<u128>,
+    distance: DistanceType,
+    payload_index: PayloadIndex,
 }
 
-impl Default for FlatMemTable {
-    fn default() -> Self {
+impl FlatMemTable {
+    fn new(distance: DistanceType, payload_indexes: &PayloadIndexConfig) -> Self {
         FlatMemTable {
             table: HashMap::with_capacity(500),
+            tombstones: HashSet::new(),
+            distance,
+            payload_index: PayloadIndex::new(payload_indexes),
         }
     }
 }
 
 impl MemTable for FlatMemTable {
     fn upsert(&mut self, doc: Document) {
-        self.table.insert(doc.id, Arc::new(doc));
+        let doc = Arc::new(doc);
+        self.tombstones.remove(&doc.id);
+        if let Some(old) = self.table.insert(doc.id, doc.clone()) {
+            self.payload_index.remove(&old);
+        }
+        self.payload_index.insert(&doc);
     }
 
     fn delete(&mut self, id: &u128) {
-        self.table.remove(id);
+        if let Some(old) = self.table.remove(id) {
+            self.payload_index.remove(&old);
+        }
+        self.tombstones.insert(*id);
     }
 
-    fn get(&self, id: &u128) -> Option<Arc<Document>> {
-        self.table.get(id).cloned()
+    fn get(&self, id: &u128) -> Option<Record> {
+        lookup(self.table.get(id), &self.tombstones, id)
     }
 
-    fn search(&self, vector: &Vec<f32>, top_k: i32) -> Vec<Arc<Document>> {
-        panic!("Not implemented");
+    // Exact scan, this is the ground truth the approximate indexes are measured against
+    fn search_filtered(
+        &self,
+        vector: &[f32],
+        top_k: i32,
+        filter: Option<&Filter>,
+    ) -> Vec<ScoredDocument> {
+        let mut top = TopK::new(top_k.max(0) as usize);
+        for doc in self.table.values().filter(|doc| accepts(filter, doc)) {
+            top.push(self.distance.distance(vector, &doc.vector), doc);
+        }
+
+        top.into_sorted_vec()
+            .into_iter()
+            .map(|(score, doc)| ScoredDocument {
+                document: doc.clone(),
+                score,
+            })
+            .collect()
     }
 
     fn size(&self) -> usize {
-        self.table.len()
+        self.table.len() + self.tombstones.len()
     }
 
-    fn sorted_iter(&self) -> Box<dyn Iterator<Item = Arc<Document>> + '_> {
-        // 可能return 要做 Option 避免 unwrap
-        let map = &self.table;
-        let mut keys: Vec<u128> = map.keys().copied().collect();
-        keys.sort_unstable();
-        Box::new(keys.into_iter().map(|id| map.get(&id).unwrap().clone()))
+    fn sorted_iter(&self) -> Box<dyn Iterator<Item = Record> + '_> {
+        sorted_records(self.table.values(), &self.tombstones)
+    }
+
+    fn payload_index(&self) -> &PayloadIndex {
+        &self.payload_index
+    }
+}
+
+struct HNSWMemTable {
+    graph: HnswGraph,
+    // id -> (graph node, document), only live documents are kept here
+    table: HashMap<u128, (usize, Arc<Document>)>,
+    tombstones: HashSet<u128>,
+    payload_index: PayloadIndex,
+    ann_config: AnnConfig,
+}
+
+impl HNSWMemTable {
+    fn new(
+        params: HnswParams,
+        distance: DistanceType,
+        payload_indexes: &PayloadIndexConfig,
+    ) -> Self {
+        HNSWMemTable {
+            ann_config: AnnConfig::Hnsw(params.clone(), distance.clone()),
+            graph: HnswGraph::new(params, distance),
+            table: HashMap::with_capacity(500),
+            tombstones: HashSet::new(),
+            payload_index: PayloadIndex::new(payload_indexes),
+        }
     }
 }
 
-struct HNSWMemTable {}
+impl MemTable for HNSWMemTable {
+    fn upsert(&mut self, doc: Document) {
+        let doc = Arc::new(doc);
+        self.tombstones.remove(&doc.id);
+        // Overwrites get a fresh node, the stale one only stays around for routing
+        if let Some((old_node, old)) = self.table.remove(&doc.id) {
+            self.graph.mark_deleted(old_node);
+            self.payload_index.remove(&old);
+        }
+        self.payload_index.insert(&doc);
+        let node = self.graph.insert(doc.clone());
+        self.table.insert(doc.id, (node, doc));
+    }
+
+    fn delete(&mut self, id: &u128) {
+        if let Some((node, old)) = self.table.remove(id) {
+            self.graph.mark_deleted(node);
+            self.payload_index.remove(&old);
+        }
+        self.tombstones.insert(*id);
+    }
+
+    fn get(&self, id: &u128) -> Option<Record> {
+        lookup(self.table.get(id).map(|(_, doc)| doc), &self.tombstones, id)
+    }
+
+    fn search_filtered(
+        &self,
+        vector: &[f32],
+        top_k: i32,
+        filter: Option<&Filter>,
+    ) -> Vec<ScoredDocument> {
+        self.graph
+            .search(vector, top_k.max(0) as usize, filter)
+            .into_iter()
+            .map(|(score, document)| ScoredDocument { document, score })
+            .collect()
+    }
 
-impl MemTable for HNSWMemTable {}
+    fn size(&self) -> usize {
+        self.table.len() + self.tombstones.len()
+    }
+
+    fn sorted_iter(&self) -> Box<dyn Iterator<Item = Record> + '_> {
+        sorted_records(self.table.values().map(|(_, doc)| doc), &self.tombstones)
+    }
+
+    fn payload_index(&self) -> &PayloadIndex {
+        &self.payload_index
+    }
 
-impl Default for HNSWMemTable {
-    fn default() -> Self {
-        HNSWMemTable {}
+    fn ann_config(&self) -> Option<&AnnConfig> {
+        Some(&self.ann_config)
     }
 }
 
-struct IVFMemTable {}
+struct IVFMemTable {
+    params: IvfParams,
+    distance: DistanceType,
+    // id -> (inverted list, document), the list is None until the centroids are trained
+    table: HashMap<u128, (Option<usize>, Arc<Document>)>,
+    tombstones: HashSet<u128>,
+    centroids: TrainedCentroids, // shared by the memtables of the collection
+    lists: Vec<HashMap<u128, Arc<Document>>>, // empty until this memtable is indexed
+    payload_index: PayloadIndex,
+    ann_config: AnnConfig,
+}
+
+impl IVFMemTable {
+    fn new(
+        params: IvfParams,
+        distance: DistanceType,
+        payload_indexes: &PayloadIndexConfig,
+        memtable_size: usize,
+        centroids: &TrainedCentroids,
+    ) -> Self {
+        IVFMemTable {
+            ann_config: AnnConfig::Ivf(params.clone(), distance.clone()),
+            // A memtable never holds much more than memtable_size documents: more lists
+            // would leave a handful of vectors in each, and a larger training sample
+            // would never be collected
+            params: IvfParams {
+                nlist: effective_nlist(params.nlist, memtable_size),
+                training_size: params.training_size.min(memtable_size),
+                ..params
+            },
+            distance,
+            table: HashMap::with_capacity(500),
+            tombstones: HashSet::new(),
+            // Trained by an earlier memtable, this one is indexed from its first write
+            lists: match centroids.get() {
+                Some(trained) => vec![HashMap::new(); trained.len()],
+                None => Vec::new(),
+            },
+            centroids: centroids.clone(),
+            payload_index: PayloadIndex::new(payload_indexes),
+        }
+    }
+
+    fn is_trained(&self) -> bool {
+        !self.lists.is_empty()
+    }
+
+    fn train(&mut self) {
+        let docs: Vec<Arc<Document>> = self.table.values().map(|(_, doc)| doc.clone()).collect();
+        let nlist = self.params.nlist;
+        let lists = self
+            .centroids
+            .get_or_train(|| {
+                let sample: Vec<&[f32]> = docs
+                    .iter()
+                    .take(self.params.training_size)
+                    .map(|doc| doc.vector.as_slice())
+                    .collect();
+                train_centroids(&sample, nlist, &self.distance)
+            })
+            .len();
 
-impl MemTable for IVFMemTable {}
+        self.lists = vec![HashMap::new(); lists];
+        for doc in docs {
+            self.assign(doc);
+        }
+    }
 
-impl Default for IVFMemTable {
-    fn default() -> Self {
-        IVFMemTable {}
+    fn assign(&mut self, doc: Arc<Document>) {
+        let centroids = self.centroids.get().unwrap_or_default();
+        let list = closest_centroid(centroids, &doc.vector, &self.distance);
+        self.lists[list].insert(doc.id, doc.clone());
+        self.table.insert(doc.id, (Some(list), doc));
+    }
+
+    fn remove(&mut self, id: &u128) -> Option<Arc<Document>> {
+        let (list, doc) = self.table.remove(id)?;
+        if let Some(list) = list {
+            self.lists[list].remove(id);
+        }
+        self.payload_index.remove(&doc);
+        Some(doc)
     }
 }
 
-pub fn get_memtable(i_type: &IndexType) -> Box<dyn MemTable> {
-    match i_type {
-        IndexType::Flat => Box::new(FlatMemTable::default()),
-        IndexType::HNSW => Box::new(HNSWMemTable::default()),
-        IndexType::IVF => Box::new(IVFMemTable::default()),
+impl MemTable for IVFMemTable {
+    fn upsert(&mut self, doc: Document) {
+        let doc = Arc::new(doc);
+        self.tombstones.remove(&doc.id);
+        self.remove(&doc.id);
+        self.payload_index.insert(&doc);
+
+        if self.is_trained() {
+            self.assign(doc);
+        } else {
+            self.table.insert(doc.id, (None, doc));
+            if self.table.len() >= self.params.training_size {
+                self.train();
+            }
+        }
+    }
+
+    fn delete(&mut self, id: &u128) {
+        self.remove(id);
+        self.tombstones.insert(*id);
+    }
+
+    fn get(&self, id: &u128) -> Option<Record> {
+        lookup(self.table.get(id).map(|(_, doc)| doc), &self.tombstones, id)
+    }
+
+    fn search_filtered(
+        &self,
+        vector: &[f32],
+        top_k: i32,
+        filter: Option<&Filter>,
+    ) -> Vec<ScoredDocument> {
+        let top_k = top_k.max(0) as usize;
+        let mut top = TopK::new(top_k);
+        if let (true, Some(centroids)) = (self.is_trained(), self.centroids.get()) {
+            let lists = closest_centroids(centroids, vector, self.lists.len(), &self.distance);
+
+            // Probe nprobe lists, then twice as many, until top_k documents are accepted
+            let (mut probed, mut nprobe, mut accepted) = (0, self.params.nprobe, 0);
+            while probed < lists.len() && accepted < top_k {
+                let end = nprobe.min(lists.len());
+                for list in lists[probed..end].iter() {
+                    for doc in self.lists[*list]
+                        .values()
+                        .filter(|doc| accepts(filter, doc))
+                    {
+                        top.push(self.distance.distance(vector, &doc.vector), doc);
+                        accepted += 1;
+                    }
+                }
+                probed = end;
+                nprobe *= 2;
+            }
+        } else {
+            for (_, doc) in self.table.values().filter(|(_, doc)| accepts(filter, doc)) {
+                top.push(self.distance.distance(vector, &doc.vector), doc);
+            }
+        }
+
+        top.into_sorted_vec()
+            .into_iter()
+            .map(|(score, doc)| ScoredDocument {
+                document: doc.clone(),
+                score,
+            })
+            .collect()
+    }
+
+    fn size(&self) -> usize {
+        self.table.len() + self.tombstones.len()
+    }
+
+    fn sorted_iter(&self) -> Box<dyn Iterator<Item = Record> + '_> {
+        sorted_records(self.table.values().map(|(_, doc)| doc), &self.tombstones)
+    }
+
+    fn payload_index(&self) -> &PayloadIndex {
+        &self.payload_index
+    }
+
+    fn ann_config(&self) -> Option<&AnnConfig> {
+        Some(&self.ann_config)
+    }
+}
+
+/// An empty memtable. `centroids` are the collection's, IVF memtables train them once
+/// and share them.
+pub fn get_memtable(
+    index_config: &IndexConfig,
+    distance: &DistanceType,
+    memtable_size: usize,
+    centroids: &TrainedCentroids,
+) -> Box<dyn MemTable> {
+    match index_config.index_type() {
+        IndexType::Flat => Box::new(FlatMemTable::new(
+            distance.clone(),
+            &index_config.payload_indexes,
+        )),
+        // Params are validated when the IndexConfig is built, fall back to defaults just in case
+        IndexType::HNSW => Box::new(HNSWMemTable::new(
+            HnswParams::from_params(&index_config.params).unwrap_or_default(),
+            distance.clone(),
+            &index_config.payload_indexes,
+        )),
+        IndexType::IVF => Box::new(IVFMemTable::new(
+            IvfParams::from_params(&index_config.params).unwrap_or_default(),
+            distance.clone(),
+            &index_config.payload_indexes,
+            memtable_size,
+            centroids,
+        )),
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::constant::DEFAULT_MEMTABLE_SIZE;
+    use crate::payload::Payload;
+    use crate::test_utils::bulk_random_documents;
+
+    fn flat_config() -> IndexConfig {
+        IndexConfig::new_with_default_config("flat").unwrap()
+    }
+
+    fn brute_force(
+        docs: &[Document],
+        query: &[f32],
+        k: usize,
+        distance: &DistanceType,
+    ) -> Vec<u128> {
+        let mut scored: Vec<(f32, u128)> = docs
+            .iter()
+            .map(|d| (distance.distance(query, &d.vector), d.id))
+            .collect();
+        scored.sort_by(|a, b| a.0.total_cmp(&b.0));
+        scored.into_iter().take(k).map(|(_, id)| id).collect()
+    }
 
     #[test]
-    fn test_flat_memtable() {}
+    fn test_flat_memtable() {
+        for distance in [DistanceType::L2, DistanceType::Cosine, DistanceType::Dot] {
+            let mut memtable = get_memtable(
+                &flat_config(),
+                &distance,
+                DEFAULT_MEMTABLE_SIZE,
+                &TrainedCentroids::default(),
+            );
+            let docs = bulk_random_documents(16, 200);
+            for doc in &docs {
+                memtable.upsert(doc.clone());
+            }
+
+            let query = docs[0].vector.clone();
+            let result = memtable.search(&query, 10);
+
+            assert_eq!(result.len(), 10);
+            assert!(result.windows(2).all(|w| w[0].score <= w[1].score));
+            let ids: Vec<u128> = result.iter().map(|r| r.document.id).collect();
+            assert_eq!(ids, brute_force(&docs, &query, 10, &distance));
+        }
+    }
+
+    #[test]
+    fn test_flat_memtable_top_k_larger_than_size() {
+        let mut memtable = get_memtable(
+            &flat_config(),
+            &DistanceType::L2,
+            DEFAULT_MEMTABLE_SIZE,
+            &TrainedCentroids::default(),
+        );
+        for doc in bulk_random_documents(4, 3) {
+            memtable.upsert(doc);
+        }
+
+        assert_eq!(memtable.search(&[0.0; 4], 10).len(), 3);
+        assert!(memtable.search(&[0.0; 4], 0).is_empty());
+    }
+
+    #[test]
+    fn test_hnsw_memtable() {
+        let config = IndexConfig::new_with_default_config("hnsw").unwrap();
+        let mut memtable = get_memtable(
+            &config,
+            &DistanceType::L2,
+            DEFAULT_MEMTABLE_SIZE,
+            &TrainedCentroids::default(),
+        );
+        let docs = bulk_random_documents(8, 300);
+        for doc in &docs {
+            memtable.upsert(doc.clone());
+        }
+        assert_eq!(memtable.size(), 300);
+
+        let result = memtable.search(&docs[7].vector, 5);
+        assert_eq!(result.len(), 5);
+        assert_eq!(result[0].document.id, docs[7].id);
+
+        // Overwrite moves the document, delete hides it
+        let mut moved = docs[7].clone();
+        moved.vector = vec![100.0; 8];
+        memtable.upsert(moved);
+        assert_eq!(memtable.size(), 300);
+        assert!(
+            matches!(memtable.get(&docs[7].id), Some(Record::Live(doc)) if doc.vector == vec![100.0; 8])
+        );
+        assert_eq!(memtable.search(&[100.0; 8], 1)[0].document.id, docs[7].id);
+
+        memtable.delete(&docs[7].id);
+        assert!(matches!(
+            memtable.get(&docs[7].id),
+            Some(Record::Tombstone(_))
+        ));
+        assert!(
+            memtable
+                .search(&[100.0; 8], 5)
+                .iter()
+                .all(|r| r.document.id != docs[7].id)
+        );
+
+        let records: Vec<Record> = memtable.sorted_iter().collect();
+        assert_eq!(records.len(), 300);
+        assert!(records.windows(2).all(|w| w[0].id() < w[1].id()));
+        assert_eq!(
+            records
+                .iter()
+                .filter(|r| matches!(r, Record::Tombstone(_)))
+                .count(),
+            1
+        );
+    }
+
+    fn ivf_config(nlist: usize, nprobe: usize) -> IndexConfig {
+        let mut params = HashMap::new();
+        params.insert("nlist".to_string(), nlist.to_string());
+        params.insert("nprobe".to_string(), nprobe.to_string());
+        params.insert("trainingSize".to_string(), "200".to_string());
+        IndexConfig::new("ivf", params).unwrap()
+    }
+
+    #[test]
+    fn test_ivf_memtable_falls_back_to_flat_before_training() {
+        let mut memtable = get_memtable(
+            &ivf_config(16, 1),
+            &DistanceType::L2,
+            DEFAULT_MEMTABLE_SIZE,
+            &TrainedCentroids::default(),
+        );
+        let docs = bulk_random_documents(8, 100);
+        for doc in &docs {
+            memtable.upsert(doc.clone());
+        }
+
+        let ids: Vec<u128> = memtable
+            .search(&docs[3].vector, 10)
+            .iter()
+            .map(|r| r.document.id)
+            .collect();
+        assert_eq!(
+            ids,
+            brute_force(&docs, &docs[3].vector, 10, &DistanceType::L2)
+        );
+    }
+
+    #[test]
+    fn test_ivf_memtable() {
+        // Probing every list is an exact search once trained
+        let mut memtable = get_memtable(
+            &ivf_config(8, 8),
+            &DistanceType::Cosine,
+            DEFAULT_MEMTABLE_SIZE,
+            &TrainedCentroids::default(),
+        );
+        let docs = bulk_random_documents(8, 500);
+        for doc in &docs {
+            memtable.upsert(doc.clone());
+        }
+        assert_eq!(memtable.size(), 500);
+
+        for query in docs.iter().take(10) {
+            let ids: Vec<u128> = memtable
+                .search(&query.vector, 10)
+                .iter()
+                .map(|r| r.document.id)
+                .collect();
+            assert_eq!(
+                ids,
+                brute_force(&docs, &query.vector, 10, &DistanceType::Cosine)
+            );
+        }
+
+        memtable.delete(&docs[0].id);
+        assert!(matches!(
+            memtable.get(&docs[0].id),
+            Some(Record::Tombstone(_))
+        ));
+        assert_ne!(
+            memtable.search(&docs[0].vector, 1)[0].document.id,
+            docs[0].id
+        );
+        assert_eq!(memtable.sorted_iter().count(), 500);
+
+        // Writing the id again resurrects it
+        memtable.upsert(docs[0].clone());
+        assert!(matches!(memtable.get(&docs[0].id), Some(Record::Live(_))));
+        assert_eq!(memtable.size(), 500);
+    }
+
+    #[test]
+    fn test_ivf_memtable_probes_more_lists_for_a_selective_filter() {
+        let mut memtable = get_memtable(
+            &ivf_config(64, 2),
+            &DistanceType::L2,
+            DEFAULT_MEMTABLE_SIZE,
+            &TrainedCentroids::default(),
+        );
+        let docs: Vec<Document> = bulk_random_documents(8, 4000)
+            .into_iter()
+            .enumerate()
+            .map(|(i, doc)| {
+                doc.with_payload(Payload::from([("rare".to_string(), (i % 100 == 0).into())]))
+            })
+            .collect();
+        for doc in &docs {
+            memtable.upsert(doc.clone());
+        }
+
+        let matching: Vec<Document> = docs.iter().step_by(100).cloned().collect();
+        let filter = Filter::eq("rare", true);
+        let ids: Vec<u128> = memtable
+            .search_filtered(&docs[1].vector, 10, Some(&filter))
+            .iter()
+            .map(|r| r.document.id)
+            .collect();
+        assert_eq!(ids.len(), 10);
+        for id in &ids {
+            assert!(matching.iter().any(|doc| doc.id == *id));
+        }
+
+        // Asking for more than ever pass the filter ends up scanning every list
+        assert_eq!(
+            memtable
+                .search_filtered(&docs[1].vector, 100, Some(&filter))
+                .len(),
+            40
+        );
+    }
+
+    #[test]
+    fn test_ivf_memtables_share_the_collection_centroids() {
+        let config = ivf_config(8, 1);
+        let params = IvfParams::from_params(&config.params).unwrap();
+        let centroids = TrainedCentroids::default();
+        let new_memtable = || {
+            IVFMemTable::new(
+                params.clone(),
+                DistanceType::L2,
+                &config.payload_indexes,
+                DEFAULT_MEMTABLE_SIZE,
+                &centroids,
+            )
+        };
+
+        let mut first = new_memtable();
+        for doc in bulk_random_documents(8, 199) {
+            first.upsert(doc);
+        }
+        assert!(!first.is_trained());
+        assert!(centroids.get().is_none());
+        first.upsert(bulk_random_documents(8, 1).remove(0));
+        assert!(first.is_trained());
+        assert_eq!(centroids.get().unwrap().len(), 8);
+
+        // The next memtable of the collection is indexed from its first write
+        let mut second = new_memtable();
+        assert!(second.is_trained());
+        let doc = bulk_random_documents(8, 1).remove(0);
+        second.upsert(doc.clone());
+        assert_eq!(second.search(&doc.vector, 1)[0].document.id, doc.id);
+        assert!(matches!(second.table.get(&doc.id), Some((Some(_), _))));
+    }
+
+    #[test]
+    fn test_nlist_and_training_are_capped_by_the_memtable_size() {
+        assert_eq!(effective_nlist(1024, DEFAULT_MEMTABLE_SIZE), 156);
+        assert_eq!(effective_nlist(8, DEFAULT_MEMTABLE_SIZE), 8);
+        assert_eq!(effective_nlist(8, 10), 1);
+
+        // 64 lists want 256 training vectors, a memtable of 100 never holds them
+        let config = ivf_config(64, 1);
+        let mut memtable = IVFMemTable::new(
+            IvfParams::from_params(&config.params).unwrap(),
+            DistanceType::L2,
+            &config.payload_indexes,
+            100,
+            &TrainedCentroids::default(),
+        );
+        let docs = bulk_random_documents(8, 100);
+        for doc in docs.iter() {
+            memtable.upsert(doc.clone());
+        }
+        assert!(memtable.is_trained());
+        assert_eq!(memtable.lists.len(), 3);
+        assert_eq!(
+            memtable.search(&docs[7].vector, 1)[0].document.id,
+            docs[7].id
+        );
+    }
 }
@@ -0,0 +1,106 @@
+/**
+ * Tunables of a database, passed to `AetherDB::with_options`.
+ */
+use std::time::Duration;
+
+use crate::constant::{
+    DEFAULT_BLOCK_CACHE_SIZE, DEFAULT_MAX_FROZEN_MEMTABLES, DEFAULT_MAX_PENDING_COMPACTIONS,
+    DEFAULT_MEMTABLE_SIZE, DEFAULT_SLOWDOWN_DELAY, DEFAULT_SLOWDOWN_TRIGGER,
+};
+
+#[derive(Debug, Clone)]
+pub struct DatabaseOptions {
+    pub memtable_size: usize, // records per memtable before it is frozen and flushed
+    pub write_stall: WriteStallConfig,
+    pub wal_sync: WalSyncMode, // applies to the WAL of every collection
+    pub wal_recovery: WalRecoveryPolicy,
+    pub block_cache_size: usize, // bytes of decoded SST blocks shared by every collection, 0 disables the cache
+}
+
+impl Default for DatabaseOptions {
+    fn default() -> Self {
+        DatabaseOptions {
+            memtable_size: DEFAULT_MEMTABLE_SIZE,
+            write_stall: WriteStallConfig::default(),
+            wal_sync: WalSyncMode::default(),
+            wal_recovery: WalRecoveryPolicy::default(),
+            block_cache_size: DEFAULT_BLOCK_CACHE_SIZE,
+        }
+    }
+}
+
+/// When a write reaches the disk. Every write is handed to the OS before it returns, so
+/// only an OS crash or a power loss can lose what was not synced yet.
+#[derive(Debug, Clone, Default)]
+pub enum WalSyncMode {
+    /// Fsync before every write returns.
+    #[default]
+    Always,
+    /// Concurrent writers share fsyncs: the first one to wait syncs for every write made
+    /// within `max_delay`, or as soon as `max_batch` writes are waiting. Writes still
+    /// return only once they are on disk.
+    GroupCommit {
+        max_delay: Duration,
+        max_batch: usize,
+    },
+    /// A background thread fsyncs at this interval, writes do not wait for it.
+    Interval(Duration),
+    /// Writes never wait for the disk, only memtable rotations and `close` fsync.
+    Never,
+}
+
+/// What replaying the WAL on open does with damaged records.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WalRecoveryPolicy {
+    /// Refuse to open on any damage, even a torn tail.
+    Strict,
+    /// Truncate a torn tail, the last write cut short by a crash, and refuse to open on
+    /// corruption followed by intact records.
+    #[default]
+    TolerateTornTail,
+    /// Also skip corrupted records and go on with the intact ones after them.
+    SkipCorrupted,
+}
+
+/// What a write does when the background flushes and merges of its collection fall behind.
+#[derive(Debug, Clone)]
+pub enum WriteStallPolicy {
+    /// Wait until the backlog drops below the limits.
+    Block,
+    /// Sleep for the given delay on every write once `slowdown_trigger` memtables are
+    /// frozen, and block at the hard limits.
+    SlowDown(Duration),
+    /// Fail right away with `CollectionError::WriteStall`.
+    Error,
+}
+
+/// Per collection limits on the work queued behind the writes.
+#[derive(Debug, Clone)]
+pub struct WriteStallConfig {
+    pub max_frozen_memtables: usize,
+    pub max_pending_compactions: usize, // queued or running flushes and merges
+    pub slowdown_trigger: usize,        // frozen memtables, only used by `SlowDown`
+    pub policy: WriteStallPolicy,
+}
+
+impl Default for WriteStallConfig {
+    fn default() -> Self {
+        WriteStallConfig {
+            max_frozen_memtables: DEFAULT_MAX_FROZEN_MEMTABLES,
+            max_pending_compactions: DEFAULT_MAX_PENDING_COMPACTIONS,
+            slowdown_trigger: DEFAULT_SLOWDOWN_TRIGGER,
+            policy: WriteStallPolicy::SlowDown(DEFAULT_SLOWDOWN_DELAY),
+        }
+    }
+}
+
+/// Counters of a collection's write stalls since it was opened.
+#[derive(Debug, Clone, Default)]
+pub struct WriteStallStats {
+    pub slowdowns: u64,      // writes delayed by `SlowDown`
+    pub stalls: u64,         // writes that hit a hard limit
+    pub rejected: u64,       // stalls answered with `CollectionError::WriteStall`
+    pub stalled_micros: u64, // time spent blocked on hard limits
+    pub frozen_memtables: usize,
+    pub pending_compactions: usize,
+}
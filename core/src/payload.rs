@@ -0,0 +1,252 @@
+use crate::document::Document;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+/// Structured metadata of a document, keyed by field name.
+pub type Payload = BTreeMap<String, PayloadValue>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum PayloadValue {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+    List(Vec<PayloadValue>),
+}
+
+impl PayloadValue {
+    /// Integers and floats compare with each other, every other kind only with itself.
+    fn compare(&self, other: &PayloadValue) -> Option<Ordering> {
+        match (self, other) {
+            (PayloadValue::String(a), PayloadValue::String(b)) => Some(a.cmp(b)),
+            (PayloadValue::Integer(a), PayloadValue::Integer(b)) => Some(a.cmp(b)),
+            (PayloadValue::Integer(a), PayloadValue::Float(b)) => (*a as f64).partial_cmp(b),
+            (PayloadValue::Float(a), PayloadValue::Integer(b)) => a.partial_cmp(&(*b as f64)),
+            (PayloadValue::Float(a), PayloadValue::Float(b)) => a.partial_cmp(b),
+            (PayloadValue::Bool(a), PayloadValue::Bool(b)) => Some(a.cmp(b)),
+            _ => None,
+        }
+    }
+
+    fn equals(&self, other: &PayloadValue) -> bool {
+        self == other || self.compare(other) == Some(Ordering::Equal)
+    }
+}
+
+impl From<&str> for PayloadValue {
+    fn from(value: &str) -> Self {
+        PayloadValue::String(value.to_string())
+    }
+}
+
+impl From<String> for PayloadValue {
+    fn from(value: String) -> Self {
+        PayloadValue::String(value)
+    }
+}
+
+impl From<i64> for PayloadValue {
+    fn from(value: i64) -> Self {
+        PayloadValue::Integer(value)
+    }
+}
+
+impl From<i32> for PayloadValue {
+    fn from(value: i32) -> Self {
+        PayloadValue::Integer(value as i64)
+    }
+}
+
+impl From<f64> for PayloadValue {
+    fn from(value: f64) -> Self {
+        PayloadValue::Float(value)
+    }
+}
+
+impl From<bool> for PayloadValue {
+    fn from(value: bool) -> Self {
+        PayloadValue::Bool(value)
+    }
+}
+
+impl<T: Into<PayloadValue>> From<Vec<T>> for PayloadValue {
+    fn from(values: Vec<T>) -> Self {
+        PayloadValue::List(values.into_iter().map(Into::into).collect())
+    }
+}
+
+/// A condition on document payloads, used to restrict a search.
+///
+/// A field condition on a list field holds if it holds for any element of the list.
+/// A document without the field never matches a field condition, `Not` turns that
+/// into a match.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    Eq(String, PayloadValue),
+    Gt(String, PayloadValue),
+    Gte(String, PayloadValue),
+    Lt(String, PayloadValue),
+    Lte(String, PayloadValue),
+    In(String, Vec<PayloadValue>),
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+}
+
+impl Filter {
+    pub fn eq(field: &str, value: impl Into<PayloadValue>) -> Self {
+        Filter::Eq(field.to_string(), value.into())
+    }
+
+    pub fn gt(field: &str, value: impl Into<PayloadValue>) -> Self {
+        Filter::Gt(field.to_string(), value.into())
+    }
+
+    pub fn gte(field: &str, value: impl Into<PayloadValue>) -> Self {
+        Filter::Gte(field.to_string(), value.into())
+    }
+
+    pub fn lt(field: &str, value: impl Into<PayloadValue>) -> Self {
+        Filter::Lt(field.to_string(), value.into())
+    }
+
+    pub fn lte(field: &str, value: impl Into<PayloadValue>) -> Self {
+        Filter::Lte(field.to_string(), value.into())
+    }
+
+    pub fn is_in<T: Into<PayloadValue>>(field: &str, values: Vec<T>) -> Self {
+        Filter::In(
+            field.to_string(),
+            values.into_iter().map(Into::into).collect(),
+        )
+    }
+
+    pub fn and(filters: Vec<Filter>) -> Self {
+        Filter::And(filters)
+    }
+
+    pub fn or(filters: Vec<Filter>) -> Self {
+        Filter::Or(filters)
+    }
+
+    pub fn negate(filter: Filter) -> Self {
+        Filter::Not(Box::new(filter))
+    }
+
+    pub fn matches(&self, payload: &Payload) -> bool {
+        match self {
+            Filter::Eq(field, target) => field_matches(payload, field, |v| v.equals(target)),
+            Filter::Gt(field, bound) => field_matches(payload, field, |v| {
+                v.compare(bound) == Some(Ordering::Greater)
+            }),
+            Filter::Gte(field, bound) => field_matches(payload, field, |v| {
+                matches!(v.compare(bound), Some(Ordering::Greater | Ordering::Equal))
+            }),
+            Filter::Lt(field, bound) => {
+                field_matches(payload, field, |v| v.compare(bound) == Some(Ordering::Less))
+            }
+            Filter::Lte(field, bound) => field_matches(payload, field, |v| {
+                matches!(v.compare(bound), Some(Ordering::Less | Ordering::Equal))
+            }),
+            Filter::In(field, targets) => {
+                field_matches(payload, field, |v| targets.iter().any(|t| v.equals(t)))
+            }
+            Filter::And(filters) => filters.iter().all(|f| f.matches(payload)),
+            Filter::Or(filters) => filters.iter().any(|f| f.matches(payload)),
+            Filter::Not(filter) => !filter.matches(payload),
+        }
+    }
+}
+
+fn field_matches(
+    payload: &Payload,
+    field: &str,
+    condition: impl Fn(&PayloadValue) -> bool,
+) -> bool {
+    match payload.get(field) {
+        Some(value @ PayloadValue::List(values)) => {
+            condition(value) || values.iter().any(&condition)
+        }
+        Some(value) => condition(value),
+        None => false,
+    }
+}
+
+/// Whether a search restricted by `filter` may return `document`.
+pub(crate) fn accepts(filter: Option<&Filter>, document: &Document) -> bool {
+    filter.is_none_or(|filter| filter.matches(&document.payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload() -> Payload {
+        Payload::from([
+            ("color".to_string(), "red".into()),
+            ("price".to_string(), 12.into()),
+            ("rating".to_string(), 4.5.into()),
+            ("in_stock".to_string(), true.into()),
+            ("tags".to_string(), vec!["sale", "new"].into()),
+        ])
+    }
+
+    #[test]
+    fn test_field_conditions() {
+        let payload = payload();
+        assert!(Filter::eq("color", "red").matches(&payload));
+        assert!(!Filter::eq("color", "blue").matches(&payload));
+        assert!(Filter::eq("price", 12.0).matches(&payload));
+        assert!(Filter::eq("in_stock", true).matches(&payload));
+        assert!(!Filter::eq("price", "12").matches(&payload));
+
+        assert!(Filter::gt("price", 10).matches(&payload));
+        assert!(Filter::gte("price", 12).matches(&payload));
+        assert!(!Filter::lt("price", 12).matches(&payload));
+        assert!(Filter::lte("rating", 4.5).matches(&payload));
+        assert!(Filter::lt("rating", 5).matches(&payload));
+
+        assert!(Filter::is_in("color", vec!["green", "red"]).matches(&payload));
+        assert!(!Filter::is_in("color", vec!["green"]).matches(&payload));
+
+        // A missing field fails every field condition
+        assert!(!Filter::eq("size", "xl").matches(&payload));
+        assert!(Filter::negate(Filter::eq("size", "xl")).matches(&payload));
+    }
+
+    #[test]
+    fn test_list_fields_match_any_element() {
+        let payload = payload();
+        assert!(Filter::eq("tags", "new").matches(&payload));
+        assert!(Filter::eq("tags", vec!["sale", "new"]).matches(&payload));
+        assert!(Filter::is_in("tags", vec!["old", "sale"]).matches(&payload));
+        assert!(!Filter::eq("tags", "old").matches(&payload));
+    }
+
+    #[test]
+    fn test_combinators() {
+        let payload = payload();
+        assert!(
+            Filter::and(vec![
+                Filter::eq("color", "red"),
+                Filter::gte("price", 10),
+                Filter::lt("price", 20),
+            ])
+            .matches(&payload)
+        );
+        assert!(
+            !Filter::and(vec![Filter::eq("color", "red"), Filter::gt("price", 20)])
+                .matches(&payload)
+        );
+        assert!(
+            Filter::or(vec![
+                Filter::eq("color", "blue"),
+                Filter::eq("tags", "sale")
+            ])
+            .matches(&payload)
+        );
+        assert!(!Filter::or(vec![]).matches(&payload));
+        assert!(Filter::and(vec![]).matches(&payload));
+    }
+}
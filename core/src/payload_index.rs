@@ -0,0 +1,369 @@
+/*
+Secondary payload indexes
+
+A collection declares which payload fields are indexed and how. Every memtable keeps an
+index of its live documents up to date, and every SST carries one for its records in a
+sidecar file, so a tier can answer "which ids may match this filter" without reading
+its documents.
+
+- Keyword: value -> ids, for string and boolean fields. Answers equality and `in`.
+- Numeric: sorted (value, id) pairs, for integer and float fields. Answers equality
+  and ranges.
+
+Values of a list field are indexed one by one. The candidates of a filter are a
+superset of its matches (integers are indexed as f64, ranges are looked up
+inclusively), so every candidate is still checked against the filter.
+*/
+
+use crate::document::{Document, Record};
+use crate::payload::{Filter, Payload, PayloadValue};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadIndexType {
+    Keyword,
+    Numeric,
+}
+
+/// Indexed payload fields of a collection.
+pub type PayloadIndexConfig = BTreeMap<String, PayloadIndexType>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Keyword {
+    Bool(bool),
+    String(String),
+}
+
+impl Keyword {
+    fn from_value(value: &PayloadValue) -> Option<Self> {
+        match value {
+            PayloadValue::String(s) => Some(Keyword::String(s.clone())),
+            PayloadValue::Bool(b) => Some(Keyword::Bool(*b)),
+            _ => None,
+        }
+    }
+}
+
+/// f64 mapped onto u64 so that the integer order is the float order.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct NumericKey(u64);
+
+impl NumericKey {
+    const MIN: NumericKey = NumericKey(0);
+    const MAX: NumericKey = NumericKey(u64::MAX);
+
+    fn new(value: f64) -> Self {
+        // -0.0 == 0.0 for the filters, they get the same key
+        let value = if value == 0.0 { 0.0 } else { value };
+        let bits = value.to_bits();
+        if bits >> 63 == 1 {
+            NumericKey(!bits)
+        } else {
+            NumericKey(bits | 1 << 63)
+        }
+    }
+
+    fn from_value(value: &PayloadValue) -> Option<Self> {
+        match value {
+            PayloadValue::Integer(i) => Some(NumericKey::new(*i as f64)),
+            PayloadValue::Float(f) => Some(NumericKey::new(*f)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum FieldIndex {
+    Keyword(BTreeMap<Keyword, BTreeSet<u128>>),
+    Numeric(BTreeSet<(NumericKey, u128)>),
+}
+
+impl FieldIndex {
+    fn insert(&mut self, value: &PayloadValue, id: u128) {
+        match self {
+            FieldIndex::Keyword(keywords) => {
+                if let Some(keyword) = Keyword::from_value(value) {
+                    keywords.entry(keyword).or_default().insert(id);
+                }
+            }
+            FieldIndex::Numeric(numbers) => {
+                if let Some(key) = NumericKey::from_value(value) {
+                    numbers.insert((key, id));
+                }
+            }
+        }
+    }
+
+    fn remove(&mut self, value: &PayloadValue, id: u128) {
+        match self {
+            FieldIndex::Keyword(keywords) => {
+                if let Some(keyword) = Keyword::from_value(value)
+                    && let Some(ids) = keywords.get_mut(&keyword)
+                {
+                    ids.remove(&id);
+                    if ids.is_empty() {
+                        keywords.remove(&keyword);
+                    }
+                }
+            }
+            FieldIndex::Numeric(numbers) => {
+                if let Some(key) = NumericKey::from_value(value) {
+                    numbers.remove(&(key, id));
+                }
+            }
+        }
+    }
+
+    /// Ids whose value may equal `target`, `None` if this index cannot tell.
+    fn equal(&self, target: &PayloadValue) -> Option<BTreeSet<u128>> {
+        match self {
+            FieldIndex::Keyword(keywords) => {
+                let keyword = Keyword::from_value(target)?;
+                Some(keywords.get(&keyword).cloned().unwrap_or_default())
+            }
+            FieldIndex::Numeric(_) => {
+                let key = NumericKey::from_value(target)?;
+                self.range(Bound::Included(key), Bound::Included(key))
+            }
+        }
+    }
+
+    fn range(&self, lower: Bound<NumericKey>, upper: Bound<NumericKey>) -> Option<BTreeSet<u128>> {
+        let FieldIndex::Numeric(numbers) = self else {
+            return None;
+        };
+        let lower = match lower {
+            Bound::Included(key) => Bound::Included((key, u128::MIN)),
+            Bound::Excluded(key) => Bound::Excluded((key, u128::MAX)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let upper = match upper {
+            Bound::Included(key) => Bound::Included((key, u128::MAX)),
+            Bound::Excluded(key) => Bound::Excluded((key, u128::MIN)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        Some(numbers.range((lower, upper)).map(|(_, id)| *id).collect())
+    }
+}
+
+/// Payload indexes of one tier, a memtable or an SST.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PayloadIndex {
+    fields: BTreeMap<String, FieldIndex>,
+}
+
+impl PayloadIndex {
+    pub fn new(config: &PayloadIndexConfig) -> Self {
+        let fields = config
+            .iter()
+            .map(|(field, kind)| {
+                let index = match kind {
+                    PayloadIndexType::Keyword => FieldIndex::Keyword(BTreeMap::new()),
+                    PayloadIndexType::Numeric => FieldIndex::Numeric(BTreeSet::new()),
+                };
+                (field.clone(), index)
+            })
+            .collect();
+        PayloadIndex { fields }
+    }
+
+    /// Index of the live documents among `records`, used for compaction outputs.
+    pub fn build(config: &PayloadIndexConfig, records: &[Record]) -> Self {
+        let mut index = Self::new(config);
+        for record in records {
+            if let Record::Live(document) = record {
+                index.insert(document);
+            }
+        }
+        index
+    }
+
+    /// No field is indexed, there is nothing worth persisting.
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    pub fn insert(&mut self, document: &Document) {
+        self.update(&document.payload, document.id, FieldIndex::insert);
+    }
+
+    pub fn remove(&mut self, document: &Document) {
+        self.update(&document.payload, document.id, FieldIndex::remove);
+    }
+
+    fn update(
+        &mut self,
+        payload: &Payload,
+        id: u128,
+        apply: fn(&mut FieldIndex, &PayloadValue, u128),
+    ) {
+        for (field, index) in self.fields.iter_mut() {
+            match payload.get(field) {
+                Some(PayloadValue::List(values)) => {
+                    for value in values {
+                        apply(index, value, id);
+                    }
+                }
+                Some(value) => apply(index, value, id),
+                None => {}
+            }
+        }
+    }
+
+    /// Ids that may match `filter`, or `None` if the indexed fields cannot narrow it
+    /// down and every document has to be checked.
+    pub fn candidates(&self, filter: &Filter) -> Option<BTreeSet<u128>> {
+        match filter {
+            Filter::Eq(field, target) => self.fields.get(field)?.equal(target),
+            Filter::In(field, targets) => {
+                let index = self.fields.get(field)?;
+                let mut ids = BTreeSet::new();
+                for target in targets {
+                    ids.append(&mut index.equal(target)?);
+                }
+                Some(ids)
+            }
+            // Bounds are inclusive, a strict comparison of two integers that share an
+            // f64 must not lose the larger one
+            Filter::Gt(field, bound) | Filter::Gte(field, bound) => {
+                let key = NumericKey::from_value(bound)?;
+                self.fields
+                    .get(field)?
+                    .range(Bound::Included(key), Bound::Included(NumericKey::MAX))
+            }
+            Filter::Lt(field, bound) | Filter::Lte(field, bound) => {
+                let key = NumericKey::from_value(bound)?;
+                self.fields
+                    .get(field)?
+                    .range(Bound::Included(NumericKey::MIN), Bound::Included(key))
+            }
+            // Any narrowed down operand bounds the conjunction
+            Filter::And(filters) => filters
+                .iter()
+                .filter_map(|f| self.candidates(f))
+                .reduce(|a, b| a.intersection(&b).copied().collect()),
+            Filter::Or(filters) => {
+                let mut ids = BTreeSet::new();
+                for filter in filters {
+                    ids.append(&mut self.candidates(filter)?);
+                }
+                Some(ids)
+            }
+            Filter::Not(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::accepts;
+
+    fn document(id: u128, payload: Vec<(&str, PayloadValue)>) -> Document {
+        Document {
+            id,
+            vector: vec![0.0],
+            content: String::new(),
+            payload: payload
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+            external_id: None,
+        }
+    }
+
+    fn index() -> PayloadIndex {
+        let config = PayloadIndexConfig::from([
+            ("color".to_string(), PayloadIndexType::Keyword),
+            ("price".to_string(), PayloadIndexType::Numeric),
+        ]);
+        let mut index = PayloadIndex::new(&config);
+        index.insert(&document(
+            1,
+            vec![("color", "red".into()), ("price", 5.into())],
+        ));
+        index.insert(&document(2, vec![("color", vec!["red", "blue"].into())]));
+        index.insert(&document(
+            3,
+            vec![("color", "blue".into()), ("price", 7.5.into())],
+        ));
+        index.insert(&document(4, vec![("price", (-2).into())]));
+        index
+    }
+
+    fn ids(candidates: Option<BTreeSet<u128>>) -> Vec<u128> {
+        candidates.unwrap().into_iter().collect()
+    }
+
+    #[test]
+    fn test_keyword_and_numeric_lookups() {
+        let index = index();
+        assert_eq!(ids(index.candidates(&Filter::eq("color", "red"))), [1, 2]);
+        assert_eq!(
+            ids(index.candidates(&Filter::is_in("color", vec!["blue", "green"]))),
+            [2, 3]
+        );
+        assert_eq!(ids(index.candidates(&Filter::eq("price", 7.5))), [3]);
+        assert_eq!(ids(index.candidates(&Filter::gte("price", 5))), [1, 3]);
+        assert_eq!(ids(index.candidates(&Filter::lt("price", 0.0))), [4]);
+
+        // Fields and kinds the index cannot answer
+        assert!(index.candidates(&Filter::eq("size", "xl")).is_none());
+        assert!(index.candidates(&Filter::eq("color", 1)).is_none());
+        assert!(
+            index
+                .candidates(&Filter::negate(Filter::eq("color", "red")))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_combinators_and_removal() {
+        let mut index = index();
+        let red_and_cheap = Filter::and(vec![
+            Filter::eq("color", "red"),
+            Filter::lte("price", 6),
+            Filter::eq("size", "xl"),
+        ]);
+        assert_eq!(ids(index.candidates(&red_and_cheap)), [1]);
+        assert!(
+            index
+                .candidates(&Filter::or(vec![
+                    Filter::eq("color", "red"),
+                    Filter::eq("size", "xl"),
+                ]))
+                .is_none()
+        );
+
+        index.remove(&document(2, vec![("color", vec!["red", "blue"].into())]));
+        assert_eq!(ids(index.candidates(&Filter::eq("color", "red"))), [1]);
+        assert_eq!(ids(index.candidates(&Filter::eq("color", "blue"))), [3]);
+    }
+
+    #[test]
+    fn test_numeric_key_keeps_float_order() {
+        let values = [f64::NEG_INFINITY, -3.5, -0.0, 0.0, 1e-9, 2.0, 1e300];
+        let keys: Vec<NumericKey> = values.iter().map(|v| NumericKey::new(*v)).collect();
+        assert!(keys.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn test_signed_zeros_share_a_key() {
+        assert_eq!(NumericKey::new(-0.0), NumericKey::new(0.0));
+
+        let config = PayloadIndexConfig::from([("price".to_string(), PayloadIndexType::Numeric)]);
+        let mut index = PayloadIndex::new(&config);
+        let positive = document(1, vec![("price", 0.0.into())]);
+        let negative = document(2, vec![("price", (-0.0).into())]);
+        index.insert(&positive);
+        index.insert(&negative);
+
+        // The prefilter agrees with the post-filter on both
+        for filter in [Filter::eq("price", 0.0), Filter::eq("price", -0.0)] {
+            assert_eq!(ids(index.candidates(&filter)), [1, 2]);
+            assert!(accepts(Some(&filter), &positive) && accepts(Some(&filter), &negative));
+        }
+    }
+}
@@ -1,28 +1,678 @@
 use crate::DistanceType;
 use crate::IndexManager;
-use crate::document::Document;
+use crate::SSTMetadata;
+use crate::constant::PREFILTER_MAX_SELECTIVITY;
+use crate::document::{Document, Record};
+use crate::error::CollectionError;
+use crate::memtable::MemTable;
+use crate::payload::{Filter, accepts};
+use crate::payload_index::PayloadIndex;
+use crate::sst::{SSTError, SSTManager};
+use crate::table::Table;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, BinaryHeap};
 use std::sync::Arc;
 
+/// A search hit, `score` is the distance to the query (lower is closer).
+#[derive(Debug, Clone)]
+pub struct ScoredDocument {
+    pub document: Arc<Document>,
+    pub score: f32,
+}
+
+struct HeapEntry<T> {
+    score: f32,
+    item: T,
+}
+
+impl<T> PartialEq for HeapEntry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.score.total_cmp(&other.score) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for HeapEntry<T> {}
+
+impl<T> PartialOrd for HeapEntry<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for HeapEntry<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score.total_cmp(&other.score)
+    }
+}
+
+/// Bounded max-heap keeping the `k` lowest scores seen so far,
+/// so a scan costs O(n log k) instead of sorting every candidate.
+pub struct TopK<T> {
+    k: usize,
+    heap: BinaryHeap<HeapEntry<T>>,
+}
+
+impl<T> TopK<T> {
+    pub fn new(k: usize) -> Self {
+        TopK {
+            k,
+            heap: BinaryHeap::with_capacity(k + 1),
+        }
+    }
+
+    /// The score a candidate has to beat to enter the heap.
+    pub fn threshold(&self) -> f32 {
+        if self.heap.len() < self.k {
+            f32::INFINITY
+        } else {
+            self.heap.peek().map_or(f32::INFINITY, |e| e.score)
+        }
+    }
+
+    pub fn push(&mut self, score: f32, item: T) {
+        if self.k == 0 || score >= self.threshold() {
+            return;
+        }
+        self.heap.push(HeapEntry { score, item });
+        if self.heap.len() > self.k {
+            self.heap.pop();
+        }
+    }
+
+    /// Drains the heap, closest first.
+    pub fn into_sorted_vec(self) -> Vec<(f32, T)> {
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|e| (e.score, e.item))
+            .collect()
+    }
+}
+
+/// A top-k candidate, documents found in an SST's vector columns are only read once
+/// they make the final cut.
+enum Hit {
+    Loaded(Arc<Document>),
+    Flushed(Arc<SSTMetadata>, u128),
+}
+
+/// Ids to score one by one when the payload index narrows `filter` down to a small
+/// share of a tier holding `size` records. `None` when filtering the results of the
+/// tier's own search is cheaper, or when there is nothing to narrow down.
+fn prefilter(
+    index: Option<&PayloadIndex>,
+    filter: Option<&Filter>,
+    size: usize,
+) -> Option<BTreeSet<u128>> {
+    let candidates = index?.candidates(filter?)?;
+    (candidates.len() as f64 <= size as f64 * PREFILTER_MAX_SELECTIVITY).then_some(candidates)
+}
+
 pub struct SearchManager {
     index_manager: Arc<IndexManager>,
+    sst_manager: Arc<SSTManager>,
     distance: DistanceType,
 }
 
 impl SearchManager {
-    pub fn new(index_manager: Arc<IndexManager>, distance: DistanceType) -> Self {
+    pub fn new(
+        index_manager: Arc<IndexManager>,
+        sst_manager: Arc<SSTManager>,
+        distance: DistanceType,
+    ) -> Self {
         SearchManager {
             index_manager,
+            sst_manager,
             distance,
         }
     }
 
-    pub fn search(&self, query: &Vec<f32>, top_k: i32) -> Vec<Arc<Document>> {
-        panic!("Not implemented");
+    /// Global top-k over every tier of the collection.
+    ///
+    /// `memtables` must be ordered newest first (active memtable, then frozen ones from the
+    /// most recently frozen), every SST is older than any memtable. When an id lives in
+    /// several tiers only the newest version is a candidate, even if an older one is closer.
+    /// `filter` applies to that newest version: an older version that matches does not
+    /// stand in for a newer one that does not. Each tier is pre-filtered through its
+    /// payload index when the filter is selective enough, post-filtered otherwise.
+    /// Without a filter SSTs are scored from their vector columns alone, through their
+    /// ANN index when they carry one, and only the documents that make the final top-k
+    /// are read.
+    pub fn search(
+        &self,
+        query: &[f32],
+        top_k: i32,
+        filter: Option<&Filter>,
+        memtables: &[&dyn MemTable],
+    ) -> Result<Vec<ScoredDocument>, CollectionError> {
+        let top_k = top_k.max(0) as usize;
+        let mut top = TopK::new(top_k);
+
+        for (i, memtable) in memtables.iter().enumerate() {
+            let newer = &memtables[..i];
+            match prefilter(Some(memtable.payload_index()), filter, memtable.size()) {
+                Some(candidates) => {
+                    for id in candidates {
+                        let Some(Record::Live(document)) = memtable.get(&id) else {
+                            continue;
+                        };
+                        if accepts(filter, &document) && newer.iter().all(|m| m.get(&id).is_none())
+                        {
+                            top.push(
+                                self.distance.distance(query, &document.vector),
+                                Hit::Loaded(document),
+                            );
+                        }
+                    }
+                }
+                None => {
+                    for hit in self.search_memtable(*memtable, newer, query, top_k, filter) {
+                        top.push(hit.score, Hit::Loaded(hit.document));
+                    }
+                }
+            }
+        }
+
+        // A row is shadowed by any newer version of its id, tombstones included: in a
+        // memtable or in one of the SSTs scanned before its own. Only rows close enough to
+        // make the top-k are checked, through the bloom filter and id column of the newer
+        // SSTs covering the id.
+        let mut newer: Vec<Arc<Table>> = Vec::new();
+        let shadowed = |id: u128, newer: &[Arc<Table>]| -> Result<bool, SSTError> {
+            if memtables.iter().any(|m| m.get(&id).is_some()) {
+                return Ok(true);
+            }
+            for table in newer {
+                if table.contains(id)? {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        };
+
+        for metadata in self.index_manager.sst_metadata_newest_first() {
+            let table = self.sst_manager.table(&metadata)?;
+            let candidates = prefilter(
+                metadata.payload_index.as_deref(),
+                filter,
+                metadata.entry_count as usize,
+            );
+            let records = match (candidates, filter) {
+                (Some(candidates), _) => table.scan_selected(&candidates)?,
+                // Every block of the file, kept out of the cache like compaction reads
+                (None, Some(_)) => self.sst_manager.scan_uncached(&metadata)?,
+                // Nothing to check but distances, the vectors alone will do
+                (None, None) => {
+                    let columns = table.vector_columns()?;
+                    let ids = columns.ids();
+                    match table.ann_index()? {
+                        Some(ann) => {
+                            // The index asks about one row at a time, keep the first error
+                            let error = RefCell::new(None);
+                            let visible = |row: usize| {
+                                !columns.is_tombstone(row)
+                                    && match shadowed(ids[row], &newer) {
+                                        Ok(shadowed) => !shadowed,
+                                        Err(e) => {
+                                            error.borrow_mut().get_or_insert(e);
+                                            false
+                                        }
+                                    }
+                            };
+                            let hits = ann.search(&columns, &self.distance, query, top_k, visible);
+                            if let Some(e) = error.into_inner() {
+                                return Err(e.into());
+                            }
+                            for (score, row) in hits {
+                                top.push(score, Hit::Flushed(metadata.clone(), ids[row]));
+                            }
+                        }
+                        None => {
+                            for row in (0..columns.len()).filter(|row| !columns.is_tombstone(*row))
+                            {
+                                let score = self.distance.distance(query, columns.vector(row));
+                                if score < top.threshold() && !shadowed(ids[row], &newer)? {
+                                    top.push(score, Hit::Flushed(metadata.clone(), ids[row]));
+                                }
+                            }
+                        }
+                    }
+                    newer.push(table);
+                    continue;
+                }
+            };
+            for record in records.iter() {
+                let Record::Live(document) = record else {
+                    continue;
+                };
+                if !accepts(filter, document) {
+                    continue;
+                }
+
+                let score = self.distance.distance(query, &document.vector);
+                if score < top.threshold() && !shadowed(document.id, &newer)? {
+                    top.push(score, Hit::Loaded(document.clone()));
+                }
+            }
+            newer.push(table);
+        }
+
+        top.into_sorted_vec()
+            .into_iter()
+            .map(|(score, hit)| {
+                let document = match hit {
+                    Hit::Loaded(document) => document,
+                    Hit::Flushed(metadata, id) => self
+                        .sst_manager
+                        .read_from(&metadata.path, id)?
+                        .into_document()
+                        .ok_or(SSTError::NotFound)?,
+                };
+                Ok(ScoredDocument { document, score })
+            })
+            .collect()
+    }
+
+    /// Top-k of a single memtable after dropping ids overwritten in a newer memtable.
+    /// Shadowed hits take slots in the memtable's own top-k, so widen until enough survive.
+    fn search_memtable(
+        &self,
+        memtable: &dyn MemTable,
+        newer: &[&dyn MemTable],
+        query: &[f32],
+        top_k: usize,
+        filter: Option<&Filter>,
+    ) -> Vec<ScoredDocument> {
+        let mut k = top_k;
+        loop {
+            let hits = memtable.search_filtered(query, k as i32, filter);
+            let exhausted = hits.len() < k;
+            let visible: Vec<ScoredDocument> = hits
+                .into_iter()
+                .filter(|hit| newer.iter().all(|m| m.get(&hit.document.id).is_none()))
+                .collect();
+
+            if visible.len() >= top_k || exhausted || k >= memtable.size() {
+                return visible;
+            }
+            k *= 2;
+        }
+    }
+
+    /// Latest version of a document, `memtables` ordered newest first like in `search`.
+    /// The newest record wins, so a tombstone means the document is gone.
+    pub fn fetch(
+        &self,
+        id: &u128,
+        memtables: &[&dyn MemTable],
+    ) -> Result<Option<Arc<Document>>, CollectionError> {
+        for memtable in memtables {
+            if let Some(record) = memtable.get(id) {
+                return Ok(record.into_document());
+            }
+        }
+
+        for metadata in self.index_manager.get_sst_metadata(*id) {
+            match self.sst_manager.read_from(&metadata.path, *id) {
+                Ok(record) => return Ok(record.into_document()),
+                Err(SSTError::NotFound) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collection::IndexConfig;
+    use crate::constant::DEFAULT_MEMTABLE_SIZE;
+    use crate::ivf::TrainedCentroids;
+    use crate::memtable::get_memtable;
+    use crate::payload::Payload;
+    use tempfile::tempdir;
+
+    fn document(id: u128, vector: Vec<f32>) -> Document {
+        Document {
+            id,
+            vector,
+            content: id.to_string(),
+            payload: Default::default(),
+            external_id: None,
+        }
+    }
+
+    fn flat_memtable(documents: Vec<Document>) -> Box<dyn MemTable> {
+        let mut memtable = get_memtable(
+            &IndexConfig::new_with_default_config("flat").unwrap(),
+            &DistanceType::L2,
+            DEFAULT_MEMTABLE_SIZE,
+            &TrainedCentroids::default(),
+        );
+        for document in documents {
+            memtable.upsert(document);
+        }
+        memtable
+    }
+
+    #[test]
+    fn test_search_merges_tiers_newest_version_wins() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let sst_manager = Arc::new(SSTManager::new(dir.path().to_path_buf()));
+        let index_manager = Arc::new(IndexManager::default());
+
+        // Oldest tier on disk: id 1 is right on the query, but it gets overwritten later
+        let flushed = flat_memtable(vec![
+            document(1, vec![0.0, 0.0]),
+            document(2, vec![1.0, 0.0]),
+            document(3, vec![9.0, 0.0]),
+        ]);
+        index_manager.add_sst_metadata(
+            sst_manager
+                .write_memtable("test", 1, 0, flushed.as_ref())
+                .unwrap(),
+        );
+
+        let frozen = flat_memtable(vec![
+            document(1, vec![5.0, 0.0]),
+            document(4, vec![2.0, 0.0]),
+        ]);
+        let active = flat_memtable(vec![
+            document(4, vec![8.0, 0.0]),
+            document(5, vec![3.0, 0.0]),
+        ]);
+
+        let search_manager = SearchManager::new(index_manager, sst_manager, DistanceType::L2);
+        let result = search_manager
+            .search(&[0.0, 0.0], 4, None, &[active.as_ref(), frozen.as_ref()])
+            .unwrap();
+
+        let ids: Vec<u128> = result.iter().map(|r| r.document.id).collect();
+        assert_eq!(ids, vec![2, 5, 1, 4]);
+        assert_eq!(result[2].document.vector, vec![5.0, 0.0]);
+        assert_eq!(result[3].document.vector, vec![8.0, 0.0]);
+    }
+
+    #[test]
+    fn test_indexed_ssts_skip_shadowed_rows() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let sst_manager = Arc::new(SSTManager::new(dir.path().to_path_buf()));
+        let index_manager = Arc::new(IndexManager::default());
+
+        let mut flushed = get_memtable(
+            &IndexConfig::new_with_default_config("hnsw").unwrap(),
+            &DistanceType::L2,
+            DEFAULT_MEMTABLE_SIZE,
+            &TrainedCentroids::default(),
+        );
+        for id in 1..=50 {
+            flushed.upsert(document(id, vec![id as f32, 0.0]));
+        }
+        let metadata = sst_manager
+            .write_memtable("test", 1, 0, flushed.as_ref())
+            .unwrap();
+        let table = sst_manager.table(&metadata).unwrap();
+        assert!(matches!(table.ann_index(), Ok(Some(_))));
+        index_manager.add_sst_metadata(metadata);
+
+        // The closest rows of the graph are overwritten or deleted in the memtable
+        let mut active = flat_memtable(vec![
+            document(1, vec![100.0, 0.0]),
+            document(2, vec![101.0, 0.0]),
+        ]);
+        active.delete(&3);
+
+        let search_manager = SearchManager::new(index_manager, sst_manager, DistanceType::L2);
+        let result = search_manager
+            .search(&[0.0, 0.0], 4, None, &[active.as_ref()])
+            .unwrap();
+        let ids: Vec<u128> = result.iter().map(|r| r.document.id).collect();
+        assert_eq!(ids, vec![4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn test_newer_ssts_shadow_indexed_rows() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let sst_manager = Arc::new(SSTManager::new(dir.path().to_path_buf()));
+
+        for config in ["hnsw", "ivf"] {
+            let mut older = get_memtable(
+                &IndexConfig::new_with_default_config(config).unwrap(),
+                &DistanceType::L2,
+                DEFAULT_MEMTABLE_SIZE,
+                &TrainedCentroids::default(),
+            );
+            for id in 1..=50 {
+                older.upsert(document(id, vec![id as f32, 0.0]));
+            }
+            let mut newer = flat_memtable(vec![
+                document(1, vec![100.0, 0.0]),
+                document(2, vec![101.0, 0.0]),
+            ]);
+            newer.delete(&3);
+
+            let index_manager = Arc::new(IndexManager::default());
+            for (seq_no, memtable) in [(1, older), (2, newer)] {
+                let collection = format!("test_{}", config);
+                let metadata = sst_manager
+                    .write_memtable(&collection, seq_no, 0, memtable.as_ref())
+                    .unwrap();
+                index_manager.add_sst_metadata(metadata);
+            }
+
+            let search_manager =
+                SearchManager::new(index_manager, sst_manager.clone(), DistanceType::L2);
+            let result = search_manager.search(&[0.0, 0.0], 4, None, &[]).unwrap();
+            let ids: Vec<u128> = result.iter().map(|r| r.document.id).collect();
+            assert_eq!(ids, vec![4, 5, 6, 7], "{}", config);
+        }
+    }
+
+    #[test]
+    fn test_fetch_returns_latest_version() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let sst_manager = Arc::new(SSTManager::new(dir.path().to_path_buf()));
+        let index_manager = Arc::new(IndexManager::default());
+
+        // Both files cover id 2, the newer one must win
+        let older = flat_memtable(vec![document(1, vec![1.0]), document(2, vec![2.0])]);
+        let newer = flat_memtable(vec![document(2, vec![20.0]), document(3, vec![3.0])]);
+        for (seq_no, memtable) in [(1, older), (2, newer)] {
+            index_manager.add_sst_metadata(
+                sst_manager
+                    .write_memtable("test", seq_no, 0, memtable.as_ref())
+                    .unwrap(),
+            );
+        }
+
+        let active = flat_memtable(vec![document(3, vec![30.0])]);
+        let search_manager = SearchManager::new(index_manager, sst_manager, DistanceType::L2);
+        let fetch = |id: u128| {
+            search_manager
+                .fetch(&id, &[active.as_ref()])
+                .unwrap()
+                .map(|document| document.vector[0])
+        };
+
+        assert_eq!(fetch(1), Some(1.0));
+        assert_eq!(fetch(2), Some(20.0));
+        assert_eq!(fetch(3), Some(30.0));
+        assert_eq!(fetch(4), None);
+    }
+
+    #[test]
+    fn test_tombstones_hide_older_versions() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let sst_manager = Arc::new(SSTManager::new(dir.path().to_path_buf()));
+        let index_manager = Arc::new(IndexManager::default());
+
+        let older = flat_memtable(vec![
+            document(1, vec![0.0]),
+            document(2, vec![1.0]),
+            document(3, vec![2.0]),
+        ]);
+        let mut newer = flat_memtable(vec![]);
+        newer.delete(&1);
+        for (seq_no, memtable) in [(1, older), (2, newer)] {
+            index_manager.add_sst_metadata(
+                sst_manager
+                    .write_memtable("test", seq_no, 0, memtable.as_ref())
+                    .unwrap(),
+            );
+        }
+
+        // Deleted in memory while the live version is only on disk
+        let mut active = flat_memtable(vec![]);
+        active.delete(&2);
+
+        let search_manager = SearchManager::new(index_manager, sst_manager, DistanceType::L2);
+        let memtables = [active.as_ref()];
+
+        assert!(search_manager.fetch(&1, &memtables).unwrap().is_none());
+        assert!(search_manager.fetch(&2, &memtables).unwrap().is_none());
+        assert!(search_manager.fetch(&3, &memtables).unwrap().is_some());
+
+        let result = search_manager.search(&[0.0], 3, None, &memtables).unwrap();
+        let ids: Vec<u128> = result.iter().map(|r| r.document.id).collect();
+        assert_eq!(ids, vec![3]);
+    }
+
+    #[test]
+    fn test_filter_applies_to_newest_version() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let sst_manager = Arc::new(SSTManager::new(dir.path().to_path_buf()));
+        let index_manager = Arc::new(IndexManager::default());
+
+        let colored = |id: u128, x: f32, color: &str| {
+            document(id, vec![x]).with_payload(Payload::from([("color".to_string(), color.into())]))
+        };
+        let flushed = flat_memtable(
+            (1..=6)
+                .map(|id| colored(id, id as f32, if id % 2 == 0 { "red" } else { "blue" }))
+                .collect(),
+        );
+        let metadata = sst_manager
+            .write_memtable("test", 1, 0, flushed.as_ref())
+            .unwrap();
+        sst_manager.table(&metadata).unwrap();
+        let cached = sst_manager.block_cache().stats();
+        index_manager.add_sst_metadata(metadata);
+
+        // 2 was red on disk, its newest version is not
+        let frozen = flat_memtable(vec![colored(2, 2.0, "blue")]);
+        let mut active = get_memtable(
+            &IndexConfig::new_with_default_config("hnsw").unwrap(),
+            &DistanceType::L2,
+            DEFAULT_MEMTABLE_SIZE,
+            &TrainedCentroids::default(),
+        );
+        for id in 7..=9 {
+            active.upsert(colored(id, 0.1 * id as f32, "blue"));
+        }
+        active.upsert(colored(10, 10.0, "red"));
+
+        let search_manager =
+            SearchManager::new(index_manager, sst_manager.clone(), DistanceType::L2);
+        let filter = Filter::eq("color", "red");
+        let result = search_manager
+            .search(
+                &[0.0],
+                3,
+                Some(&filter),
+                &[active.as_ref(), frozen.as_ref()],
+            )
+            .unwrap();
+
+        // The closest documents are all blue, the top-k is still full
+        let ids: Vec<u128> = result.iter().map(|r| r.document.id).collect();
+        assert_eq!(ids, vec![4, 6, 10]);
+
+        // The whole file was read, none of its blocks were cached
+        let stats = sst_manager.block_cache().stats();
+        assert!(stats.documents.misses > cached.documents.misses);
+        assert_eq!(stats.entries, cached.entries);
+    }
+
+    #[test]
+    fn test_prefiltered_search_matches_post_filtering() {
+        let indexed = IndexConfig::new_with_default_config("flat")
+            .unwrap()
+            .with_payload_index("color", crate::PayloadIndexType::Keyword);
+        let colored = |id: u128, color: &str| {
+            document(id, vec![id as f32])
+                .with_payload(Payload::from([("color".to_string(), color.into())]))
+        };
+
+        let search = |config: &IndexConfig| {
+            let dir = tempdir().expect("Failed to create temp dir");
+            let sst_manager = Arc::new(SSTManager::new(dir.path().to_path_buf()));
+            let index_manager = Arc::new(IndexManager::default());
+
+            // A few red documents among many blue ones, selective enough to pre-filter
+            let mut flushed = get_memtable(
+                config,
+                &DistanceType::L2,
+                DEFAULT_MEMTABLE_SIZE,
+                &TrainedCentroids::default(),
+            );
+            for id in 1..=100 {
+                flushed.upsert(colored(id, if id % 25 == 0 { "red" } else { "blue" }));
+            }
+            let metadata = sst_manager
+                .write_memtable("test", 1, 0, flushed.as_ref())
+                .unwrap();
+            let filter = Filter::eq("color", "red");
+            let prefiltered =
+                prefilter(metadata.payload_index.as_deref(), Some(&filter), 100).is_some();
+            index_manager.add_sst_metadata(metadata);
+
+            // 25 turns blue and 50 is deleted in memory, 3 turns red
+            let mut active = get_memtable(
+                config,
+                &DistanceType::L2,
+                DEFAULT_MEMTABLE_SIZE,
+                &TrainedCentroids::default(),
+            );
+            active.upsert(colored(25, "blue"));
+            active.delete(&50);
+            active.upsert(colored(3, "red"));
+            active.upsert(colored(3, "red"));
+
+            let search_manager = SearchManager::new(index_manager, sst_manager, DistanceType::L2);
+            let result = search_manager
+                .search(&[0.0], 10, Some(&filter), &[active.as_ref()])
+                .unwrap();
+            let ids: Vec<u128> = result.iter().map(|r| r.document.id).collect();
+            (prefiltered, ids)
+        };
+
+        let (prefiltered, ids) = search(&indexed);
+        assert!(prefiltered);
+        assert_eq!(ids, vec![3, 75, 100]);
+        assert_eq!(
+            search(&IndexConfig::new_with_default_config("flat").unwrap()),
+            (false, ids)
+        );
+    }
+
+    #[test]
+    fn test_top_k_keeps_lowest_scores() {
+        let mut top_k = TopK::new(3);
+        for (score, item) in [(5.0, 'a'), (1.0, 'b'), (4.0, 'c'), (0.5, 'd'), (3.0, 'e')] {
+            top_k.push(score, item);
+        }
+
+        let result: Vec<char> = top_k.into_sorted_vec().into_iter().map(|e| e.1).collect();
+        assert_eq!(result, vec!['d', 'b', 'e']);
     }
 
-    pub fn fetch(&self, id: &u128) -> Option<Arc<Document>> {
-        // 1. Search index
-        // 2. If not found, search SSTs
-        panic!("Not implemented");
+    #[test]
+    fn test_top_k_zero() {
+        let mut top_k = TopK::new(0);
+        top_k.push(1.0, 1);
+        assert!(top_k.into_sorted_vec().is_empty());
     }
 }
@@ -1,30 +1,206 @@
 use crate::SSTMetadata;
-use crate::document::Document;
+use crate::ann::{AnnConfig, AnnIndex};
+use crate::block_cache::BlockCache;
+use crate::bloom::BloomFilter;
+use crate::checksum::crc32c;
+use crate::constant::{
+    BLOOM_BITS_PER_KEY, DEFAULT_BLOCK_CACHE_SIZE, SST_BLOCK_SIZE, TABLE_CACHE_CAPACITY,
+};
+use crate::document::{Document, Record};
 use crate::memtable::MemTable;
+use crate::payload_index::PayloadIndex;
+use crate::table::{Table, TableCache, TableRecords};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
 use std::fs::{self, File};
-use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 const SST_MAGIC: u32 = 0x53535401; // "SST\x01"
+const SST_VERSION: u32 = 4; // written by this build, v1 to v3 files are still read
 
-const FOOTER_SIZE: usize = 64;
+const FOOTER_TRAILER_SIZE: usize = 8; // magic and version, the last bytes of every version
+const FOOTER_V1_SIZE: usize = 64;
+const FOOTER_V2_SIZE: usize = 84;
+const FOOTER_V3_SIZE: usize = 120;
+const FOOTER_V4_SIZE: usize = 136;
 
+pub(crate) const BLOCK_ENTRY_HEADER_SIZE: usize = 21; // id, tombstone flag, length
+pub(crate) const CHECKSUM_SIZE: usize = 4;
+const VECTOR_ALIGNMENT: u64 = 64; // the vector section starts on a cache line
+
+const COMPACTION_LOG_PREFIX: &str = "COMPACT-";
+const COMPACTION_LOG_EXTENSION: &str = "log";
+
+const PAYLOAD_INDEX_EXTENSION: &str = "pidx";
+
+/*
+v4 file layout:
+- data blocks: records in id order, a block is closed once it reaches SST_BLOCK_SIZE.
+  Each record is its id (u128), a tombstone flag (u8), the length of its body (u32, 0
+  for a tombstone) and the body: bincode encoded content, payload and external id.
+- vector section: one row of `dimension` f32 per record in id order, zeros for a
+  tombstone. It starts on a 64 byte boundary and holds nothing else, so a search
+  streams raw vectors without decoding a single document.
+- id section: the id (u128) of every record in id order, then a tombstone flag (u8)
+  per record
+- block index: bincode encoded Vec<BlockHandle>
+- bloom filter: bincode encoded BloomFilter over every id of the file, tombstones included
+- ANN index: bincode encoded AnnIndex over the live rows, see ann.rs. Absent (size 0)
+  for flat collections.
+- footer: see `Footer`
+
+Integers and floats are little-endian, except in the footer. Every data block and
+section is followed by the CRC32C of its bytes, handles and footer offsets/sizes do not
+count it. The vector section is the exception: the rows of each block are checked by
+the CRC32C kept in its handle, so reading one block's vectors checks only those.
+
+v3 files are v4 files without an ANN index.
+v2 files have whole bincode documents in their blocks and neither vectors nor ids.
+v1 files are a single data section of bincode documents, a bincode Vec<IndexEntry>
+and the 64 byte footer, in the layouts of `v1`.
+*/
+
+/// Index entry of a v1 file.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct IndexEntry {
     pub id: u128,
     pub offset: u64,
     pub length: u32,
+    pub tombstone: bool, // tombstones have no data, offset and length are 0
+}
+
+/// Location and id range of a data block, and of its rows in the vector section.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct BlockHandle {
+    pub first_id: u128,
+    pub last_id: u128,
+    pub offset: u64,
+    pub size: u64,
+    pub first_row: u64,
+    pub rows: u64,
+    pub vectors_crc: u32,
+}
+
+/// v1 files as the first build wrote them: index entries without a tombstone flag and
+/// documents without payload or external id.
+pub(crate) mod v1 {
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    pub struct IndexEntry {
+        pub id: u128,
+        pub offset: u64,
+        pub length: u32,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Document {
+        pub id: u128,
+        pub vector: Vec<f32>,
+        pub content: String,
+    }
+
+    impl From<IndexEntry> for super::IndexEntry {
+        fn from(entry: IndexEntry) -> Self {
+            super::IndexEntry {
+                id: entry.id,
+                offset: entry.offset,
+                length: entry.length,
+                tombstone: false,
+            }
+        }
+    }
+
+    impl From<Document> for crate::document::Document {
+        fn from(doc: Document) -> Self {
+            crate::document::Document {
+                id: doc.id,
+                vector: doc.vector,
+                content: doc.content,
+                payload: Default::default(),
+                external_id: None,
+            }
+        }
+    }
+}
+
+/// Block handles and documents as v2 wrote them, before the vector section existed.
+pub(crate) mod v2 {
+    use crate::document::ExternalId;
+    use crate::payload::Payload;
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    pub struct BlockHandle {
+        pub first_id: u128,
+        pub last_id: u128,
+        pub offset: u64,
+        pub size: u64,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Document {
+        pub id: u128,
+        pub vector: Vec<f32>,
+        pub content: String,
+        pub payload: Payload,
+        pub external_id: Option<ExternalId>,
+    }
+
+    impl From<BlockHandle> for super::BlockHandle {
+        fn from(handle: BlockHandle) -> Self {
+            super::BlockHandle {
+                first_id: handle.first_id,
+                last_id: handle.last_id,
+                offset: handle.offset,
+                size: handle.size,
+                first_row: 0,
+                rows: 0,
+                vectors_crc: 0,
+            }
+        }
+    }
+
+    impl From<Document> for crate::document::Document {
+        fn from(doc: Document) -> Self {
+            crate::document::Document {
+                id: doc.id,
+                vector: doc.vector,
+                content: doc.content,
+                payload: doc.payload,
+                external_id: doc.external_id,
+            }
+        }
+    }
 }
 
-/// Footer layout (64 bytes, fixed size, manual serialization):
-/// - min_id:               16 bytes (u128 big-endian)
-/// - max_id:               16 bytes (u128 big-endian)
-/// - index_section_offset: 8 bytes  (u64 big-endian)
-/// - index_section_size:   8 bytes  (u64 big-endian)
-/// - entry_count:          8 bytes  (u64 big-endian)
-/// - magic_number:         4 bytes  (u32 big-endian)
-/// - version:              4 bytes  (u32 big-endian)
+/// Footer layout (fixed size per version, manual serialization, big-endian).
+///
+/// v1, 64 bytes:
+/// - min_id:               16 bytes (u128)
+/// - max_id:               16 bytes (u128)
+/// - index_section_offset: 8 bytes  (u64)
+/// - index_section_size:   8 bytes  (u64)
+/// - entry_count:          8 bytes  (u64)
+/// - magic_number:         4 bytes  (u32)
+/// - version:              4 bytes  (u32)
+///
+/// v2, 84 bytes: the v1 fields up to entry_count (the index section is the block index),
+/// bloom_offset and bloom_size (u64 each), the CRC32C of the bytes before it (u32),
+/// magic_number and version.
+///
+/// v3, 120 bytes: the v2 fields up to bloom_size, then ids_offset, ids_size,
+/// vectors_offset, vectors_size (u64 each) and dimension (u32), followed by the CRC32C,
+/// magic_number and version.
+///
+/// v4, 136 bytes: the v3 fields up to dimension, then ann_offset and ann_size (u64 each),
+/// followed by the CRC32C, magic_number and version.
+///
+/// Magic and version always close the file, so a reader knows which footer to expect.
 #[derive(Debug, Clone)]
 pub struct Footer {
     pub min_id: u128,
@@ -32,17 +208,29 @@ pub struct Footer {
     pub index_section_offset: u64,
     pub index_section_size: u64,
     pub entry_count: u64,
+    pub bloom_offset: u64, // from v2 on, 0 before
+    pub bloom_size: u64,
+    pub ids_offset: u64, // from v3 on, 0 before
+    pub ids_size: u64,
+    pub vectors_offset: u64,
+    pub vectors_size: u64,
+    pub dimension: u32,
+    pub ann_offset: u64, // from v4 on, 0 before or without an ANN index
+    pub ann_size: u64,
     pub magic: u32,
     pub version: u32,
 }
 
 impl Footer {
+    /// A footer of the current version, its id, vector and ANN sections still empty.
     pub fn new(
         min_id: u128,
         max_id: u128,
         index_section_offset: u64,
         index_section_size: u64,
         entry_count: u64,
+        bloom_offset: u64,
+        bloom_size: u64,
     ) -> Self {
         Self {
             min_id,
@@ -50,33 +238,118 @@ impl Footer {
             index_section_offset,
             index_section_size,
             entry_count,
+            bloom_offset,
+            bloom_size,
+            ids_offset: 0,
+            ids_size: 0,
+            vectors_offset: 0,
+            vectors_size: 0,
+            dimension: 0,
+            ann_offset: 0,
+            ann_size: 0,
             magic: SST_MAGIC,
-            version: 1,
+            version: SST_VERSION,
         }
     }
 
-    pub fn to_bytes(&self) -> [u8; FOOTER_SIZE] {
-        let mut buf = [0u8; FOOTER_SIZE];
-        buf[0..16].copy_from_slice(&self.min_id.to_be_bytes());
-        buf[16..32].copy_from_slice(&self.max_id.to_be_bytes());
-        buf[32..40].copy_from_slice(&self.index_section_offset.to_be_bytes());
-        buf[40..48].copy_from_slice(&self.index_section_size.to_be_bytes());
-        buf[48..56].copy_from_slice(&self.entry_count.to_be_bytes());
-        buf[56..60].copy_from_slice(&self.magic.to_be_bytes());
-        buf[60..64].copy_from_slice(&self.version.to_be_bytes());
+    /// Size of the footer of a given format version, `None` for unknown versions.
+    pub fn size(version: u32) -> Option<usize> {
+        match version {
+            1 => Some(FOOTER_V1_SIZE),
+            2 => Some(FOOTER_V2_SIZE),
+            3 => Some(FOOTER_V3_SIZE),
+            4 => Some(FOOTER_V4_SIZE),
+            _ => None,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(FOOTER_V4_SIZE);
+        buf.extend_from_slice(&self.min_id.to_be_bytes());
+        buf.extend_from_slice(&self.max_id.to_be_bytes());
+        buf.extend_from_slice(&self.index_section_offset.to_be_bytes());
+        buf.extend_from_slice(&self.index_section_size.to_be_bytes());
+        buf.extend_from_slice(&self.entry_count.to_be_bytes());
+        if self.version >= 2 {
+            buf.extend_from_slice(&self.bloom_offset.to_be_bytes());
+            buf.extend_from_slice(&self.bloom_size.to_be_bytes());
+        }
+        if self.version >= 3 {
+            buf.extend_from_slice(&self.ids_offset.to_be_bytes());
+            buf.extend_from_slice(&self.ids_size.to_be_bytes());
+            buf.extend_from_slice(&self.vectors_offset.to_be_bytes());
+            buf.extend_from_slice(&self.vectors_size.to_be_bytes());
+            buf.extend_from_slice(&self.dimension.to_be_bytes());
+        }
+        if self.version >= 4 {
+            buf.extend_from_slice(&self.ann_offset.to_be_bytes());
+            buf.extend_from_slice(&self.ann_size.to_be_bytes());
+        }
+        if self.version >= 2 {
+            let crc = crc32c(&buf);
+            buf.extend_from_slice(&crc.to_be_bytes());
+        }
+        buf.extend_from_slice(&self.magic.to_be_bytes());
+        buf.extend_from_slice(&self.version.to_be_bytes());
         buf
     }
 
-    pub fn from_bytes(buf: &[u8; FOOTER_SIZE]) -> Self {
-        Self {
+    /// Parses a whole footer, rejecting foreign files, unknown versions and, from v2 on,
+    /// damaged footers.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, SSTError> {
+        let trailer = buf
+            .len()
+            .checked_sub(FOOTER_TRAILER_SIZE)
+            .ok_or_else(|| SSTError::Corrupted("Footer too short".to_string()))?;
+        let magic = u32::from_be_bytes(buf[trailer..trailer + 4].try_into().unwrap());
+        let version = u32::from_be_bytes(buf[trailer + 4..].try_into().unwrap());
+        if magic != SST_MAGIC {
+            return Err(SSTError::InvalidMagic);
+        }
+        let size = Footer::size(version).ok_or(SSTError::UnsupportedVersion(version))?;
+        if buf.len() != size {
+            return Err(SSTError::Corrupted(format!(
+                "Footer of version {} is {} bytes, expected {}",
+                version,
+                buf.len(),
+                size
+            )));
+        }
+        if version >= 2 {
+            let crc_at = trailer - CHECKSUM_SIZE;
+            let crc = u32::from_be_bytes(buf[crc_at..trailer].try_into().unwrap());
+            if crc32c(&buf[..crc_at]) != crc {
+                return Err(SSTError::Corrupted("Footer checksum mismatch".to_string()));
+            }
+        }
+
+        let u64_at = |at: usize| u64::from_be_bytes(buf[at..at + 8].try_into().unwrap());
+        let mut footer = Self {
             min_id: u128::from_be_bytes(buf[0..16].try_into().unwrap()),
             max_id: u128::from_be_bytes(buf[16..32].try_into().unwrap()),
-            index_section_offset: u64::from_be_bytes(buf[32..40].try_into().unwrap()),
-            index_section_size: u64::from_be_bytes(buf[40..48].try_into().unwrap()),
-            entry_count: u64::from_be_bytes(buf[48..56].try_into().unwrap()),
-            magic: u32::from_be_bytes(buf[56..60].try_into().unwrap()),
-            version: u32::from_be_bytes(buf[60..64].try_into().unwrap()),
+            index_section_offset: u64_at(32),
+            index_section_size: u64_at(40),
+            entry_count: u64_at(48),
+            magic,
+            version,
+            ..Footer::new(0, 0, 0, 0, 0, 0, 0)
+        };
+        if version >= 2 {
+            footer.bloom_offset = u64_at(56);
+            footer.bloom_size = u64_at(64);
         }
+        if version >= 3 {
+            footer.ids_offset = u64_at(72);
+            footer.ids_size = u64_at(80);
+            footer.vectors_offset = u64_at(88);
+            footer.vectors_size = u64_at(96);
+            footer.dimension = u32::from_be_bytes(buf[104..108].try_into().unwrap());
+        }
+        if version >= 4 {
+            footer.ann_offset = u64_at(108);
+            footer.ann_size = u64_at(116);
+        }
+        Ok(footer)
     }
 }
 
@@ -84,24 +357,353 @@ impl Footer {
 pub enum SSTError {
     Io(std::io::Error),
     InvalidMagic,
+    UnsupportedVersion(u32),
+    Corrupted(String), // a checksum or a bound does not match
     NotFound,
     DeserializeError(String),
 }
 
+impl fmt::Display for SSTError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SSTError::Io(e) => write!(f, "IO error: {}", e),
+            SSTError::InvalidMagic => write!(f, "Invalid magic number"),
+            SSTError::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported SST version {}, this build reads versions 1 to {}",
+                version, SST_VERSION
+            ),
+            SSTError::Corrupted(msg) => write!(f, "Corrupted SST: {}", msg),
+            SSTError::NotFound => write!(f, "Not found"),
+            SSTError::DeserializeError(msg) => write!(f, "Deserialize error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for SSTError {}
+
 impl From<std::io::Error> for SSTError {
     fn from(e: std::io::Error) -> Self {
         SSTError::Io(e)
     }
 }
 
+impl From<bincode::Error> for SSTError {
+    fn from(e: bincode::Error) -> Self {
+        SSTError::DeserializeError(e.to_string())
+    }
+}
+
+fn read_footer_from<R: Read + Seek>(reader: &mut R) -> Result<Footer, SSTError> {
+    reader.seek(SeekFrom::End(-(FOOTER_TRAILER_SIZE as i64)))?;
+    let mut trailer = [0u8; FOOTER_TRAILER_SIZE];
+    reader.read_exact(&mut trailer)?;
+    if u32::from_be_bytes(trailer[0..4].try_into().unwrap()) != SST_MAGIC {
+        return Err(SSTError::InvalidMagic);
+    }
+    let version = u32::from_be_bytes(trailer[4..8].try_into().unwrap());
+    let size = Footer::size(version).ok_or(SSTError::UnsupportedVersion(version))?;
+
+    reader.seek(SeekFrom::End(-(size as i64)))?;
+    let mut footer_buf = vec![0u8; size];
+    reader.read_exact(&mut footer_buf)?;
+    Footer::from_bytes(&footer_buf)
+}
+
+/// Footer of a whole file held in memory.
+pub(crate) fn parse_footer(file: &[u8]) -> Result<Footer, SSTError> {
+    let trailer = file
+        .len()
+        .checked_sub(FOOTER_TRAILER_SIZE)
+        .ok_or_else(|| SSTError::Corrupted("File too short for a footer".to_string()))?;
+    if u32::from_be_bytes(file[trailer..trailer + 4].try_into().unwrap()) != SST_MAGIC {
+        return Err(SSTError::InvalidMagic);
+    }
+    let version = u32::from_be_bytes(file[trailer + 4..].try_into().unwrap());
+    let size = Footer::size(version).ok_or(SSTError::UnsupportedVersion(version))?;
+    let start = file
+        .len()
+        .checked_sub(size)
+        .ok_or_else(|| SSTError::Corrupted("File too short for a footer".to_string()))?;
+    Footer::from_bytes(&file[start..])
+}
+
+pub fn read_footer(path: &Path) -> Result<Footer, SSTError> {
+    read_footer_from(&mut File::open(path)?)
+}
+
+/// Inputs and outputs of a committed compaction, relative to the collection directory.
+#[derive(Serialize, Deserialize)]
+struct CompactionLog {
+    inputs: Vec<PathBuf>,
+    outputs: Vec<PathBuf>,
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    PathBuf::from(temp)
+}
+
+/// Sidecar of an SST holding the payload indexes of its live documents:
+/// L{layer}/{name}.sst.pidx
+fn payload_index_path(path: &Path) -> PathBuf {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(".");
+    sidecar.push(PAYLOAD_INDEX_EXTENSION);
+    PathBuf::from(sidecar)
+}
+
+/// A missing or unreadable sidecar only costs speed, searches scan that SST instead.
+fn read_payload_index(path: &Path) -> Option<Arc<PayloadIndex>> {
+    let bytes = fs::read(payload_index_path(path)).ok()?;
+    bincode::deserialize(&bytes).ok().map(Arc::new)
+}
+
+fn relative_paths(base: &Path, paths: &[PathBuf]) -> Vec<PathBuf> {
+    paths
+        .iter()
+        .map(|path| path.strip_prefix(base).unwrap_or(path).to_path_buf())
+        .collect()
+}
+
+/// Makes the entries created, renamed or deleted in `dir` durable.
+fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+fn remove_if_exists(path: &Path) -> std::io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Writes `bytes` followed by their checksum, returns how many bytes were covered.
+fn write_checked<W: Write>(writer: &mut W, bytes: &[u8]) -> std::io::Result<u64> {
+    writer.write_all(bytes)?;
+    writer.write_all(&crc32c(bytes).to_le_bytes())?;
+    Ok(bytes.len() as u64)
+}
+
+/// Writes `records` (sorted by id) as an SST at `fpath` and returns its footer.
+fn write_file(
+    fpath: &Path,
+    records: impl Iterator<Item = Record>,
+    capacity: usize,
+    ann: Option<&AnnConfig>,
+) -> std::io::Result<Footer> {
+    let file = File::create(fpath)?;
+    let mut writer = BufWriter::new(file);
+
+    let mut blocks: Vec<BlockHandle> = Vec::new();
+    let mut block = Vec::with_capacity(SST_BLOCK_SIZE * 2);
+    let mut block_first_id = 0;
+    let mut block_first_row = 0;
+    let mut bloom = BloomFilter::new(capacity, BLOOM_BITS_PER_KEY);
+    let mut ids: Vec<u128> = Vec::with_capacity(capacity);
+    let mut tombstones: Vec<bool> = Vec::with_capacity(capacity);
+    let mut live_vectors: Vec<f32> = Vec::new();
+    let mut live_docs: Vec<(u32, Arc<Document>)> = Vec::new(); // only kept to build the ANN index
+    let mut dimension = None;
+    let mut offset = 0;
+    let mut min_id = u128::MAX;
+    let mut max_id = u128::MIN;
+
+    // data blocks
+    for record in records {
+        let id = record.id();
+        min_id = min_id.min(id);
+        max_id = max_id.max(id);
+        bloom.insert(id);
+        if block.is_empty() {
+            block_first_id = id;
+            block_first_row = ids.len() as u64;
+        }
+        ids.push(id);
+
+        block.extend_from_slice(&id.to_le_bytes());
+        match record {
+            Record::Live(doc) => {
+                let dimension = *dimension.get_or_insert(doc.vector.len());
+                if doc.vector.len() != dimension {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!(
+                            "Document {} has {} dimensions, expected {}",
+                            id,
+                            doc.vector.len(),
+                            dimension
+                        ),
+                    ));
+                }
+                live_vectors.extend_from_slice(&doc.vector);
+                tombstones.push(false);
+                if ann.is_some() {
+                    live_docs.push((ids.len() as u32 - 1, doc.clone()));
+                }
+
+                let body = bincode::serialize(&(&doc.content, &doc.payload, &doc.external_id))
+                    .expect("Failed to serialize document");
+                block.push(0);
+                block.extend_from_slice(&(body.len() as u32).to_le_bytes());
+                block.extend(body);
+            }
+            // Tombstones are persisted so they keep hiding the versions in older SSTs
+            Record::Tombstone(_) => {
+                tombstones.push(true);
+                block.push(1);
+                block.extend_from_slice(&0u32.to_le_bytes());
+            }
+        }
+
+        if block.len() >= SST_BLOCK_SIZE {
+            blocks.push(write_block(
+                &mut writer,
+                &block,
+                (block_first_id, id),
+                (block_first_row, ids.len() as u64),
+                &mut offset,
+            )?);
+            block.clear();
+        }
+    }
+    if !block.is_empty() {
+        blocks.push(write_block(
+            &mut writer,
+            &block,
+            (block_first_id, max_id),
+            (block_first_row, ids.len() as u64),
+            &mut offset,
+        )?);
+    }
+
+    // vector section, tombstones get a row of zeros so that rows follow the ids
+    let dimension = dimension.unwrap_or(0);
+    let mut vectors = Vec::with_capacity(ids.len() * dimension * 4);
+    let mut live_rows = live_vectors.chunks_exact(dimension.max(1));
+    for tombstone in tombstones.iter() {
+        if *tombstone {
+            vectors.resize(vectors.len() + dimension * 4, 0);
+        } else if let Some(row) = live_rows.next() {
+            for value in row {
+                vectors.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+    }
+    let row_size = dimension * 4;
+    for handle in blocks.iter_mut() {
+        let start = handle.first_row as usize * row_size;
+        handle.vectors_crc = crc32c(&vectors[start..start + handle.rows as usize * row_size]);
+    }
+    let padding = (VECTOR_ALIGNMENT - offset % VECTOR_ALIGNMENT) % VECTOR_ALIGNMENT;
+    writer.write_all(&vec![0u8; padding as usize])?;
+    let vectors_offset = offset + padding;
+    writer.write_all(&vectors)?;
+    let vectors_size = vectors.len() as u64;
+
+    // id section
+    let ids_offset = vectors_offset + vectors_size;
+    let mut id_section = Vec::with_capacity(ids.len() * 17);
+    for id in ids.iter() {
+        id_section.extend_from_slice(&id.to_le_bytes());
+    }
+    id_section.extend(tombstones.iter().map(|tombstone| *tombstone as u8));
+    let ids_size = write_checked(&mut writer, &id_section)?;
+
+    // block index and bloom filter
+    let index_section_offset = ids_offset + ids_size + CHECKSUM_SIZE as u64;
+    let index_bytes = bincode::serialize(&blocks).expect("Failed to serialize block index");
+    let index_section_size = write_checked(&mut writer, &index_bytes)?;
+    let bloom_offset = index_section_offset + index_section_size + CHECKSUM_SIZE as u64;
+    let bloom_bytes = bincode::serialize(&bloom).expect("Failed to serialize bloom filter");
+    let bloom_size = write_checked(&mut writer, &bloom_bytes)?;
+
+    // ANN index
+    let ann_offset = bloom_offset + bloom_size + CHECKSUM_SIZE as u64;
+    let ann_index = ann.and_then(|config| AnnIndex::build(config, ids.len(), &live_docs));
+    let ann_size = match ann_index {
+        Some(index) => {
+            let ann_bytes = bincode::serialize(&index).expect("Failed to serialize ANN index");
+            write_checked(&mut writer, &ann_bytes)?
+        }
+        None => 0,
+    };
+
+    // footer section
+    let footer = Footer {
+        ids_offset,
+        ids_size,
+        vectors_offset,
+        vectors_size,
+        dimension: dimension as u32,
+        ann_offset: if ann_size > 0 { ann_offset } else { 0 },
+        ann_size,
+        ..Footer::new(
+            min_id,
+            max_id,
+            index_section_offset,
+            index_section_size,
+            ids.len() as u64,
+            bloom_offset,
+            bloom_size,
+        )
+    };
+    writer.write_all(&footer.to_bytes())?;
+    writer.flush()?;
+    // The WAL segments behind this file are only replayed up to the newest SST,
+    // so the SST has to be on disk before anyone relies on it
+    writer.get_ref().sync_all()?;
+
+    Ok(footer)
+}
+
+/// Writes a data block at `offset` and returns its handle, the vector checksum is filled
+/// in once the vector section is known.
+fn write_block<W: Write>(
+    writer: &mut W,
+    block: &[u8],
+    (first_id, last_id): (u128, u128),
+    (first_row, end_row): (u64, u64),
+    offset: &mut u64,
+) -> std::io::Result<BlockHandle> {
+    let size = write_checked(writer, block)?;
+    let handle = BlockHandle {
+        first_id,
+        last_id,
+        offset: *offset,
+        size,
+        first_row,
+        rows: end_row - first_row,
+        vectors_crc: 0,
+    };
+    *offset += size + CHECKSUM_SIZE as u64;
+    Ok(handle)
+}
+
 pub struct SSTManager {
     pub path: PathBuf,
+    next_file_no: AtomicU64, // names compaction outputs and logs, bumped past the files found on disk
+    tables: TableCache,
 }
 
 impl SSTManager {
     pub fn new(path: PathBuf) -> Self {
+        Self::with_block_cache(path, Arc::new(BlockCache::new(DEFAULT_BLOCK_CACHE_SIZE)))
+    }
+
+    /// An SSTManager whose reads go through `block_cache`.
+    pub fn with_block_cache(path: PathBuf, block_cache: Arc<BlockCache>) -> Self {
         let path = path.join("data");
-        Self { path }
+        Self {
+            path,
+            next_file_no: AtomicU64::new(0),
+            tables: TableCache::new(TABLE_CACHE_CAPACITY, block_cache),
+        }
+    }
+
+    pub fn block_cache(&self) -> &BlockCache {
+        self.tables.block_cache()
     }
 
     pub fn write_memtable(
@@ -112,144 +714,491 @@ impl SSTManager {
         memtable: &dyn MemTable,
     ) -> std::io::Result<SSTMetadata> {
         // fp: root/{collection}/L{layer}/{seq_no}.sst
-        let dir_path = self.path.join(collection_name).join(format!("L{}", layer));
-        fs::create_dir_all(&dir_path)?;
-
-        let fname = format!("{:06}.sst", seq_no);
-        let fpath = dir_path.join(&fname);
-
-        let file = File::create(&fpath)?;
-        let mut writer = BufWriter::new(file);
+        let dir_path = self.create_layer_dir(collection_name, layer)?;
 
-        let mut data_section = Vec::new();
-        let mut index_entries: Vec<IndexEntry> = Vec::with_capacity(memtable.size());
-        let mut min_id = u128::MAX;
-        let mut max_id = u128::MIN;
-
-        // data section
-        for doc in memtable.sorted_iter() {
-            if doc.id < min_id {
-                min_id = doc.id;
-            }
-            if doc.id > max_id {
-                max_id = doc.id;
-            }
+        // Written under a .tmp name and renamed once synced, the WAL segments it covers
+        // are deleted as soon as this returns. A .tmp left by a crash is dropped on open.
+        let fpath = dir_path.join(format!("{:06}.sst", seq_no));
+        self.tables.evict(&fpath);
+        let footer = write_file(
+            &temp_path(&fpath),
+            memtable.sorted_iter(),
+            memtable.size(),
+            memtable.ann_config(),
+        )
+        .and_then(|footer| {
+            fs::rename(temp_path(&fpath), &fpath)?;
+            sync_dir(&dir_path)?;
+            Ok(footer)
+        })
+        .inspect_err(|_| {
+            // The flush is retried anyway
+            let _ = fs::remove_file(temp_path(&fpath));
+        })?;
 
-            let current_offset = data_section.len() as u64;
-            let serialized = doc.serialize().expect("Failed to serialize document");
-            let length = serialized.len() as u32;
+        let payload_index = memtable.payload_index();
+        let payload_index = if payload_index.is_empty() {
+            None
+        } else {
+            self.write_payload_index(&fpath, payload_index)?;
+            Some(Arc::new(payload_index.clone()))
+        };
 
-            index_entries.push(IndexEntry {
-                id: doc.id,
-                offset: current_offset,
-                length,
-            });
+        Ok(SSTMetadata {
+            collection_name: collection_name.to_string(),
+            seq_no,
+            layer,
+            min_id: footer.min_id,
+            max_id: footer.max_id,
+            path: fpath,
+            entry_count: footer.entry_count,
+            payload_index,
+        })
+    }
 
-            data_section.extend(serialized);
+    /// Writes the sidecar of the SST at `path`, only once the SST itself is complete so
+    /// that a sidecar never outlives or predates its file.
+    pub fn write_payload_index(&self, path: &Path, index: &PayloadIndex) -> std::io::Result<()> {
+        let sidecar = payload_index_path(path);
+        let bytes = bincode::serialize(index).map_err(std::io::Error::other)?;
+        {
+            let mut file = File::create(temp_path(&sidecar))?;
+            file.write_all(&bytes)?;
+            file.sync_all()?;
         }
+        fs::rename(temp_path(&sidecar), &sidecar)?;
+        sync_dir(sidecar.parent().unwrap_or(Path::new(".")))
+    }
 
-        writer.write_all(&data_section)?;
-        let data_section_size = data_section.len() as u64;
-
-        // index section
-        let index_section_offset = data_section_size;
-        let index_bytes =
-            bincode::serialize(&index_entries).expect("Failed to serialize index entries");
-        writer.write_all(&index_bytes)?;
-        let index_section_size = index_bytes.len() as u64;
+    /// Writes one output of a compaction, `records` must be sorted by id and number at
+    /// most `capacity`.
+    ///
+    /// Several outputs share the seq_no of their newest input, so the file name also
+    /// carries a unique file number: L{layer}/{seq_no}-{file_no}.sst. The file is written
+    /// under a `.tmp` name and only gets its real name in `commit_compaction`. The file
+    /// carries the ANN index described by `ann`, if any.
+    pub fn write_compaction_output(
+        &self,
+        collection_name: &str,
+        seq_no: u64,
+        layer: u64,
+        records: impl Iterator<Item = Record>,
+        capacity: usize,
+        ann: Option<&AnnConfig>,
+    ) -> std::io::Result<SSTMetadata> {
+        let dir_path = self.create_layer_dir(collection_name, layer)?;
 
-        // footer section
-        let entry_count = index_entries.len() as u64;
-        let footer = Footer::new(
-            min_id,
-            max_id,
-            index_section_offset,
-            index_section_size,
-            entry_count,
-        );
-        writer.write_all(&footer.to_bytes())?;
-        writer.flush()?;
+        let file_no = self.next_file_no.fetch_add(1, Ordering::SeqCst);
+        let fpath = dir_path.join(format!("{:06}-{:06}.sst", seq_no, file_no));
+        let footer = write_file(&temp_path(&fpath), records, capacity, ann)?;
 
         Ok(SSTMetadata {
             collection_name: collection_name.to_string(),
             seq_no,
             layer,
-            min_id,
-            max_id,
+            min_id: footer.min_id,
+            max_id: footer.max_id,
             path: fpath,
-            entry_count,
+            entry_count: footer.entry_count,
+            payload_index: None, // written once the output is committed
         })
     }
 
-    pub fn read(
+    /// Makes a compaction durable: the inputs and outputs are recorded in a log,
+    /// then the outputs are renamed to their real names. From here on the inputs are
+    /// dead, a crash before `finish_compaction` is rolled forward by `load_metadata`.
+    pub fn commit_compaction(
         &self,
         collection_name: &str,
-        seq_no: u64,
-        layer: u64,
-        id: u128,
-    ) -> Result<Document, SSTError> {
-        let dir_path = self.path.join(collection_name).join(format!("L{}", layer));
+        inputs: &[PathBuf],
+        outputs: &[PathBuf],
+    ) -> std::io::Result<PathBuf> {
+        let collection_path = self.path.join(collection_name);
+        let log = CompactionLog {
+            inputs: relative_paths(&collection_path, inputs),
+            outputs: relative_paths(&collection_path, outputs),
+        };
+        let bytes = bincode::serialize(&log).map_err(std::io::Error::other)?;
 
-        let fname = format!("{:06}.sst", seq_no);
-        let file = File::open(dir_path.join(&fname))?;
-        let _file_size = file.metadata()?.len();
-        let mut reader = BufReader::new(file);
+        let file_no = self.next_file_no.fetch_add(1, Ordering::SeqCst);
+        let log_path = collection_path.join(format!(
+            "{}{:06}.{}",
+            COMPACTION_LOG_PREFIX, file_no, COMPACTION_LOG_EXTENSION
+        ));
+        {
+            let mut file = File::create(&log_path)?;
+            file.write_all(&bytes)?;
+            file.sync_all()?;
+        }
+        let renamed = sync_dir(&collection_path).and_then(|_| {
+            for output in outputs {
+                fs::rename(temp_path(output), output)?;
+                self.tables.evict(output);
+            }
+            for dir in outputs.iter().filter_map(|output| output.parent()) {
+                sync_dir(dir)?;
+            }
+            Ok(())
+        });
+
+        if let Err(e) = renamed {
+            // Without the log the outputs are never rolled forward, drop them as well
+            if fs::remove_file(&log_path).is_ok() {
+                for output in outputs {
+                    fs::remove_file(output).ok();
+                }
+                self.abort_compaction(outputs);
+            }
+            return Err(e);
+        }
 
-        // 1. read footer (last 64 bytes)
-        reader.seek(SeekFrom::End(-(FOOTER_SIZE as i64)))?;
-        let mut footer_buf = [0u8; FOOTER_SIZE];
-        reader.read_exact(&mut footer_buf)?;
-        let footer = Footer::from_bytes(&footer_buf);
+        Ok(log_path)
+    }
 
-        if footer.magic != SST_MAGIC {
-            return Err(SSTError::InvalidMagic);
+    /// Deletes the inputs of a committed compaction, then its log.
+    pub fn finish_compaction(&self, log_path: &Path, inputs: &[PathBuf]) -> std::io::Result<()> {
+        for input in inputs {
+            remove_if_exists(&payload_index_path(input))?;
+            remove_if_exists(input)?;
+            self.tables.evict(input);
+        }
+        remove_if_exists(log_path)
+    }
+
+    /// Drops the `.tmp` outputs of a compaction that failed before being committed.
+    pub fn abort_compaction(&self, outputs: &[PathBuf]) {
+        for output in outputs {
+            fs::remove_file(temp_path(output)).ok();
         }
+    }
 
-        if id < footer.min_id || id > footer.max_id {
-            return Err(SSTError::NotFound);
+    /// Rolls forward the compactions committed before a crash and drops the
+    /// outputs of the ones that never got committed.
+    fn recover_compactions(&self, collection_path: &Path) -> std::io::Result<()> {
+        for entry in fs::read_dir(collection_path)? {
+            let path = entry?.path();
+            let Some(file_no) = path
+                .file_name()
+                .and_then(|f| f.to_str())
+                .and_then(|f| f.strip_prefix(COMPACTION_LOG_PREFIX))
+                .and_then(|f| f.strip_suffix(&format!(".{}", COMPACTION_LOG_EXTENSION)))
+                .and_then(|f| f.parse::<u64>().ok())
+            else {
+                continue;
+            };
+            self.next_file_no.fetch_max(file_no + 1, Ordering::SeqCst);
+
+            // A log is fsynced before any output is renamed, so it is never torn
+            let log: CompactionLog = bincode::deserialize(&fs::read(&path)?)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            for output in &log.outputs {
+                let output = collection_path.join(output);
+                if temp_path(&output).exists() {
+                    fs::rename(temp_path(&output), &output)?;
+                    self.tables.evict(&output);
+                }
+            }
+            let inputs: Vec<PathBuf> = log
+                .inputs
+                .iter()
+                .map(|input| collection_path.join(input))
+                .collect();
+            self.finish_compaction(&path, &inputs)?;
         }
+        Ok(())
+    }
+
+    fn layer_path(&self, collection_name: &str, layer: u64) -> PathBuf {
+        self.path.join(collection_name).join(format!("L{}", layer))
+    }
 
-        // 2. read index section
-        reader.seek(SeekFrom::Start(footer.index_section_offset))?;
-        let mut index_bytes = vec![0u8; footer.index_section_size as usize];
-        reader.read_exact(&mut index_bytes)?;
+    /// The directory of a layer, created if needed. The collection and data directories
+    /// are synced as well, so that a new layer directory does not vanish with its files.
+    fn create_layer_dir(&self, collection_name: &str, layer: u64) -> std::io::Result<PathBuf> {
+        let dir_path = self.layer_path(collection_name, layer);
+        fs::create_dir_all(&dir_path)?;
+        sync_dir(&self.path.join(collection_name))?;
+        sync_dir(&self.path)?;
+        Ok(dir_path)
+    }
 
-        let index_entries: Vec<IndexEntry> = bincode::deserialize(&index_bytes)
-            .map_err(|e| SSTError::DeserializeError(e.to_string()))?;
+    /// Metadata of every SST of a collection, ordered by layer then seq_no. Files are
+    /// only renamed to `.sst` once complete, a `.tmp` left by a crash is removed and an
+    /// `.sst` without a valid footer is an error.
+    pub fn load_metadata(&self, collection_name: &str) -> std::io::Result<Vec<SSTMetadata>> {
+        let collection_path = self.path.join(collection_name);
+        if !collection_path.exists() {
+            return Ok(Vec::new());
+        }
+        self.recover_compactions(&collection_path)?;
 
-        // 3. binary search for id (index is sorted by id)
-        let entry = index_entries
-            .binary_search_by_key(&id, |e| e.id)
-            .ok()
-            .map(|idx| &index_entries[idx]);
+        let mut metadata = Vec::new();
+        for layer_dir in fs::read_dir(&collection_path)? {
+            let layer_dir = layer_dir?.path();
+            let Some(layer) = layer_dir
+                .file_name()
+                .and_then(|f| f.to_str())
+                .and_then(|f| f.strip_prefix('L'))
+                .and_then(|f| f.parse::<u64>().ok())
+            else {
+                continue;
+            };
 
-        match entry {
-            Some(index_entry) => {
-                // 4. seek to data offset and read document
-                reader.seek(SeekFrom::Start(index_entry.offset))?;
-                let mut doc_bytes = vec![0u8; index_entry.length as usize];
-                reader.read_exact(&mut doc_bytes)?;
+            for entry in fs::read_dir(&layer_dir)? {
+                let path = entry?.path();
+                let Some(stem) = path
+                    .file_name()
+                    .and_then(|f| f.to_str())
+                    .and_then(|f| f.strip_suffix(".sst"))
+                else {
+                    // Includes the .tmp outputs of a compaction that was never committed
+                    if path.extension().is_some_and(|e| e == "tmp") {
+                        fs::remove_file(&path)?;
+                    }
+                    // Sidecar whose SST was deleted before it
+                    if path
+                        .extension()
+                        .is_some_and(|e| e == PAYLOAD_INDEX_EXTENSION)
+                        && !path.with_extension("").exists()
+                    {
+                        fs::remove_file(&path)?;
+                    }
+                    continue;
+                };
 
-                // 5. deserialize document
-                let doc: Document = bincode::deserialize(&doc_bytes)
-                    .map_err(|e| SSTError::DeserializeError(e.to_string()))?;
+                // {seq_no}.sst for a flush, {seq_no}-{file_no}.sst for a compaction output
+                let (seq_no, file_no) = match stem.split_once('-') {
+                    Some((seq_no, file_no)) => (seq_no, file_no.parse::<u64>().ok()),
+                    None => (stem, None),
+                };
+                let Ok(seq_no) = seq_no.parse::<u64>() else {
+                    continue;
+                };
+                if let Some(file_no) = file_no {
+                    self.next_file_no.fetch_max(file_no + 1, Ordering::SeqCst);
+                }
 
-                Ok(doc)
+                match read_footer(&path) {
+                    Ok(footer) => metadata.push(SSTMetadata {
+                        collection_name: collection_name.to_string(),
+                        seq_no,
+                        layer,
+                        min_id: footer.min_id,
+                        max_id: footer.max_id,
+                        payload_index: read_payload_index(&path),
+                        path,
+                        entry_count: footer.entry_count,
+                    }),
+                    // Damaged, or written by a newer build. Either way skipping it would
+                    // silently drop its records, their WAL segments are already gone.
+                    Err(e) => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("{}: {}", path.display(), e),
+                        ));
+                    }
+                }
             }
-            None => Err(SSTError::NotFound),
         }
+
+        metadata.sort_by_key(|m| (m.layer, m.seq_no));
+        Ok(metadata)
+    }
+
+    /// Highest seq_no among the complete SSTs of a collection. An SST with seq_no N
+    /// holds everything written to WAL segments below N.
+    pub fn last_flushed_seq_no(&self, collection_name: &str) -> std::io::Result<Option<u64>> {
+        Ok(self
+            .load_metadata(collection_name)?
+            .iter()
+            .map(|m| m.seq_no)
+            .max())
+    }
+
+    pub fn read(
+        &self,
+        collection_name: &str,
+        seq_no: u64,
+        layer: u64,
+        id: u128,
+    ) -> Result<Record, SSTError> {
+        let fname = format!("{:06}.sst", seq_no);
+        self.read_from(&self.layer_path(collection_name, layer).join(&fname), id)
+    }
+
+    pub fn read_from(&self, path: &Path, id: u128) -> Result<Record, SSTError> {
+        self.tables.get(path)?.get(id)
+    }
+
+    /// The open table of an SST, shared through the table cache.
+    pub fn table(&self, metadata: &SSTMetadata) -> Result<Arc<Table>, SSTError> {
+        self.tables.get(&metadata.path)
+    }
+
+    /// Records of the `wanted` ids an SST holds. Only the blocks holding wanted ids are
+    /// decoded.
+    pub fn scan_selected(
+        &self,
+        metadata: &SSTMetadata,
+        wanted: &BTreeSet<u128>,
+    ) -> Result<Vec<Record>, SSTError> {
+        self.table(metadata)?.scan_selected(wanted)
+    }
+
+    /// Every record of an SST in id order.
+    pub fn scan(&self, metadata: &SSTMetadata) -> Result<Vec<Record>, SSTError> {
+        self.table(metadata)?.scan(true)
+    }
+
+    /// Every record of an SST in id order, without filling the block cache. For
+    /// compaction inputs, which are read once and then deleted.
+    pub fn scan_uncached(&self, metadata: &SSTMetadata) -> Result<Vec<Record>, SSTError> {
+        self.table(metadata)?.scan(false)
+    }
+
+    /// Every record of an SST in id order, read one block at a time without filling the
+    /// block cache. A compaction merges its inputs through these.
+    pub fn records(&self, metadata: &SSTMetadata) -> Result<TableRecords, SSTError> {
+        self.table(metadata)?.records()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::collection::IndexType;
+    use crate::collection::{DistanceType, IndexConfig};
+    use crate::constant::DEFAULT_MEMTABLE_SIZE;
+    use crate::document::Document;
+    use crate::ivf::TrainedCentroids;
     use crate::memtable::get_memtable;
     use crate::test_utils::bulk_random_documents;
     use std::collections::HashMap;
     use tempfile::tempdir;
 
+    /// A v1 file as the first build wrote it, to check that older files stay readable.
+    /// That build had no tombstones, payloads or external ids.
+    fn write_v1_file(fpath: &Path, records: &[Record]) {
+        #[derive(Serialize)]
+        struct V1IndexEntry {
+            id: u128,
+            offset: u64,
+            length: u32,
+        }
+        #[derive(Serialize)]
+        struct V1Document<'a> {
+            id: u128,
+            vector: &'a [f32],
+            content: &'a str,
+        }
+
+        let mut data_section = Vec::new();
+        let mut index_entries = Vec::new();
+        for record in records {
+            let Record::Live(doc) = record else {
+                panic!("v1 files have no tombstones");
+            };
+            let serialized = bincode::serialize(&V1Document {
+                id: doc.id,
+                vector: &doc.vector,
+                content: &doc.content,
+            })
+            .unwrap();
+            index_entries.push(V1IndexEntry {
+                id: doc.id,
+                offset: data_section.len() as u64,
+                length: serialized.len() as u32,
+            });
+            data_section.extend(serialized);
+        }
+        let index_bytes = bincode::serialize(&index_entries).unwrap();
+        assert_eq!(index_bytes.len(), 8 + 28 * records.len());
+        let footer = Footer {
+            version: 1,
+            ..Footer::new(
+                records.first().unwrap().id(),
+                records.last().unwrap().id(),
+                data_section.len() as u64,
+                index_bytes.len() as u64,
+                records.len() as u64,
+                0,
+                0,
+            )
+        };
+        assert_eq!(footer.to_bytes().len(), FOOTER_V1_SIZE);
+        fs::create_dir_all(fpath.parent().unwrap()).unwrap();
+        fs::write(
+            fpath,
+            [data_section, index_bytes, footer.to_bytes()].concat(),
+        )
+        .unwrap();
+    }
+
+    /// A v2 file: a single block of whole documents, its index and bloom filter.
+    fn write_v2_file(fpath: &Path, records: &[Record]) {
+        #[derive(Serialize)]
+        struct V2Handle {
+            first_id: u128,
+            last_id: u128,
+            offset: u64,
+            size: u64,
+        }
+
+        let mut block = Vec::new();
+        let mut bloom = BloomFilter::new(records.len(), BLOOM_BITS_PER_KEY);
+        for record in records {
+            bloom.insert(record.id());
+            block.extend_from_slice(&record.id().to_le_bytes());
+            match record {
+                Record::Live(doc) => {
+                    let serialized = doc.serialize().unwrap();
+                    block.push(0);
+                    block.extend_from_slice(&(serialized.len() as u32).to_le_bytes());
+                    block.extend(serialized);
+                }
+                Record::Tombstone(_) => block.extend_from_slice(&[1, 0, 0, 0, 0]),
+            }
+        }
+        let handles = vec![V2Handle {
+            first_id: records.first().unwrap().id(),
+            last_id: records.last().unwrap().id(),
+            offset: 0,
+            size: block.len() as u64,
+        }];
+
+        let mut file = Vec::new();
+        write_checked(&mut file, &block).unwrap();
+        let index_offset = file.len() as u64;
+        let index_size = write_checked(&mut file, &bincode::serialize(&handles).unwrap()).unwrap();
+        let bloom_offset = file.len() as u64;
+        let bloom_size = write_checked(&mut file, &bincode::serialize(&bloom).unwrap()).unwrap();
+        let footer = Footer {
+            version: 2,
+            ..Footer::new(
+                handles[0].first_id,
+                handles[0].last_id,
+                index_offset,
+                index_size,
+                records.len() as u64,
+                bloom_offset,
+                bloom_size,
+            )
+        };
+        assert_eq!(footer.to_bytes().len(), FOOTER_V2_SIZE);
+        file.extend(footer.to_bytes());
+        fs::create_dir_all(fpath.parent().unwrap()).unwrap();
+        fs::write(fpath, file).unwrap();
+    }
+
+    fn sorted_records(documents: Vec<Document>, tombstones: &[u128]) -> Vec<Record> {
+        let mut records: Vec<Record> = documents
+            .into_iter()
+            .map(|doc| Record::Live(Arc::new(doc)))
+            .chain(tombstones.iter().map(|id| Record::Tombstone(*id)))
+            .collect();
+        records.sort_by_key(Record::id);
+        records
+    }
+
     #[test]
     fn test_sst_write_and_read_all_documents() {
         let dir = tempdir().expect("Failed to create temp dir");
@@ -266,7 +1215,12 @@ mod tests {
             expected.insert(doc.id, (doc.vector.clone(), doc.content.clone()));
         }
 
-        let mut memtable = get_memtable(&IndexType::Flat);
+        let mut memtable = get_memtable(
+            &IndexConfig::new_with_default_config("flat").unwrap(),
+            &DistanceType::L2,
+            DEFAULT_MEMTABLE_SIZE,
+            &TrainedCentroids::default(),
+        );
         for doc in docs {
             memtable.upsert(doc);
         }
@@ -276,9 +1230,12 @@ mod tests {
             .expect("Failed to write SST");
 
         for (id, (expected_vector, expected_content)) in expected.iter() {
-            let doc = sst_manager
+            let Record::Live(doc) = sst_manager
                 .read(collection_name, seq_no, layer, *id)
-                .expect(&format!("Failed to read document with id {}", id));
+                .expect(&format!("Failed to read document with id {}", id))
+            else {
+                panic!("Expected a live document for id {}", id);
+            };
 
             assert_eq!(doc.id, *id, "ID mismatch");
             assert_eq!(
@@ -294,6 +1251,50 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_sst_scan() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let sst_manager = SSTManager::new(dir.path().to_path_buf());
+
+        let mut memtable = get_memtable(
+            &IndexConfig::new_with_default_config("flat").unwrap(),
+            &DistanceType::L2,
+            DEFAULT_MEMTABLE_SIZE,
+            &TrainedCentroids::default(),
+        );
+        for doc in bulk_random_documents(16, 50) {
+            memtable.upsert(doc);
+        }
+        // Ids that only ever existed in an older SST
+        memtable.delete(&0);
+        memtable.delete(&u128::MAX);
+
+        let metadata = sst_manager
+            .write_memtable("test_collection", 1, 0, memtable.as_ref())
+            .expect("Failed to write SST");
+        assert_eq!(metadata.min_id, 0);
+        assert_eq!(metadata.max_id, u128::MAX);
+
+        let records = sst_manager.scan(&metadata).expect("Failed to scan SST");
+        let expected: Vec<u128> = memtable.sorted_iter().map(|record| record.id()).collect();
+        assert_eq!(
+            records.iter().map(Record::id).collect::<Vec<u128>>(),
+            expected
+        );
+        assert_eq!(records.len(), 52);
+        assert!(matches!(records[0], Record::Tombstone(0)));
+        assert!(matches!(records[51], Record::Tombstone(u128::MAX)));
+        assert!(records[1..51].iter().all(|record| match record {
+            Record::Live(doc) => doc.vector.len() == 16,
+            Record::Tombstone(_) => false,
+        }));
+
+        assert!(matches!(
+            sst_manager.read_from(&metadata.path, u128::MAX),
+            Ok(Record::Tombstone(u128::MAX))
+        ));
+    }
+
     #[test]
     fn test_sst_read_not_found() {
         let dir = tempdir().expect("Failed to create temp dir");
@@ -304,7 +1305,12 @@ mod tests {
         let layer = 0;
 
         let docs = bulk_random_documents(64, 10);
-        let mut memtable = get_memtable(&IndexType::Flat);
+        let mut memtable = get_memtable(
+            &IndexConfig::new_with_default_config("flat").unwrap(),
+            &DistanceType::L2,
+            DEFAULT_MEMTABLE_SIZE,
+            &TrainedCentroids::default(),
+        );
         for doc in docs {
             memtable.upsert(doc);
         }
@@ -317,4 +1323,264 @@ mod tests {
         let result = sst_manager.read(collection_name, seq_no, layer, 12345678901234567890);
         assert!(matches!(result, Err(SSTError::NotFound)));
     }
+
+    #[test]
+    fn test_last_flushed_seq_no_skips_torn_files() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let sst_manager = SSTManager::new(dir.path().to_path_buf());
+        let collection_name = "test_collection";
+
+        assert_eq!(
+            sst_manager.last_flushed_seq_no(collection_name).unwrap(),
+            None
+        );
+
+        let mut memtable = get_memtable(
+            &IndexConfig::new_with_default_config("flat").unwrap(),
+            &DistanceType::L2,
+            DEFAULT_MEMTABLE_SIZE,
+            &TrainedCentroids::default(),
+        );
+        for doc in bulk_random_documents(8, 10) {
+            memtable.upsert(doc);
+        }
+        for seq_no in [1, 3] {
+            sst_manager
+                .write_memtable(collection_name, seq_no, 0, memtable.as_ref())
+                .expect("Failed to write SST");
+        }
+        assert_eq!(
+            sst_manager.last_flushed_seq_no(collection_name).unwrap(),
+            Some(3)
+        );
+
+        // Flushes are renamed into place once complete
+        let layer_dir = sst_manager.path.join(collection_name).join("L0");
+        assert!(!temp_path(&layer_dir.join("000003.sst")).exists());
+
+        // Simulate a crash in the middle of flushing seq_no 4, before its rename
+        std::fs::write(temp_path(&layer_dir.join("000004.sst")), vec![7u8; 100]).unwrap();
+        assert_eq!(
+            sst_manager.last_flushed_seq_no(collection_name).unwrap(),
+            Some(3)
+        );
+        assert_eq!(sst_manager.load_metadata(collection_name).unwrap().len(), 2);
+        assert!(!temp_path(&layer_dir.join("000004.sst")).exists());
+
+        // A complete file that fails its footer check is damaged, not torn
+        std::fs::write(layer_dir.join("000005.sst"), vec![7u8; 100]).unwrap();
+        let err = sst_manager
+            .last_flushed_seq_no(collection_name)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_older_versions_stay_readable() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let sst_manager = SSTManager::new(dir.path().to_path_buf());
+        let records = sorted_records(bulk_random_documents(8, 20), &[0]);
+        let layer_path = sst_manager.layer_path("test_collection", 0);
+        write_v1_file(&layer_path.join("000001.sst"), &records[1..]);
+        write_v2_file(&layer_path.join("000002.sst"), &records);
+
+        // Without an ANN index, a v3 file only differs by its footer
+        let v3_path = layer_path.join("000003.sst");
+        let footer = write_file(&v3_path, records.iter().cloned(), records.len(), None).unwrap();
+        let mut bytes = fs::read(&v3_path).unwrap();
+        bytes.truncate(bytes.len() - FOOTER_V4_SIZE);
+        bytes.extend(
+            Footer {
+                version: 3,
+                ..footer
+            }
+            .to_bytes(),
+        );
+        fs::write(&v3_path, &bytes).unwrap();
+
+        let metadata = sst_manager.load_metadata("test_collection").unwrap();
+        assert_eq!(metadata.len(), 3);
+        for metadata in metadata.iter() {
+            // The v1 file holds the live records only
+            let v1 = metadata.seq_no == 1;
+            let records = if v1 { &records[1..] } else { &records[..] };
+            assert_eq!(metadata.entry_count, records.len() as u64);
+
+            let scanned = sst_manager.scan(metadata).unwrap();
+            assert_eq!(
+                scanned.iter().map(Record::id).collect::<Vec<_>>(),
+                records.iter().map(Record::id).collect::<Vec<_>>()
+            );
+            if !v1 {
+                assert!(matches!(
+                    sst_manager.read_from(&metadata.path, 0),
+                    Ok(Record::Tombstone(0))
+                ));
+            }
+            let Ok(Record::Live(doc)) = sst_manager.read_from(&metadata.path, records[5].id())
+            else {
+                panic!("Expected a live document");
+            };
+            let Record::Live(written) = &records[5] else {
+                unreachable!()
+            };
+            assert_eq!(doc.id, written.id);
+            assert_eq!(doc.vector, written.vector);
+            assert_eq!(doc.content, written.content);
+            assert!(doc.payload.is_empty());
+            assert_eq!(doc.external_id, None);
+
+            let wanted = BTreeSet::from([records[3].id()]);
+            let selected = sst_manager.scan_selected(metadata, &wanted).unwrap();
+            assert_eq!(selected.len(), 1);
+
+            let table = sst_manager.table(metadata).unwrap();
+            assert!(table.contains(records[3].id()).unwrap());
+            assert_eq!(table.contains(0).unwrap(), !v1); // tombstones shadow as well
+            assert!(!table.contains(records.last().unwrap().id() + 1).unwrap());
+            let columns = table.vector_columns().unwrap();
+            assert_eq!(columns.is_tombstone(0), !v1);
+            assert_eq!(columns.vector(5), doc.vector.as_slice());
+        }
+    }
+
+    #[test]
+    fn test_vector_columns_follow_the_ids() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let sst_manager = SSTManager::new(dir.path().to_path_buf());
+        let documents = bulk_random_documents(32, 200);
+        let records = sorted_records(documents, &[0, 7]);
+        let metadata = sst_manager
+            .write_compaction_output(
+                "test_collection",
+                1,
+                1,
+                records.iter().cloned(),
+                records.len(),
+                None,
+            )
+            .unwrap();
+        let path = temp_path(&metadata.path);
+        let metadata = SSTMetadata { path, ..metadata };
+
+        let footer = read_footer(&metadata.path).unwrap();
+        assert_eq!(footer.version, SST_VERSION);
+        assert_eq!(footer.vectors_offset % VECTOR_ALIGNMENT, 0);
+        assert_eq!(footer.vectors_size, 202 * 32 * 4);
+
+        let table = sst_manager.table(&metadata).unwrap();
+        let columns = table.vector_columns().unwrap();
+        assert_eq!(columns.len(), 202);
+        for (row, record) in records.iter().enumerate() {
+            assert_eq!(columns.ids()[row], record.id());
+            match record {
+                Record::Live(doc) => {
+                    assert!(!columns.is_tombstone(row));
+                    assert_eq!(columns.vector(row), doc.vector.as_slice());
+                }
+                Record::Tombstone(_) => {
+                    assert!(columns.is_tombstone(row));
+                    assert!(columns.vector(row).iter().all(|v| *v == 0.0));
+                }
+            }
+        }
+
+        // Documents come back whole, their vectors taken from the vector section
+        let scanned = sst_manager.scan(&metadata).unwrap();
+        for (scanned, record) in scanned.iter().zip(records.iter()) {
+            match (scanned, record) {
+                (Record::Live(a), Record::Live(b)) => {
+                    assert_eq!(
+                        (a.id, &a.vector, &a.content, &a.payload, &a.external_id),
+                        (b.id, &b.vector, &b.content, &b.payload, &b.external_id)
+                    );
+                }
+                (Record::Tombstone(a), Record::Tombstone(b)) => assert_eq!(a, b),
+                _ => panic!("Record kind changed"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_checksums_catch_damaged_blocks() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let sst_manager = SSTManager::new(dir.path().to_path_buf());
+        let records = sorted_records(bulk_random_documents(64, 100), &[]);
+        let metadata = sst_manager
+            .write_compaction_output(
+                "test_collection",
+                1,
+                1,
+                records.iter().cloned(),
+                records.len(),
+                None,
+            )
+            .unwrap();
+        let path = temp_path(&metadata.path);
+
+        // Blocks are small enough for a file of 100 documents to need several
+        let blocks = Table::open(&path, Arc::new(BlockCache::new(0)))
+            .unwrap()
+            .block_handles();
+        assert!(blocks.len() > 1);
+
+        // Flip a byte inside the second block
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[blocks[1].offset as usize + 30] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        let in_second_block = records
+            .iter()
+            .map(Record::id)
+            .find(|id| *id >= blocks[1].first_id)
+            .unwrap();
+        let metadata = SSTMetadata {
+            path: path.clone(),
+            ..metadata
+        };
+        assert!(matches!(
+            sst_manager.read_from(&path, in_second_block),
+            Err(SSTError::Corrupted(_))
+        ));
+        assert!(matches!(
+            sst_manager.scan(&metadata),
+            Err(SSTError::Corrupted(_))
+        ));
+        // The first block is intact and still answers
+        assert!(sst_manager.read_from(&path, records[0].id()).is_ok());
+    }
+
+    #[test]
+    fn test_unknown_versions_are_rejected() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let sst_manager = SSTManager::new(dir.path().to_path_buf());
+        let mut memtable = get_memtable(
+            &IndexConfig::new_with_default_config("flat").unwrap(),
+            &DistanceType::L2,
+            DEFAULT_MEMTABLE_SIZE,
+            &TrainedCentroids::default(),
+        );
+        for doc in bulk_random_documents(8, 10) {
+            memtable.upsert(doc);
+        }
+        let metadata = sst_manager
+            .write_memtable("test_collection", 1, 0, memtable.as_ref())
+            .unwrap();
+
+        let mut bytes = fs::read(&metadata.path).unwrap();
+        let len = bytes.len();
+        bytes[len - 4..].copy_from_slice(&9u32.to_be_bytes());
+        fs::write(&metadata.path, &bytes).unwrap();
+
+        assert!(matches!(
+            Footer::from_bytes(&bytes[len - FOOTER_V2_SIZE..]),
+            Err(SSTError::UnsupportedVersion(9))
+        ));
+        assert!(matches!(
+            sst_manager.read_from(&metadata.path, 1),
+            Err(SSTError::UnsupportedVersion(9))
+        ));
+        let error = sst_manager.load_metadata("test_collection").unwrap_err();
+        assert!(error.to_string().contains("Unsupported SST version 9"));
+    }
 }
@@ -0,0 +1,843 @@
+/*
+Reading SSTs
+
+A `Table` is an SST opened once: the file is memory-mapped read-only and its footer is
+parsed when it is opened. Everything decoded from it goes through the database's
+`BlockCache`: the block index, the bloom filter and the id column, the ANN index, the
+records of each data block, and the vector columns of files older than v3. Vectors of
+newer files are handed out as slices of the mapping and never copied.
+
+Open tables are shared through the `TableCache`, an LRU keyed by path owned by the
+SSTManager, so that a lookup does not pay for opening its file again. A closed table
+drops its blocks from the block cache, so tables of collections with pinned index
+blocks are never evicted, they count against the capacity all the same. See sst.rs for
+the file layout.
+*/
+
+use crate::ann::AnnIndex;
+use crate::block_cache::{BlockCache, BlockKey, BlockKind};
+use crate::bloom::BloomFilter;
+use crate::checksum::crc32c;
+use crate::document::{Document, ExternalId, Record};
+use crate::payload::Payload;
+use crate::sst::{
+    BLOCK_ENTRY_HEADER_SIZE, BlockHandle, CHECKSUM_SIZE, Footer, IndexEntry, SSTError, v1, v2,
+};
+use memmap2::Mmap;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+
+// Vector sections are little-endian f32 and are read in place
+const _: () = assert!(
+    cfg!(target_endian = "little"),
+    "SST vector sections are read in place as little-endian f32"
+);
+
+static NEXT_TABLE_ID: AtomicU64 = AtomicU64::new(0); // keys the blocks of each open table
+
+/// Whatever locates the records of a file, cached as one index block.
+struct TableIndex {
+    records: RecordIndex,
+    id_column: Option<Arc<IdColumn>>, // v3 on
+}
+
+enum RecordIndex {
+    V1(Vec<IndexEntry>),
+    Blocks {
+        blocks: Vec<BlockHandle>,
+        bloom: BloomFilter,
+    },
+}
+
+struct IdColumn {
+    ids: Vec<u128>,
+    tombstones: Vec<bool>,
+}
+
+/// Ids and vectors of every record of an SST as parallel columns, row `i` is the
+/// `i`-th record in id order. Vectors are borrowed from the mapping from v3 on.
+#[derive(Clone)]
+pub struct VectorColumns<'a> {
+    ids: Arc<IdColumn>,
+    dimension: usize,
+    vectors: Floats<'a>,
+}
+
+#[derive(Clone)]
+enum Floats<'a> {
+    Mapped(&'a [f32]),
+    Decoded(Arc<[f32]>), // decoded from an older file, shared with the block cache
+}
+
+impl VectorColumns<'_> {
+    pub fn ids(&self) -> &[u128] {
+        &self.ids.ids
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.ids.is_empty()
+    }
+
+    pub fn is_tombstone(&self, row: usize) -> bool {
+        self.ids.tombstones[row]
+    }
+
+    /// Vector of the record at `row`, zeros for a tombstone.
+    pub fn vector(&self, row: usize) -> &[f32] {
+        let vectors: &[f32] = match &self.vectors {
+            Floats::Mapped(vectors) => vectors,
+            Floats::Decoded(vectors) => vectors,
+        };
+        &vectors[row * self.dimension..(row + 1) * self.dimension]
+    }
+}
+
+pub struct Table {
+    id: u64,
+    path: PathBuf,
+    collection: Arc<str>, // whose index blocks are pinned along with the collection's
+    mmap: Mmap,
+    footer: Footer,
+    cache: Arc<BlockCache>,
+    vectors_checked: OnceLock<Result<(), String>>, // the whole section, on first scan
+}
+
+impl Table {
+    pub fn open(path: &Path, cache: Arc<BlockCache>) -> Result<Self, SSTError> {
+        let file = File::open(path)?;
+        // SAFETY: SSTs are immutable once written and are only ever unlinked, which
+        // leaves existing mappings valid
+        let mmap = unsafe { Mmap::map(&file)? };
+        let footer = crate::sst::parse_footer(&mmap)?;
+
+        // root/{collection}/L{layer}/{seq_no}.sst
+        let collection = path
+            .parent()
+            .and_then(Path::parent)
+            .and_then(Path::file_name)
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+        let table = Table {
+            id: NEXT_TABLE_ID.fetch_add(1, Ordering::Relaxed),
+            path: path.to_path_buf(),
+            collection: Arc::from(collection.as_ref()),
+            mmap,
+            footer,
+            cache,
+            vectors_checked: OnceLock::new(),
+        };
+        // A corrupted index fails the open rather than the first lookup
+        table.index()?;
+        Ok(table)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn footer(&self) -> &Footer {
+        &self.footer
+    }
+
+    fn key(&self, kind: BlockKind, offset: u64) -> BlockKey {
+        BlockKey {
+            table: self.id,
+            kind,
+            offset,
+        }
+    }
+
+    fn index(&self) -> Result<Arc<TableIndex>, SSTError> {
+        let footer = &self.footer;
+        let charge = footer.index_section_size + footer.bloom_size + footer.ids_size;
+        self.cache.get_or_load(
+            self.key(BlockKind::Index, 0),
+            &self.collection,
+            true,
+            || {
+                let index = TableIndex {
+                    records: self.parse_index()?,
+                    id_column: match footer.version >= 3 {
+                        true => Some(Arc::new(self.parse_id_column()?)),
+                        false => None,
+                    },
+                };
+                Ok((index, charge as usize))
+            },
+        )
+    }
+
+    fn parse_index(&self) -> Result<RecordIndex, SSTError> {
+        let footer = &self.footer;
+        if footer.version == 1 {
+            let bytes = self.section(footer.index_section_offset, footer.index_section_size)?;
+            let entries = bincode::deserialize::<Vec<v1::IndexEntry>>(bytes)?;
+            return Ok(RecordIndex::V1(
+                entries.into_iter().map(IndexEntry::from).collect(),
+            ));
+        }
+
+        let blocks = self.checked(footer.index_section_offset, footer.index_section_size)?;
+        let blocks = if footer.version == 2 {
+            bincode::deserialize::<Vec<v2::BlockHandle>>(blocks)?
+                .into_iter()
+                .map(BlockHandle::from)
+                .collect()
+        } else {
+            bincode::deserialize(blocks)?
+        };
+        let bloom = self.checked(footer.bloom_offset, footer.bloom_size)?;
+        Ok(RecordIndex::Blocks {
+            blocks,
+            bloom: bincode::deserialize(bloom)?,
+        })
+    }
+
+    /// Ids and tombstone flags of a v3 file.
+    fn parse_id_column(&self) -> Result<IdColumn, SSTError> {
+        let bytes = self.checked(self.footer.ids_offset, self.footer.ids_size)?;
+        let count = self.footer.entry_count as usize;
+        if bytes.len() != count * 17 {
+            return Err(SSTError::Corrupted(format!(
+                "Id section of {} bytes for {} records",
+                bytes.len(),
+                count
+            )));
+        }
+        let (ids, flags) = bytes.split_at(count * 16);
+        Ok(IdColumn {
+            ids: ids
+                .chunks_exact(16)
+                .map(|id| u128::from_le_bytes(id.try_into().unwrap()))
+                .collect(),
+            tombstones: flags.iter().map(|flag| *flag == 1).collect(),
+        })
+    }
+
+    /// `size` bytes at `offset`, bounds checked.
+    fn section(&self, offset: u64, size: u64) -> Result<&[u8], SSTError> {
+        let start = offset as usize;
+        start
+            .checked_add(size as usize)
+            .and_then(|end| self.mmap.get(start..end))
+            .ok_or_else(|| {
+                SSTError::Corrupted(format!(
+                    "Section of {} bytes at offset {} is out of bounds",
+                    size, offset
+                ))
+            })
+    }
+
+    /// `size` bytes at `offset`, checked against the checksum that follows them.
+    fn checked(&self, offset: u64, size: u64) -> Result<&[u8], SSTError> {
+        let bytes = self.section(offset, size + CHECKSUM_SIZE as u64)?;
+        let (bytes, crc) = bytes.split_at(size as usize);
+        if crc32c(bytes) != u32::from_le_bytes(crc.try_into().unwrap()) {
+            return Err(SSTError::Corrupted(format!(
+                "Checksum mismatch at offset {}",
+                offset
+            )));
+        }
+        Ok(bytes)
+    }
+
+    /// Vector rows of the records of one block of a v3 file.
+    fn block_vectors(&self, handle: &BlockHandle) -> Result<&[f32], SSTError> {
+        let row_size = self.footer.dimension as u64 * 4;
+        let offset = self.footer.vectors_offset + handle.first_row * row_size;
+        let bytes = self.section(offset, handle.rows * row_size)?;
+        if crc32c(bytes) != handle.vectors_crc {
+            return Err(SSTError::Corrupted(format!(
+                "Vector checksum mismatch at offset {}",
+                offset
+            )));
+        }
+        as_floats(bytes)
+    }
+
+    /// Records of the block at `handle` with their vectors, cached unless `fill` is false.
+    fn block_records(
+        &self,
+        handle: &BlockHandle,
+        fill: bool,
+    ) -> Result<Arc<Vec<Record>>, SSTError> {
+        let key = self.key(BlockKind::Documents, handle.offset);
+        self.cache.get_or_load(key, &self.collection, fill, || {
+            let entries = decode_block(self.checked(handle.offset, handle.size)?)?;
+            let vectors = if self.footer.version >= 3 {
+                if entries.len() as u64 != handle.rows {
+                    return Err(SSTError::Corrupted(format!(
+                        "Block at offset {} holds {} records, its handle {}",
+                        handle.offset,
+                        entries.len(),
+                        handle.rows
+                    )));
+                }
+                Some(self.block_vectors(handle)?)
+            } else {
+                None
+            };
+
+            let dimension = self.footer.dimension as usize;
+            let records = entries
+                .iter()
+                .enumerate()
+                .map(|(row, entry)| {
+                    let vector = vectors.map(|v| &v[row * dimension..(row + 1) * dimension]);
+                    entry.record(vector)
+                })
+                .collect::<Result<Vec<Record>, SSTError>>()?;
+            let charge = handle.size + handle.rows * dimension as u64 * 4;
+            Ok((records, charge as usize))
+        })
+    }
+
+    fn v1_record(&self, entry: &IndexEntry, fill: bool) -> Result<Record, SSTError> {
+        if entry.tombstone {
+            return Ok(Record::Tombstone(entry.id));
+        }
+        let key = self.key(BlockKind::Documents, entry.offset);
+        let record = self.cache.get_or_load(key, &self.collection, fill, || {
+            let bytes = self.section(entry.offset, entry.length as u64)?;
+            let doc = bincode::deserialize::<v1::Document>(bytes)?;
+            let record = Record::Live(Arc::new(Document::from(doc)));
+            Ok((record, entry.length as usize))
+        })?;
+        Ok(record.as_ref().clone())
+    }
+
+    /// The record of `id`, `SSTError::NotFound` if the file does not hold it.
+    pub fn get(&self, id: u128) -> Result<Record, SSTError> {
+        if id < self.footer.min_id || id > self.footer.max_id {
+            return Err(SSTError::NotFound);
+        }
+
+        match &self.index()?.records {
+            RecordIndex::V1(index_entries) => {
+                // binary search for id (index is sorted by id), then read the document
+                match index_entries.binary_search_by_key(&id, |e| e.id) {
+                    Ok(idx) => self.v1_record(&index_entries[idx], true),
+                    Err(_) => Err(SSTError::NotFound),
+                }
+            }
+            RecordIndex::Blocks { blocks, bloom } => {
+                if !bloom.may_contain(id) {
+                    return Err(SSTError::NotFound);
+                }
+                // the only block whose range may hold id, then search it
+                let idx = blocks.partition_point(|b| b.last_id < id);
+                let Some(handle) = blocks.get(idx).filter(|b| b.first_id <= id) else {
+                    return Err(SSTError::NotFound);
+                };
+                let records = self.block_records(handle, true)?;
+                match records.binary_search_by_key(&id, Record::id) {
+                    Ok(idx) => Ok(records[idx].clone()),
+                    Err(_) => Err(SSTError::NotFound),
+                }
+            }
+        }
+    }
+
+    /// Every record in id order. With `fill_cache` false the blocks read are not cached,
+    /// for one-off reads of a whole file such as compaction inputs.
+    pub fn scan(&self, fill_cache: bool) -> Result<Vec<Record>, SSTError> {
+        match &self.index()?.records {
+            RecordIndex::V1(index_entries) => index_entries
+                .iter()
+                .map(|entry| self.v1_record(entry, fill_cache))
+                .collect(),
+            RecordIndex::Blocks { blocks, .. } => {
+                let mut records = Vec::with_capacity(self.footer.entry_count as usize);
+                for handle in blocks.iter() {
+                    records.extend_from_slice(&self.block_records(handle, fill_cache)?);
+                }
+                Ok(records)
+            }
+        }
+    }
+
+    /// Every record in id order, decoded one block at a time as the iterator is pulled.
+    /// The blocks read are not cached, for compaction inputs.
+    pub fn records(self: Arc<Self>) -> Result<TableRecords, SSTError> {
+        Ok(TableRecords {
+            index: self.index()?,
+            table: self,
+            next: 0,
+            block: Arc::default(),
+            row: 0,
+        })
+    }
+
+    /// Whether the file holds a record of `id`, tombstones included. Answered from the
+    /// bloom filter and, from v3 on, the id column, without decoding a block.
+    pub fn contains(&self, id: u128) -> Result<bool, SSTError> {
+        if id < self.footer.min_id || id > self.footer.max_id {
+            return Ok(false);
+        }
+
+        let index = self.index()?;
+        match (&index.records, &index.id_column) {
+            (RecordIndex::V1(index_entries), _) => {
+                Ok(index_entries.binary_search_by_key(&id, |e| e.id).is_ok())
+            }
+            (RecordIndex::Blocks { bloom, .. }, _) if !bloom.may_contain(id) => Ok(false),
+            (_, Some(id_column)) => Ok(id_column.ids.binary_search(&id).is_ok()),
+            (_, None) => match self.get(id) {
+                Ok(_) => Ok(true),
+                Err(SSTError::NotFound) => Ok(false),
+                Err(e) => Err(e),
+            },
+        }
+    }
+
+    /// Records of the `wanted` ids the file holds. Only the blocks holding wanted ids
+    /// are decoded.
+    pub fn scan_selected(&self, wanted: &BTreeSet<u128>) -> Result<Vec<Record>, SSTError> {
+        let index = self.index()?;
+        let blocks = match &index.records {
+            RecordIndex::V1(index_entries) => {
+                let mut records = Vec::new();
+                for entry in index_entries.iter().filter(|e| wanted.contains(&e.id)) {
+                    records.push(self.v1_record(entry, true)?);
+                }
+                return Ok(records);
+            }
+            RecordIndex::Blocks { blocks, .. } => blocks,
+        };
+
+        let mut records = Vec::new();
+        for handle in blocks
+            .iter()
+            .filter(|b| wanted.range(b.first_id..=b.last_id).next().is_some())
+        {
+            let block = self.block_records(handle, true)?;
+            records.extend(block.iter().filter(|r| wanted.contains(&r.id())).cloned());
+        }
+
+        Ok(records)
+    }
+
+    /// Ids and vectors of every record. From v3 on the vectors are borrowed from the
+    /// mapping and the vector section is checked once, older files are decoded whole.
+    pub fn vector_columns(&self) -> Result<VectorColumns<'_>, SSTError> {
+        let index = self.index()?;
+        let (Some(ids), RecordIndex::Blocks { blocks, .. }) = (&index.id_column, &index.records)
+        else {
+            return self.decoded_vector_columns();
+        };
+
+        let bytes = self.section(self.footer.vectors_offset, self.footer.vectors_size)?;
+        self.vectors_checked
+            .get_or_init(|| {
+                let row_size = self.footer.dimension as usize * 4;
+                for handle in blocks.iter() {
+                    let start = handle.first_row as usize * row_size;
+                    let rows = bytes.get(start..start + handle.rows as usize * row_size);
+                    if rows.is_none_or(|rows| crc32c(rows) != handle.vectors_crc) {
+                        return Err(format!(
+                            "Vector checksum mismatch at offset {}",
+                            self.footer.vectors_offset + start as u64
+                        ));
+                    }
+                }
+                Ok(())
+            })
+            .clone()
+            .map_err(SSTError::Corrupted)?;
+
+        Ok(VectorColumns {
+            ids: ids.clone(),
+            dimension: self.footer.dimension as usize,
+            vectors: Floats::Mapped(as_floats(bytes)?),
+        })
+    }
+
+    /// The ANN index of the file, `None` before v4 or for a flat collection.
+    pub fn ann_index(&self) -> Result<Option<Arc<AnnIndex>>, SSTError> {
+        let footer = &self.footer;
+        if footer.ann_size == 0 {
+            return Ok(None);
+        }
+        let key = self.key(BlockKind::Index, footer.ann_offset);
+        self.cache
+            .get_or_load(key, &self.collection, true, || {
+                let bytes = self.checked(footer.ann_offset, footer.ann_size)?;
+                let ann: AnnIndex = bincode::deserialize(bytes)?;
+                Ok((ann, footer.ann_size as usize))
+            })
+            .map(Some)
+    }
+
+    fn decoded_vector_columns(&self) -> Result<VectorColumns<'_>, SSTError> {
+        let key = self.key(BlockKind::Vectors, 0);
+        let columns = self.cache.get_or_load(key, &self.collection, true, || {
+            // Charged once as columns, not again as the blocks they come from
+            let records = self.scan(false)?;
+            let dimension = records
+                .iter()
+                .find_map(|record| match record {
+                    Record::Live(doc) => Some(doc.vector.len()),
+                    Record::Tombstone(_) => None,
+                })
+                .unwrap_or(0);
+            let mut vectors = Vec::with_capacity(records.len() * dimension);
+            for record in records.iter() {
+                match record {
+                    Record::Live(doc) => vectors.extend_from_slice(&doc.vector),
+                    Record::Tombstone(_) => vectors.resize(vectors.len() + dimension, 0.0),
+                }
+            }
+            let charge = records.len() * 17 + vectors.len() * 4;
+            let columns = VectorColumns {
+                ids: Arc::new(IdColumn {
+                    ids: records.iter().map(Record::id).collect(),
+                    tombstones: records
+                        .iter()
+                        .map(|record| matches!(record, Record::Tombstone(_)))
+                        .collect(),
+                }),
+                dimension,
+                vectors: Floats::Decoded(vectors.into()),
+            };
+            Ok((columns, charge))
+        })?;
+        Ok(columns.as_ref().clone())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn block_handles(&self) -> Vec<BlockHandle> {
+        match &self.index().unwrap().records {
+            RecordIndex::V1(_) => Vec::new(),
+            RecordIndex::Blocks { blocks, .. } => blocks.clone(),
+        }
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        self.cache.forget_table(self.id);
+    }
+}
+
+/// Records of a table in id order, only the block being read is held in memory.
+pub struct TableRecords {
+    table: Arc<Table>,
+    index: Arc<TableIndex>,
+    next: usize, // index entry or block handle
+    block: Arc<Vec<Record>>,
+    row: usize,
+}
+
+impl Iterator for TableRecords {
+    type Item = Result<Record, SSTError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.row >= self.block.len() {
+            let block = match &self.index.records {
+                RecordIndex::V1(index_entries) => {
+                    let entry = index_entries.get(self.next)?;
+                    self.next += 1;
+                    return Some(self.table.v1_record(entry, false));
+                }
+                RecordIndex::Blocks { blocks, .. } => {
+                    let handle = blocks.get(self.next)?;
+                    self.next += 1;
+                    self.table.block_records(handle, false)
+                }
+            };
+            match block {
+                Ok(block) => (self.block, self.row) = (block, 0),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        self.row += 1;
+        Some(Ok(self.block[self.row - 1].clone()))
+    }
+}
+
+/// Reinterprets little-endian f32 bytes in place.
+fn as_floats(bytes: &[u8]) -> Result<&[f32], SSTError> {
+    // SAFETY: every bit pattern is a valid f32, alignment is checked below
+    let (prefix, floats, suffix) = unsafe { bytes.align_to::<f32>() };
+    if !prefix.is_empty() || !suffix.is_empty() {
+        return Err(SSTError::Corrupted("Misaligned vector rows".to_string()));
+    }
+    Ok(floats)
+}
+
+/// One record of a data block, its body is still encoded.
+struct BlockEntry<'a> {
+    id: u128,
+    tombstone: bool,
+    body: &'a [u8],
+}
+
+impl BlockEntry<'_> {
+    /// `vector` comes from the vector section, `None` for v2 blocks whose bodies are
+    /// whole documents.
+    fn record(&self, vector: Option<&[f32]>) -> Result<Record, SSTError> {
+        if self.tombstone {
+            return Ok(Record::Tombstone(self.id));
+        }
+        let doc = match vector {
+            None => bincode::deserialize::<v2::Document>(self.body)?.into(),
+            Some(vector) => {
+                let (content, payload, external_id): (String, Payload, Option<ExternalId>) =
+                    bincode::deserialize(self.body)?;
+                Document {
+                    id: self.id,
+                    vector: vector.to_vec(),
+                    content,
+                    payload,
+                    external_id,
+                }
+            }
+        };
+        Ok(Record::Live(Arc::new(doc)))
+    }
+}
+
+fn decode_block(block: &[u8]) -> Result<Vec<BlockEntry<'_>>, SSTError> {
+    let mut entries = Vec::new();
+    let mut rest = block;
+    while !rest.is_empty() {
+        let (header, tail) = rest
+            .split_at_checked(BLOCK_ENTRY_HEADER_SIZE)
+            .ok_or_else(|| SSTError::Corrupted("Truncated block entry".to_string()))?;
+        let length = u32::from_le_bytes(header[17..21].try_into().unwrap()) as usize;
+        let (body, tail) = tail
+            .split_at_checked(length)
+            .ok_or_else(|| SSTError::Corrupted("Block entry out of bounds".to_string()))?;
+        entries.push(BlockEntry {
+            id: u128::from_le_bytes(header[0..16].try_into().unwrap()),
+            tombstone: header[16] == 1,
+            body,
+        });
+        rest = tail;
+    }
+    Ok(entries)
+}
+
+struct CacheState {
+    tables: HashMap<PathBuf, (Arc<Table>, u64)>, // table and the tick of its last use
+    tick: u64,
+}
+
+/// LRU of open tables. A table evicted while a reader still holds it stays open until
+/// that reader is done.
+pub struct TableCache {
+    capacity: usize,
+    state: Mutex<CacheState>,
+    blocks: Arc<BlockCache>, // shared by the tables it opens
+}
+
+impl TableCache {
+    pub fn new(capacity: usize, blocks: Arc<BlockCache>) -> Self {
+        TableCache {
+            capacity: capacity.max(1),
+            blocks,
+            state: Mutex::new(CacheState {
+                tables: HashMap::new(),
+                tick: 0,
+            }),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CacheState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The open table of `path`, opened and cached on a miss.
+    pub fn get(&self, path: &Path) -> Result<Arc<Table>, SSTError> {
+        {
+            let mut state = self.lock();
+            state.tick += 1;
+            let tick = state.tick;
+            if let Some((table, last_used)) = state.tables.get_mut(path) {
+                *last_used = tick;
+                return Ok(table.clone());
+            }
+        }
+
+        // Opened outside the lock, two readers racing on a miss both open the file
+        let table = Arc::new(Table::open(path, self.blocks.clone())?);
+        let mut state = self.lock();
+        state.tick += 1;
+        let tick = state.tick;
+        state
+            .tables
+            .insert(path.to_path_buf(), (table.clone(), tick));
+        while state.tables.len() > self.capacity {
+            let Some(oldest) = state
+                .tables
+                .iter()
+                .filter(|(_, (table, _))| !self.blocks.is_pinned(&table.collection))
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(path, _)| path.clone())
+            else {
+                break;
+            };
+            state.tables.remove(&oldest);
+        }
+        Ok(table)
+    }
+
+    pub fn block_cache(&self) -> &BlockCache {
+        &self.blocks
+    }
+
+    /// Forgets `path`, called when its file is deleted or replaced.
+    pub fn evict(&self, path: &Path) {
+        self.lock().tables.remove(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collection::{DistanceType, IndexConfig};
+    use crate::constant::DEFAULT_MEMTABLE_SIZE;
+    use crate::ivf::TrainedCentroids;
+    use crate::memtable::get_memtable;
+    use crate::sst::SSTManager;
+    use crate::test_utils::bulk_random_documents;
+    use tempfile::tempdir;
+
+    fn write_tables(dir: &Path, count: u64) -> Vec<PathBuf> {
+        write_collection_tables(dir, "test_collection", count)
+    }
+
+    fn write_collection_tables(dir: &Path, collection: &str, count: u64) -> Vec<PathBuf> {
+        let sst_manager = SSTManager::new(dir.to_path_buf());
+        let mut memtable = get_memtable(
+            &IndexConfig::new_with_default_config("flat").unwrap(),
+            &DistanceType::L2,
+            DEFAULT_MEMTABLE_SIZE,
+            &TrainedCentroids::default(),
+        );
+        for doc in bulk_random_documents(8, 20) {
+            memtable.upsert(doc);
+        }
+        (1..=count)
+            .map(|seq_no| {
+                sst_manager
+                    .write_memtable(collection, seq_no, 0, memtable.as_ref())
+                    .unwrap()
+                    .path
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_vectors_are_borrowed_from_the_mapping() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let path = write_tables(dir.path(), 1).remove(0);
+        let table = Table::open(&path, Arc::new(BlockCache::new(0))).unwrap();
+
+        let columns = table.vector_columns().unwrap();
+        assert!(matches!(columns.vectors, Floats::Mapped(_)));
+        let mapping = table.mmap.as_ptr_range();
+        assert!(mapping.contains(&(columns.vector(3).as_ptr() as *const u8)));
+
+        let Record::Live(doc) = table.get(columns.ids()[3]).unwrap() else {
+            panic!("Expected a live document");
+        };
+        assert_eq!(doc.vector, columns.vector(3));
+    }
+
+    #[test]
+    fn test_cache_keeps_the_most_recently_used_tables() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let paths = write_tables(dir.path(), 3);
+        let cache = TableCache::new(2, Arc::new(BlockCache::new(0)));
+
+        let first = cache.get(&paths[0]).unwrap();
+        assert!(Arc::ptr_eq(&first, &cache.get(&paths[0]).unwrap()));
+        cache.get(&paths[1]).unwrap();
+        cache.get(&paths[0]).unwrap();
+
+        // paths[1] is the least recently used
+        cache.get(&paths[2]).unwrap();
+        {
+            let state = cache.lock();
+            assert_eq!(state.tables.len(), 2);
+            assert!(!state.tables.contains_key(&paths[1]));
+        }
+        assert!(Arc::ptr_eq(&first, &cache.get(&paths[0]).unwrap()));
+
+        cache.evict(&paths[0]);
+        assert!(!Arc::ptr_eq(&first, &cache.get(&paths[0]).unwrap()));
+    }
+
+    #[test]
+    fn test_cache_keeps_the_tables_of_pinned_collections() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let hot = write_collection_tables(dir.path(), "hot", 2);
+        let cold = write_collection_tables(dir.path(), "cold", 4);
+        let blocks = Arc::new(BlockCache::new(1 << 20));
+        blocks.set_pinned("hot", true);
+        let cache = TableCache::new(2, blocks.clone());
+
+        for path in hot.iter() {
+            cache.get(path).unwrap();
+        }
+        let pinned_usage = blocks.stats().pinned_usage;
+        assert!(pinned_usage > 0);
+
+        // Churn evicts the other tables only, the pinned blocks stay cached
+        for path in cold.iter() {
+            cache.get(path).unwrap();
+        }
+        assert_eq!(blocks.stats().pinned_usage, pinned_usage);
+        {
+            let state = cache.lock();
+            assert_eq!(state.tables.len(), 2);
+            assert!(hot.iter().all(|path| state.tables.contains_key(path)));
+        }
+
+        // Unpinned, they are evicted like any other table
+        blocks.set_pinned("hot", false);
+        cache.get(&cold[0]).unwrap();
+        let state = cache.lock();
+        assert_eq!(state.tables.len(), 2);
+        assert!(!state.tables.contains_key(&hot[0]));
+        assert!(state.tables.contains_key(&cold[0]));
+    }
+
+    #[test]
+    fn test_blocks_are_read_once_then_cached() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let path = write_tables(dir.path(), 1).remove(0);
+        let blocks = Arc::new(BlockCache::new(1 << 20));
+        let table = Table::open(&path, blocks.clone()).unwrap();
+        assert_eq!(blocks.stats().index.misses, 1);
+
+        let id = table.vector_columns().unwrap().ids()[5];
+        let first = table.get(id).unwrap();
+        let second = table.get(id).unwrap();
+        let (Record::Live(first), Record::Live(second)) = (first, second) else {
+            panic!("Expected a live document");
+        };
+        assert!(Arc::ptr_eq(&first, &second));
+        let stats = blocks.stats();
+        assert_eq!(stats.documents.misses, 1);
+        assert_eq!(stats.documents.hits, 1);
+        assert_eq!(stats.index.hits, 3);
+
+        // Whole-file reads that skip the cache leave it as it was
+        table.scan(false).unwrap();
+        assert_eq!(blocks.stats().entries, stats.entries);
+
+        // A closed table takes its blocks with it
+        drop(table);
+        assert_eq!(blocks.stats().entries, 0);
+        assert_eq!(blocks.stats().usage, 0);
+    }
+}
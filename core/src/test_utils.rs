@@ -8,6 +8,8 @@ pub fn random_document(dim: usize) -> Document {
         id: Uuid::new_v4().as_u128(),
         vector: (0..dim).map(|_| rng.random_range(-1.0..1.0)).collect(),
         content: "test".to_string(),
+        payload: Default::default(),
+        external_id: None,
     }
 }
 
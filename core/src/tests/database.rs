@@ -1,5 +1,13 @@
 use crate::AetherDB;
+use crate::CollectionError;
+use crate::DatabaseOptions;
+use crate::Document;
+use crate::ExternalId;
 use crate::collection::IndexConfig;
+use crate::test_utils::bulk_random_documents;
+use crate::{Filter, Payload, WalSyncMode};
+use std::time::Duration;
+use tempfile::tempdir;
 
 #[test]
 fn test_create_collection() {
@@ -16,3 +24,449 @@ fn test_create_collection() {
         .expect("Collection should exist");
     std::fs::remove_dir_all(test_path).ok();
 }
+
+#[test]
+fn test_reopen_restores_collections_and_replays_wal() {
+    let dir = tempdir().expect("Failed to create temp dir");
+    let test_path = dir.path().to_str().unwrap();
+    let test_collection = "test_collection";
+
+    let documents = bulk_random_documents(16, 20);
+    {
+        let db = AetherDB::new(test_path).unwrap();
+        let collection = db
+            .create_collection(test_collection, 16, "l2", IndexConfig::default())
+            .unwrap();
+        for document in documents.iter() {
+            collection
+                .write()
+                .unwrap()
+                .upsert(document.clone())
+                .unwrap();
+        }
+    }
+
+    let db = AetherDB::new(test_path).unwrap();
+    let collection = db
+        .get_collection(test_collection)
+        .expect("Collection should be restored from the catalog");
+    for document in documents.iter() {
+        let fetched = collection
+            .read()
+            .unwrap()
+            .fetch(&document.id)
+            .unwrap()
+            .expect("Document should survive a restart");
+        assert_eq!(fetched.vector, document.vector);
+    }
+}
+
+#[test]
+fn test_delete_survives_reopen() {
+    let dir = tempdir().expect("Failed to create temp dir");
+    let test_path = dir.path().to_str().unwrap();
+    let test_collection = "test_collection";
+
+    let documents = bulk_random_documents(8, 10);
+    {
+        let db = AetherDB::new(test_path).unwrap();
+        let collection = db
+            .create_collection(test_collection, 8, "l2", IndexConfig::default())
+            .unwrap();
+        let collection = collection.write().unwrap();
+        for document in documents.iter() {
+            collection.upsert(document.clone()).unwrap();
+        }
+        collection.delete(&documents[0].id).unwrap();
+        assert!(collection.fetch(&documents[0].id).unwrap().is_none());
+    }
+
+    let db = AetherDB::new(test_path).unwrap();
+    let collection = db.get_collection(test_collection).unwrap();
+    let collection = collection.read().unwrap();
+    assert!(collection.fetch(&documents[0].id).unwrap().is_none());
+    assert!(collection.fetch(&documents[1].id).unwrap().is_some());
+
+    let result = collection.search(&documents[0].vector, 10).unwrap();
+    assert_eq!(result.len(), 9);
+    assert!(result.iter().all(|r| r.document.id != documents[0].id));
+}
+
+#[test]
+fn test_create_existing_collection_fails() {
+    let dir = tempdir().expect("Failed to create temp dir");
+    let test_path = dir.path().to_str().unwrap();
+
+    {
+        let db = AetherDB::new(test_path).unwrap();
+        db.create_collection("a", 4, "dot", IndexConfig::default())
+            .unwrap();
+        assert!(matches!(
+            db.create_collection("a", 4, "dot", IndexConfig::default()),
+            Err(CollectionError::AlreadyExists(_))
+        ));
+    }
+
+    let db = AetherDB::new(test_path).unwrap();
+    assert!(matches!(
+        db.create_collection("a", 8, "l2", IndexConfig::default()),
+        Err(CollectionError::AlreadyExists(_))
+    ));
+}
+
+#[test]
+fn test_close_while_a_reader_holds_the_collection() {
+    let dir = tempdir().expect("Failed to create temp dir");
+    let test_path = dir.path().to_str().unwrap();
+
+    let documents = bulk_random_documents(8, 20);
+    let db = AetherDB::new(test_path).unwrap();
+    let collection = db
+        .create_collection("test", 8, "l2", IndexConfig::default())
+        .unwrap();
+    let guard = collection.read().unwrap();
+    guard.upsert_batch(documents.clone()).unwrap();
+
+    db.close().unwrap();
+    assert!(matches!(
+        guard.upsert(documents[0].clone()),
+        Err(CollectionError::Closed(_))
+    ));
+    drop(guard);
+
+    let reopened = AetherDB::new(test_path).unwrap();
+    let collection = reopened.get_collection("test").unwrap();
+    let results = collection
+        .read()
+        .unwrap()
+        .search(&documents[0].vector, 1)
+        .unwrap();
+    assert_eq!(results[0].document.id, documents[0].id);
+}
+
+#[test]
+fn test_failed_create_releases_the_name() {
+    let dir = tempdir().expect("Failed to create temp dir");
+    let test_path = dir.path().to_str().unwrap();
+
+    // A damaged SST left behind under the name fails the collection's recovery
+    let layer_path = dir.path().join("data").join("a").join("L0");
+    std::fs::create_dir_all(&layer_path).unwrap();
+    std::fs::write(layer_path.join("000001.sst"), vec![7u8; 100]).unwrap();
+
+    {
+        let db = AetherDB::new(test_path).unwrap();
+        assert!(
+            db.create_collection("a", 4, "l2", IndexConfig::default())
+                .is_err()
+        );
+        assert!(matches!(
+            db.get_collection("a"),
+            Err(CollectionError::NotFound(_))
+        ));
+        db.create_collection("b", 4, "l2", IndexConfig::default())
+            .unwrap();
+    }
+
+    // Nothing of the failed create survives a restart
+    let db = AetherDB::new(test_path).unwrap();
+    db.get_collection("b").unwrap();
+    std::fs::remove_file(layer_path.join("000001.sst")).unwrap();
+    db.create_collection("a", 4, "l2", IndexConfig::default())
+        .unwrap();
+}
+
+#[test]
+fn test_collection_failing_recovery_does_not_block_the_others() {
+    let dir = tempdir().expect("Failed to create temp dir");
+    let test_path = dir.path().to_str().unwrap();
+
+    {
+        let db = AetherDB::new(test_path).unwrap();
+        for name in ["a", "b"] {
+            db.create_collection(name, 4, "l2", IndexConfig::default())
+                .unwrap();
+        }
+    }
+    let layer_path = dir.path().join("data").join("a").join("L0");
+    std::fs::create_dir_all(&layer_path).unwrap();
+    std::fs::write(layer_path.join("000001.sst"), vec![7u8; 100]).unwrap();
+
+    {
+        let db = AetherDB::new(test_path).unwrap();
+        db.get_collection("b").unwrap();
+        assert!(matches!(
+            db.get_collection("a"),
+            Err(CollectionError::NotFound(_))
+        ));
+        // Still in the catalog, its files are left for the next open
+        assert!(matches!(
+            db.create_collection("a", 4, "l2", IndexConfig::default()),
+            Err(CollectionError::AlreadyExists(_))
+        ));
+    }
+
+    std::fs::remove_file(layer_path.join("000001.sst")).unwrap();
+    let db = AetherDB::new(test_path).unwrap();
+    db.get_collection("a").unwrap();
+}
+
+#[test]
+fn test_small_memtables_flush_and_compact_in_background() {
+    let dir = tempdir().expect("Failed to create temp dir");
+    let test_path = dir.path().to_str().unwrap();
+
+    let documents = bulk_random_documents(8, 200);
+    {
+        let options = DatabaseOptions {
+            memtable_size: 10,
+            ..Default::default()
+        };
+        let db = AetherDB::with_options(test_path, options).unwrap();
+        let collection = db
+            .create_collection(
+                "test",
+                8,
+                "l2",
+                IndexConfig::new_with_default_config("flat").unwrap(),
+            )
+            .unwrap();
+        let collection = collection.write().unwrap();
+        for document in documents.iter() {
+            collection.upsert(document.clone()).unwrap();
+        }
+        for document in documents.iter().step_by(2) {
+            collection.delete(&document.id).unwrap();
+        }
+
+        let stats = collection.write_stall_stats();
+        assert!(stats.frozen_memtables <= 4);
+        for (i, document) in documents.iter().enumerate() {
+            assert_eq!(
+                collection.fetch(&document.id).unwrap().is_some(),
+                i % 2 == 1
+            );
+        }
+        assert_eq!(
+            collection.search(&documents[1].vector, 200).unwrap().len(),
+            100
+        );
+    }
+}
+
+#[test]
+fn test_close_flushes_and_releases_lock() {
+    let dir = tempdir().expect("Failed to create temp dir");
+    let test_path = dir.path().to_str().unwrap();
+
+    let documents = bulk_random_documents(8, 20);
+    let db = AetherDB::new(test_path).unwrap();
+    let collection = db
+        .create_collection("test", 8, "l2", IndexConfig::default())
+        .unwrap();
+    for document in documents.iter() {
+        collection
+            .write()
+            .unwrap()
+            .upsert(document.clone())
+            .unwrap();
+    }
+
+    db.close().unwrap();
+    db.close().unwrap();
+
+    // The active memtable went to L0 and every WAL segment behind it is gone
+    let l0 = std::path::Path::new(test_path).join("data/test/L0");
+    assert_eq!(std::fs::read_dir(&l0).unwrap().count(), 1);
+    let wal = std::path::Path::new(test_path).join("wal");
+    assert_eq!(std::fs::read_dir(&wal).unwrap().count(), 1);
+    assert!(matches!(
+        collection.write().unwrap().upsert(documents[0].clone()),
+        Err(CollectionError::Closed(_))
+    ));
+    assert!(matches!(
+        db.create_collection("other", 8, "l2", IndexConfig::default()),
+        Err(CollectionError::Closed(_))
+    ));
+
+    // The lock is released even though the old instance is still alive
+    let reopened = AetherDB::new(test_path).unwrap();
+    let collection = reopened.get_collection("test").unwrap();
+    for document in documents.iter() {
+        assert!(
+            collection
+                .read()
+                .unwrap()
+                .fetch(&document.id)
+                .unwrap()
+                .is_some()
+        );
+    }
+}
+
+#[test]
+fn test_filtered_search_after_reopen() {
+    let dir = tempdir().expect("Failed to create temp dir");
+    let test_path = dir.path().to_str().unwrap();
+
+    let documents: Vec<Document> = bulk_random_documents(8, 25)
+        .into_iter()
+        .enumerate()
+        .map(|(i, document)| {
+            document.with_payload(Payload::from([
+                ("rank".to_string(), (i as i64).into()),
+                ("even".to_string(), (i % 2 == 0).into()),
+            ]))
+        })
+        .collect();
+    {
+        let options = DatabaseOptions {
+            memtable_size: 10,
+            ..Default::default()
+        };
+        let db = AetherDB::with_options(test_path, options).unwrap();
+        let collection = db
+            .create_collection("test", 8, "l2", IndexConfig::default())
+            .unwrap();
+        for document in documents.iter() {
+            collection
+                .write()
+                .unwrap()
+                .upsert(document.clone())
+                .unwrap();
+        }
+    }
+
+    let db = AetherDB::new(test_path).unwrap();
+    let collection = db.get_collection("test").unwrap();
+    let collection = collection.read().unwrap();
+    let filter = Filter::and(vec![Filter::eq("even", true), Filter::gte("rank", 10)]);
+    let result = collection
+        .search_with_filter(&documents[0].vector, 25, &filter)
+        .unwrap();
+
+    let mut ranks = Vec::new();
+    for hit in result.iter() {
+        let rank = documents
+            .iter()
+            .position(|d| d.id == hit.document.id)
+            .unwrap();
+        assert_eq!(hit.document.payload, documents[rank].payload);
+        ranks.push(rank);
+    }
+    ranks.sort_unstable();
+    assert_eq!(ranks, vec![10, 12, 14, 16, 18, 20, 22, 24]);
+}
+
+#[test]
+fn test_external_ids_survive_flush_and_reopen() {
+    let dir = tempdir().expect("Failed to create temp dir");
+    let test_path = dir.path().to_str().unwrap();
+
+    let sku = |i: i32, x: f32| {
+        Document::with_external_id(format!("sku-{}", i), vec![x; 4], i.to_string())
+    };
+    {
+        let options = DatabaseOptions {
+            memtable_size: 4,
+            ..Default::default()
+        };
+        let db = AetherDB::with_options(test_path, options).unwrap();
+        let collection = db
+            .create_collection("test", 4, "l2", IndexConfig::default())
+            .unwrap();
+        let collection = collection.write().unwrap();
+        for i in 0..10 {
+            collection.upsert(sku(i, i as f32)).unwrap();
+        }
+        collection
+            .upsert(Document::with_external_id(
+                7u64,
+                vec![50.0; 4],
+                "row".to_string(),
+            ))
+            .unwrap();
+
+        // sku-3 is already on disk, the upsert replaces it rather than adding a copy
+        collection.upsert(sku(3, 100.0)).unwrap();
+        collection
+            .delete_by_external_id(&ExternalId::from("sku-5"))
+            .unwrap();
+    }
+
+    let db = AetherDB::new(test_path).unwrap();
+    let collection = db.get_collection("test").unwrap();
+    let collection = collection.read().unwrap();
+
+    let sku_3 = collection
+        .fetch_by_external_id(&ExternalId::from("sku-3"))
+        .unwrap()
+        .unwrap();
+    assert_eq!(sku_3.vector, vec![100.0; 4]);
+    assert_eq!(sku_3.external_id, Some(ExternalId::from("sku-3")));
+    assert!(
+        collection
+            .fetch_by_external_id(&ExternalId::from("sku-5"))
+            .unwrap()
+            .is_none()
+    );
+    let row = collection
+        .fetch_by_external_id(&ExternalId::from(7))
+        .unwrap()
+        .unwrap();
+    assert_eq!(row.content, "row");
+
+    let result = collection.search(&[0.0; 4], 20).unwrap();
+    let mut keys: Vec<String> = result
+        .iter()
+        .map(|hit| hit.document.external_id.as_ref().unwrap().to_string())
+        .collect();
+    keys.sort_unstable();
+    assert_eq!(
+        keys,
+        [
+            "7", "sku-0", "sku-1", "sku-2", "sku-3", "sku-4", "sku-6", "sku-7", "sku-8", "sku-9"
+        ]
+    );
+}
+
+#[test]
+fn test_concurrent_writers_with_group_commit() {
+    let dir = tempdir().expect("Failed to create temp dir");
+    let test_path = dir.path().to_str().unwrap();
+
+    let documents = bulk_random_documents(8, 200);
+    {
+        let options = DatabaseOptions {
+            memtable_size: 64,
+            wal_sync: WalSyncMode::GroupCommit {
+                max_delay: Duration::from_millis(2),
+                max_batch: 4,
+            },
+            ..Default::default()
+        };
+        let db = AetherDB::with_options(test_path, options).unwrap();
+        let collection = db
+            .create_collection("test", 8, "l2", IndexConfig::default())
+            .unwrap();
+
+        // Writers only share the collection's read lock
+        std::thread::scope(|scope| {
+            for chunk in documents.chunks(50) {
+                let collection = &collection;
+                scope.spawn(move || {
+                    for document in chunk {
+                        collection.read().unwrap().upsert(document.clone()).unwrap();
+                    }
+                });
+            }
+        });
+    }
+
+    let db = AetherDB::new(test_path).unwrap();
+    let collection = db.get_collection("test").unwrap();
+    let collection = collection.read().unwrap();
+    for document in documents.iter() {
+        assert!(collection.fetch(&document.id).unwrap().is_some());
+    }
+}
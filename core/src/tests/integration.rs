@@ -1,7 +1,7 @@
 use uuid::Uuid;
 
 use crate::IndexConfig;
-use crate::test_utils::random_document;
+use crate::test_utils::{bulk_random_documents, random_document};
 use crate::tests::utils::TestDb;
 
 #[test]
@@ -40,14 +40,14 @@ fn test_fetch_documents() -> Result<(), Box<dyn std::error::Error>> {
     )?;
 
     collection.write().unwrap().upsert(test_document)?;
-    if collection.read().unwrap().fetch(&test_id).is_none() {
+    if collection.read().unwrap().fetch(&test_id)?.is_none() {
         return Err("Document not found".into());
     }
 
     if collection
         .write()
         .unwrap()
-        .fetch(&Uuid::new_v4().as_u128())
+        .fetch(&Uuid::new_v4().as_u128())?
         .is_some()
     {
         return Err("Document should not be found".into());
@@ -56,4 +56,30 @@ fn test_fetch_documents() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+#[test]
+fn test_search_documents() -> Result<(), Box<dyn std::error::Error>> {
+    let test_dimension = 32;
+    let test_db = TestDb::new("test_search").unwrap();
+
+    let collection = test_db.db.create_collection(
+        "abcde",
+        test_dimension,
+        "cosine",
+        IndexConfig::new_with_default_config("flat")?,
+    )?;
+
+    let documents = bulk_random_documents(test_dimension as usize, 100);
+    let query = documents[42].vector.clone();
+    let query_id = documents[42].id;
+    for document in documents {
+        collection.write().unwrap().upsert(document)?;
+    }
+
+    let results = collection.read().unwrap().search(&query, 5)?;
+    assert_eq!(results.len(), 5);
+    assert_eq!(results[0].document.id, query_id);
+
+    Ok(())
+}
+
 // Add multi thread tests
@@ -1,10 +1,17 @@
-use bincode;
 use std::fmt;
-use std::io::{BufReader, BufWriter, Write};
-use std::path::PathBuf;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use crate::Document;
 use crate::WalError;
+use crate::checksum::{crc32c, crc32c_append};
+use crate::document::{Document, Record};
+use crate::options::{WalRecoveryPolicy, WalSyncMode};
+use crate::sst;
+use crossbeam_channel::{RecvTimeoutError, Sender, bounded};
 use fs2::FileExt;
 use serde::{Deserialize, Serialize};
 use std::fs::File;
@@ -13,6 +20,7 @@ use std::fs::File;
 pub enum Operation {
     Insert,
     Delete,
+    Batch,
 }
 
 impl fmt::Display for Operation {
@@ -20,89 +28,698 @@ impl fmt::Display for Operation {
         match self {
             Operation::Insert => write!(f, "insert"),
             Operation::Delete => write!(f, "delete"),
+            Operation::Batch => write!(f, "batch"),
         }
     }
 }
 
 const INITIAL_SEQ_NO: u64 = 0;
 const WAL_FILE_SIZE: u64 = 50 * 1024 * 1024; // 50 MB
+const WAL_EXTENSION: &str = "wal";
+const WAL_MAGIC: u32 = 0x4C41_5741; // "AWAL"
+const WAL_VERSION: u32 = 1;
+const SEGMENT_HEADER_SIZE: usize = 16;
+const FRAME_HEADER_SIZE: usize = 16;
+
+/*
+Segment layout:
+- header: magic (u32), format version (u32) and the segment's seq_no (u64)
+- frames, one per record:
+  - crc:     4 bytes, CRC32C of everything after it up to the end of the payload
+  - length:  4 bytes, size of the payload
+  - seq:     8 bytes, number of the record within the segment, starting at 1
+  - payload: bincode encoded Operation followed by the Document for an insert,
+             the u128 id for a delete or the Vec<Document> of a batch
+All integers are little-endian.
+
+A batch is a single record, a crash either keeps all of its documents or none.
+
+Segments that do not start with the magic are version 0, written before the header
+existed: bincode encoded (Operation, Document) pairs back to back, with the Document
+of that time and no framing. They are still replayed, never written.
+
+Segments are preallocated on their first record and therefore zero filled past the
+last record. Recovery reads frames until only zeros are left. A damaged frame with no
+valid frame after it is a torn tail, a write cut short by a crash, and is truncated
+unless the policy is strict. A damaged frame followed by valid ones is corruption: recovery refuses it
+unless the policy skips corrupted records, which are then reported.
+*/
+
+/*
+Durability
+
+Appending a record only hands it to the OS, `wait_durable` then makes it durable as the
+WalSyncMode asks. Records are numbered in append order, `synced` is the highest number
+known to be on disk. Rotation fsyncs the segment it leaves, so a single fsync of the
+current segment covers every record appended so far.
+
+With group commit the first writer to wait becomes the leader: it lets the window fill
+up, fsyncs once for everyone appended meanwhile, and wakes the writers it covered.
+Writers that arrive during the fsync wait for the next leader.
+
+A failed write or fsync is permanent. After a failed fsync the kernel may have dropped
+the dirty pages, and the next fsync succeeding says nothing about them: moving `synced`
+past them would acknowledge records that are lost. Every later append, fsync and wait
+fails instead, until the WAL is reopened and replayed.
+*/
+
+struct Segment {
+    seq_no: u64,
+    next_record: u64,
+    allocated: bool, // on the first append, an idle segment stays header sized
+    file: BufWriter<File>,
+    handle: Arc<File>, // same file, fsynced without holding the segment lock
+}
+
+impl Segment {
+    fn create(fpath: &Path, name: &str, seq_no: u64) -> Result<Self, WalError> {
+        let file = File::create(segment_path(fpath, name, seq_no))?;
+        let mut segment = Segment {
+            seq_no,
+            next_record: 1,
+            allocated: false,
+            handle: Arc::new(file.try_clone()?),
+            file: BufWriter::with_capacity(65536, file),
+        };
+
+        let mut header = [0u8; SEGMENT_HEADER_SIZE];
+        header[0..4].copy_from_slice(&WAL_MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&WAL_VERSION.to_le_bytes());
+        header[8..16].copy_from_slice(&seq_no.to_le_bytes());
+        segment.file.write_all(&header)?;
+        segment.file.flush()?;
+        Ok(segment)
+    }
+
+    fn append(&mut self, payload: &[u8]) -> std::io::Result<()> {
+        if !self.allocated {
+            self.handle.allocate(WAL_FILE_SIZE)?;
+            self.allocated = true;
+        }
+
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+        header[4..8].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+        header[8..16].copy_from_slice(&self.next_record.to_le_bytes());
+        let crc = crc32c_append(crc32c(&header[4..]), payload);
+        header[0..4].copy_from_slice(&crc.to_le_bytes());
+
+        self.file.write_all(&header)?;
+        self.file.write_all(payload)?;
+        self.next_record += 1;
+        Ok(())
+    }
+}
+
+/// A damaged tail cut off a recovered segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TornTail {
+    pub segment: PathBuf,
+    pub offset: u64, // the segment now ends here
+}
+
+/// Corrupted bytes of a segment that recovery skipped over.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedRange {
+    pub segment: PathBuf,
+    pub offset: u64,
+    pub len: u64,
+    pub records: Option<u64>, // unknown when the segment header itself is damaged
+}
+
+/// What `replay_with_report` repaired or skipped, empty for a clean WAL.
+#[derive(Debug, Clone, Default)]
+pub struct WalRecoveryReport {
+    pub torn_tails: Vec<TornTail>,
+    pub skipped: Vec<SkippedRange>,
+}
+
+impl WalRecoveryReport {
+    pub fn is_clean(&self) -> bool {
+        self.torn_tails.is_empty() && self.skipped.is_empty()
+    }
+}
+
+#[derive(Default)]
+struct Durability {
+    synced: u64,
+    syncing: bool,          // a leader is running an fsync
+    failed: Option<String>, // the first write or fsync that failed, see Durability above
+}
+
+struct WalShared {
+    segment: Mutex<Segment>,
+    appended: AtomicU64, // number of the last appended record
+    durability: Mutex<Durability>,
+    durability_changed: Condvar,
+    syncs: AtomicU64, // fsyncs issued, shows how well group commit coalesces
+}
+
+impl WalShared {
+    fn lock_segment(&self) -> MutexGuard<'_, Segment> {
+        self.segment.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn lock_durability(&self) -> MutexGuard<'_, Durability> {
+        self.durability
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Fsyncs everything appended so far and publishes it as durable.
+    fn sync(&self) -> Result<(), WalError> {
+        self.check_failed()?;
+        let (upto, handle) = {
+            let mut segment = self.lock_segment();
+            self.track(segment.file.flush())?;
+            (self.appended.load(Ordering::SeqCst), segment.handle.clone())
+        };
+        self.track(handle.sync_data())?;
+        self.syncs.fetch_add(1, Ordering::Relaxed);
+        self.mark_synced(upto);
+        Ok(())
+    }
+
+    /// Marks the WAL as failed if `result` is an error.
+    fn track<T>(&self, result: std::io::Result<T>) -> Result<T, WalError> {
+        result.map_err(|e| {
+            self.lock_durability()
+                .failed
+                .get_or_insert_with(|| e.to_string());
+            self.durability_changed.notify_all();
+            e.into()
+        })
+    }
+
+    fn check_failed(&self) -> Result<(), WalError> {
+        match &self.lock_durability().failed {
+            Some(reason) => Err(WalError::WriteError(Some(format!(
+                "WAL failed earlier: {}",
+                reason
+            )))),
+            None => Ok(()),
+        }
+    }
+
+    fn mark_synced(&self, upto: u64) {
+        let mut durability = self.lock_durability();
+        durability.synced = durability.synced.max(upto);
+        self.durability_changed.notify_all();
+    }
+
+    /// Leader/follower group commit, returns once record `lsn` is on disk.
+    fn group_commit(
+        &self,
+        lsn: u64,
+        max_delay: Duration,
+        max_batch: usize,
+    ) -> Result<(), WalError> {
+        let mut durability = self.lock_durability();
+        loop {
+            if durability.synced >= lsn {
+                return Ok(());
+            }
+            if durability.failed.is_some() {
+                drop(durability);
+                return self.check_failed();
+            }
+            if durability.syncing {
+                durability = self
+                    .durability_changed
+                    .wait(durability)
+                    .unwrap_or_else(PoisonError::into_inner);
+                continue;
+            }
+
+            durability.syncing = true;
+            let deadline = Instant::now() + max_delay;
+            loop {
+                let waiting = self.appended.load(Ordering::SeqCst) - durability.synced;
+                let now = Instant::now();
+                if waiting >= max_batch as u64 || now >= deadline {
+                    break;
+                }
+                durability = self
+                    .durability_changed
+                    .wait_timeout(durability, deadline - now)
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .0;
+            }
+            drop(durability);
+
+            let result = self.sync();
+            durability = self.lock_durability();
+            durability.syncing = false;
+            self.durability_changed.notify_all();
+            result?;
+        }
+    }
+}
 
 pub struct WalManager {
     fpath: PathBuf,
     name: String,
-    seq_no: u64,
-    file: BufWriter<File>,
+    sync_mode: WalSyncMode,
+    recovery_policy: WalRecoveryPolicy,
+    shared: Arc<WalShared>,
+    syncer: Option<(Sender<()>, JoinHandle<()>)>, // only for `WalSyncMode::Interval`
+    recovered_seq_nos: Vec<u64>, // segments found on disk when the manager was opened
 }
 
 impl WalManager {
-    pub fn new(fpath: &PathBuf, name: &str) -> Result<Self, WalError> {
+    pub fn new(fpath: &Path, name: &str) -> Result<Self, WalError> {
+        Self::with_sync_mode(fpath, name, WalSyncMode::default())
+    }
+
+    /// Opens the WAL of a collection. Existing segments are kept for `replay` and writes
+    /// continue in a fresh segment after the newest one, or in the newest one itself if it
+    /// holds no records, so that reopening an idle collection leaves nothing behind.
+    pub fn with_sync_mode(
+        fpath: &Path,
+        name: &str,
+        sync_mode: WalSyncMode,
+    ) -> Result<Self, WalError> {
         let fpath = fpath.join("wal");
+        std::fs::create_dir_all(&fpath)?;
 
-        // TODO: implement WAL recovery mechanism
+        let recovered_seq_nos = list_segments(&fpath, name)?;
+        let seq_no = match recovered_seq_nos.last() {
+            Some(last) if holds_no_records(&segment_path(&fpath, name, *last), *last)? => *last,
+            Some(last) => last + 1,
+            None => INITIAL_SEQ_NO,
+        };
 
-        std::fs::create_dir_all(&fpath)?;
-        let file =
-            std::fs::File::create(fpath.join(format!("{}_{:09}.wal", name, INITIAL_SEQ_NO)))?;
-        file.allocate(WAL_FILE_SIZE)?;
+        let shared = Arc::new(WalShared {
+            segment: Mutex::new(Segment::create(&fpath, name, seq_no)?),
+            appended: AtomicU64::new(0),
+            durability: Mutex::new(Durability::default()),
+            durability_changed: Condvar::new(),
+            syncs: AtomicU64::new(0),
+        });
+        let syncer = match sync_mode {
+            WalSyncMode::Interval(interval) => Some(spawn_syncer(shared.clone(), interval)),
+            _ => None,
+        };
         Ok(WalManager {
-            fpath: fpath,
+            fpath,
             name: name.to_string(),
-            seq_no: INITIAL_SEQ_NO,
-            file: BufWriter::with_capacity(65536, file),
+            sync_mode,
+            recovery_policy: WalRecoveryPolicy::default(),
+            shared,
+            syncer,
+            recovered_seq_nos,
         })
     }
 
-    pub fn write(&mut self, op: Operation, data: &Document) -> Result<(), WalError> {
-        bincode::serialize_into(&mut self.file, &(op, data))?;
+    /// How `replay` deals with damaged records.
+    pub fn with_recovery_policy(mut self, policy: WalRecoveryPolicy) -> Self {
+        self.recovery_policy = policy;
+        self
+    }
 
-        // Flush to at least OS level
-        self.file.flush()?;
-        // Ensure data is on disk
-        self.file.get_ref().sync_data()?;
-        Ok(())
+    /// The write_* methods append a record and return its number, it is durable once
+    /// `wait_durable` returns for it.
+    pub fn write_insert(&self, document: &Document) -> Result<u64, WalError> {
+        self.write(&bincode::serialize(&(Operation::Insert, document))?)
     }
 
-    pub fn read(&self) -> Result<Vec<(Operation, Document)>, WalError> {
-        let mut records = Vec::new();
-        let file = File::open(
-            self.fpath
-                .join(format!("{}_{:09}.wal", self.name, self.seq_no)),
-        )?;
-        let mut reader = BufReader::new(file);
+    pub fn write_delete(&self, id: &u128) -> Result<u64, WalError> {
+        self.write(&bincode::serialize(&(Operation::Delete, id))?)
+    }
 
-        loop {
-            match bincode::deserialize_from(&mut reader) {
-                Ok(record) => records.push(record),
-                Err(_) => break,
+    /// Logs `documents` as one record, so they are replayed all together or not at all.
+    pub fn write_batch(&self, documents: &[Document]) -> Result<u64, WalError> {
+        self.write(&bincode::serialize(&(Operation::Batch, documents))?)
+    }
+
+    fn write(&self, payload: &[u8]) -> Result<u64, WalError> {
+        self.shared.check_failed()?;
+        let mut segment = self.shared.lock_segment();
+        self.shared.track(segment.append(payload))?;
+        // Flush to at least OS level, a process crash loses nothing from here on
+        self.shared.track(segment.file.flush())?;
+        let lsn = self.shared.appended.fetch_add(1, Ordering::SeqCst) + 1;
+        drop(segment);
+
+        if let WalSyncMode::GroupCommit { .. } = self.sync_mode {
+            // A leader may be waiting for its window to fill up
+            self.shared.durability_changed.notify_all();
+        }
+        Ok(lsn)
+    }
+
+    /// Waits until record `lsn` is as durable as the sync mode promises. Callers should not
+    /// hold locks other writers need, so that their fsyncs can be shared.
+    ///
+    /// Once a write or fsync failed, fails for good for every record not synced before.
+    pub fn wait_durable(&self, lsn: u64) -> Result<(), WalError> {
+        match self.sync_mode {
+            WalSyncMode::Always => {
+                if self.shared.lock_durability().synced < lsn {
+                    self.shared.sync()?;
+                }
+                Ok(())
             }
+            WalSyncMode::GroupCommit {
+                max_delay,
+                max_batch,
+            } => self.shared.group_commit(lsn, max_delay, max_batch),
+            WalSyncMode::Interval(_) | WalSyncMode::Never => Ok(()),
         }
+    }
 
-        Ok(records)
+    /// Records of the segment being written.
+    pub fn read(&self) -> Result<Vec<Record>, WalError> {
+        let seq_no = self.get_seq_no();
+        let path = segment_path(&self.fpath, &self.name, seq_no);
+        Ok(read_segment(&path, seq_no, self.recovery_policy)?.records)
     }
 
-    pub fn rotate(&mut self) -> Result<(), WalError> {
-        self.file.flush()?;
-        self.file.get_ref().sync_data()?;
+    /// Records of every recovered segment with `seq_no >= from_seq_no`, oldest first.
+    /// Segments below `from_seq_no` are already covered by SSTs.
+    pub fn replay(&self, from_seq_no: u64) -> Result<Vec<Record>, WalError> {
+        Ok(self.replay_with_report(from_seq_no)?.0)
+    }
+
+    /// Like `replay`, and also tells which damaged records the recovery policy let
+    /// through. Torn tails are truncated so that the next recovery finds a clean end.
+    pub fn replay_with_report(
+        &self,
+        from_seq_no: u64,
+    ) -> Result<(Vec<Record>, WalRecoveryReport), WalError> {
+        let mut records = Vec::new();
+        let mut report = WalRecoveryReport::default();
+        for seq_no in self.recovered_seq_nos.iter().filter(|s| **s >= from_seq_no) {
+            let path = segment_path(&self.fpath, &self.name, *seq_no);
+            let scan = read_segment(&path, *seq_no, self.recovery_policy)?;
+            records.extend(scan.records);
+            report.skipped.extend(scan.skipped);
+
+            if let Some(offset) = scan.torn_at {
+                let file = File::options().write(true).open(&path)?;
+                file.set_len(offset as u64)?;
+                file.sync_all()?;
+                report.torn_tails.push(TornTail {
+                    segment: path,
+                    offset: offset as u64,
+                });
+            }
+        }
+        Ok((records, report))
+    }
 
-        self.seq_no = self.seq_no + 1;
-        let file = std::fs::File::create(
-            self.fpath
-                .join(format!("{}_{:09}.wal", self.name, self.seq_no)),
-        )?;
+    /// Moves on to a new segment, the one left behind is fsynced whatever the sync mode:
+    /// an SST will soon claim to cover it.
+    pub fn rotate(&self) -> Result<(), WalError> {
+        self.shared.check_failed()?;
+        let upto = {
+            let mut segment = self.shared.lock_segment();
+            self.shared.track(segment.file.flush())?;
+            self.shared.track(segment.handle.sync_data())?;
+            self.shared.syncs.fetch_add(1, Ordering::Relaxed);
+            let upto = self.shared.appended.load(Ordering::SeqCst);
 
-        self.file = BufWriter::with_capacity(65536, file);
+            let seq_no = segment.seq_no + 1;
+            *segment = Segment::create(&self.fpath, &self.name, seq_no)?;
+            upto
+        };
+        self.shared.mark_synced(upto);
         Ok(())
     }
 
+    pub fn sync(&self) -> Result<(), WalError> {
+        self.shared.sync()
+    }
+
+    /// Why the WAL refuses writes, `None` while it works. See `wait_durable`.
+    pub fn failure(&self) -> Option<String> {
+        self.shared.lock_durability().failed.clone()
+    }
+
+    /// Fails the WAL the way a failed fsync would.
+    #[cfg(test)]
+    pub(crate) fn inject_failure(&self, reason: &str) {
+        self.shared
+            .track::<()>(Err(std::io::Error::other(reason)))
+            .ok();
+    }
+
+    /// Fsyncs issued since the WAL was opened.
+    pub fn sync_count(&self) -> u64 {
+        self.shared.syncs.load(Ordering::Relaxed)
+    }
+
     pub fn get_seq_no(&self) -> u64 {
-        self.seq_no
+        self.shared.lock_segment().seq_no
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.fpath
+    }
+}
+
+impl Drop for WalManager {
+    fn drop(&mut self) {
+        if let Some((stop, handle)) = self.syncer.take() {
+            drop(stop);
+            handle.join().ok();
+        }
     }
 }
 
+/// Background fsync every `interval` until the sender is dropped.
+fn spawn_syncer(shared: Arc<WalShared>, interval: Duration) -> (Sender<()>, JoinHandle<()>) {
+    let (stop, stopped) = bounded::<()>(0);
+    let handle = thread::spawn(move || {
+        while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+            if let Err(e) = shared.sync() {
+                eprintln!("WARN: background WAL sync failed: {}", e);
+            }
+        }
+        // Whatever the last interval appended
+        shared.sync().ok();
+    });
+    (stop, handle)
+}
+
+/// Deletes the segments of collection `name` below `seq_no`, an SST already holds their
+/// records. Returns how many segments were removed.
+pub fn remove_segments_below(fpath: &Path, name: &str, seq_no: u64) -> Result<usize, WalError> {
+    let mut removed = 0;
+    for segment in list_segments(fpath, name)?
+        .into_iter()
+        .filter(|s| *s < seq_no)
+    {
+        match std::fs::remove_file(segment_path(fpath, name, segment)) {
+            Ok(()) => removed += 1,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(removed)
+}
+
+fn segment_path(fpath: &Path, name: &str, seq_no: u64) -> PathBuf {
+    fpath.join(format!("{}_{:09}.{}", name, seq_no, WAL_EXTENSION))
+}
+
+/// Sequence numbers of the segments of collection `name`, ascending.
+fn list_segments(fpath: &Path, name: &str) -> Result<Vec<u64>, WalError> {
+    let prefix = format!("{}_", name);
+    let mut seq_nos = Vec::new();
+
+    for entry in std::fs::read_dir(fpath)? {
+        let file_name = entry?.file_name();
+        let Some(seq_no) = file_name
+            .to_str()
+            .and_then(|f| f.strip_prefix(&prefix))
+            .and_then(|f| f.strip_suffix(&format!(".{}", WAL_EXTENSION)))
+        else {
+            continue;
+        };
+
+        // Another collection whose name starts with `{name}_` would not parse here
+        if seq_no.len() == 9
+            && let Ok(seq_no) = seq_no.parse::<u64>()
+        {
+            seq_nos.push(seq_no);
+        }
+    }
+
+    seq_nos.sort_unstable();
+    Ok(seq_nos)
+}
+
+struct SegmentScan {
+    records: Vec<Record>,
+    torn_at: Option<usize>,
+    skipped: Vec<SkippedRange>,
+}
+
+fn read_segment(
+    path: &Path,
+    seq_no: u64,
+    policy: WalRecoveryPolicy,
+) -> Result<SegmentScan, WalError> {
+    let bytes = std::fs::read(path)?;
+    let mut scan = SegmentScan {
+        records: Vec::new(),
+        torn_at: None,
+        skipped: Vec::new(),
+    };
+    let corrupted = |offset: usize, what: &str| {
+        WalError::Corrupted(Some(format!(
+            "{} at offset {} of {}",
+            what,
+            offset,
+            path.display()
+        )))
+    };
+
+    // Created but the header never reached the disk
+    if is_zeroed(&bytes[..bytes.len().min(SEGMENT_HEADER_SIZE)]) {
+        return Ok(scan);
+    }
+    if bytes.len() < 4 || u32::from_le_bytes(bytes[0..4].try_into().unwrap()) != WAL_MAGIC {
+        scan.records = read_legacy_records(&bytes);
+        return Ok(scan);
+    }
+    if !valid_segment_header(&bytes, seq_no) {
+        if policy != WalRecoveryPolicy::SkipCorrupted {
+            return Err(corrupted(0, "invalid segment header"));
+        }
+        scan.skipped.push(SkippedRange {
+            segment: path.to_path_buf(),
+            offset: 0,
+            len: bytes.len() as u64,
+            records: None,
+        });
+        return Ok(scan);
+    }
+
+    let mut offset = SEGMENT_HEADER_SIZE;
+    let mut expected = 1;
+    while !is_zeroed(&bytes[offset..]) {
+        if let Some((seq, len, records)) = read_frame(&bytes, offset)
+            && seq == expected
+        {
+            scan.records.extend(records);
+            offset += len;
+            expected += 1;
+            continue;
+        }
+
+        let Some((next, seq)) = find_next_frame(&bytes, offset, expected) else {
+            if policy == WalRecoveryPolicy::Strict {
+                return Err(corrupted(offset, "torn record"));
+            }
+            scan.torn_at = Some(offset);
+            break;
+        };
+        if policy != WalRecoveryPolicy::SkipCorrupted {
+            return Err(corrupted(offset, "corrupted record"));
+        }
+        scan.skipped.push(SkippedRange {
+            segment: path.to_path_buf(),
+            offset: offset as u64,
+            len: (next - offset) as u64,
+            records: Some(seq - expected),
+        });
+        offset = next;
+        expected = seq;
+    }
+
+    Ok(scan)
+}
+
+/// Records of a version 0 segment. Like the reader of that format it stops at the first
+/// record that does not decode, nothing tells a torn tail from corruption there.
+fn read_legacy_records(bytes: &[u8]) -> Vec<Record> {
+    let mut records = Vec::new();
+    let mut rest = bytes;
+    while !is_zeroed(rest) {
+        match bincode::deserialize_from::<_, (Operation, sst::v1::Document)>(&mut rest) {
+            Ok((Operation::Insert, document)) => {
+                records.push(Record::Live(Arc::new(document.into())))
+            }
+            Ok((Operation::Delete, document)) => records.push(Record::Tombstone(document.id)),
+            // Batches came after the header
+            Ok((Operation::Batch, _)) | Err(_) => break,
+        }
+    }
+    records
+}
+
+/// Whether a segment is empty past its header, written to by nobody or never synced.
+fn holds_no_records(path: &Path, seq_no: u64) -> Result<bool, WalError> {
+    let bytes = std::fs::read(path)?;
+    let records = match valid_segment_header(&bytes, seq_no) {
+        true => &bytes[SEGMENT_HEADER_SIZE..],
+        false => &bytes[..],
+    };
+    Ok(is_zeroed(records))
+}
+
+fn is_zeroed(bytes: &[u8]) -> bool {
+    bytes.iter().all(|b| *b == 0)
+}
+
+fn valid_segment_header(bytes: &[u8], seq_no: u64) -> bool {
+    bytes.len() >= SEGMENT_HEADER_SIZE
+        && u32::from_le_bytes(bytes[0..4].try_into().unwrap()) == WAL_MAGIC
+        && u32::from_le_bytes(bytes[4..8].try_into().unwrap()) == WAL_VERSION
+        && u64::from_le_bytes(bytes[8..16].try_into().unwrap()) == seq_no
+}
+
+/// The frame at `offset` if it is whole and intact: its seq, its size and its records.
+fn read_frame(bytes: &[u8], offset: usize) -> Option<(u64, usize, Vec<Record>)> {
+    let header = bytes.get(offset..offset + FRAME_HEADER_SIZE)?;
+    let crc = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    let len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+    let seq = u64::from_le_bytes(header[8..16].try_into().unwrap());
+    if len == 0 {
+        return None;
+    }
+
+    let end = offset + FRAME_HEADER_SIZE + len;
+    let payload = bytes.get(offset + FRAME_HEADER_SIZE..end)?;
+    if crc32c_append(crc32c(&header[4..]), payload) != crc {
+        return None;
+    }
+    // Intact yet undecodable: written by something else, counts as corruption too
+    let records = decode_record(payload).ok()?;
+    Some((seq, end - offset, records))
+}
+
+/// First intact frame from `offset` on that comes after record `expected`, with its seq.
+fn find_next_frame(bytes: &[u8], offset: usize, expected: u64) -> Option<(usize, u64)> {
+    (offset..bytes.len().saturating_sub(FRAME_HEADER_SIZE)).find_map(|candidate| {
+        let seq = u64::from_le_bytes(bytes[candidate + 8..candidate + 16].try_into().unwrap());
+        if seq <= expected {
+            return None;
+        }
+        read_frame(bytes, candidate).map(|_| (candidate, seq))
+    })
+}
+
+fn decode_record(payload: &[u8]) -> Result<Vec<Record>, bincode::Error> {
+    let mut cursor = payload;
+    let op: Operation = bincode::deserialize_from(&mut cursor)?;
+    Ok(match op {
+        Operation::Insert => vec![Record::Live(Arc::new(bincode::deserialize(cursor)?))],
+        Operation::Delete => vec![Record::Tombstone(bincode::deserialize(cursor)?)],
+        Operation::Batch => bincode::deserialize::<Vec<Document>>(cursor)?
+            .into_iter()
+            .map(|document| Record::Live(Arc::new(document)))
+            .collect(),
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::payload::Payload;
     use std::path::PathBuf;
+    use tempfile::tempdir;
 
     fn get_test_path(name: &str) -> PathBuf {
         let path = PathBuf::from(name);
@@ -112,6 +729,13 @@ mod tests {
         path
     }
 
+    fn live(record: &Record) -> &Document {
+        match record {
+            Record::Live(d) => d,
+            Record::Tombstone(_) => panic!("Expected Insert"),
+        }
+    }
+
     fn cleanup(path: &PathBuf) {
         if path.exists() {
             let _ = std::fs::remove_dir_all(path);
@@ -123,23 +747,31 @@ mod tests {
         let path = get_test_path("./test_wal_rw");
 
         {
-            let mut wal = WalManager::new(&path, "test").unwrap();
-            let doc1 = Document::new(vec![1.0, 2.0], "doc1".to_string());
+            let wal = WalManager::new(&path, "test").unwrap();
+            let payload = Payload::from([
+                ("color".to_string(), "red".into()),
+                ("tags".to_string(), vec![1, 2].into()),
+            ]);
+            let doc1 =
+                Document::new(vec![1.0, 2.0], "doc1".to_string()).with_payload(payload.clone());
             let doc2 = Document::new(vec![3.0, 4.0], "doc2".to_string());
 
-            wal.write(Operation::Insert, &doc1).unwrap();
-            wal.write(Operation::Delete, &doc2).unwrap();
+            wal.write_insert(&doc1).unwrap();
+            wal.write_delete(&doc2.id).unwrap();
 
             let records = wal.read().unwrap();
             assert_eq!(records.len(), 2);
 
             match &records[0] {
-                (Operation::Insert, d) => assert_eq!(d.content, "doc1"),
+                Record::Live(d) => {
+                    assert_eq!(d.content, "doc1");
+                    assert_eq!(d.payload, payload);
+                }
                 _ => panic!("Expected Insert"),
             }
 
             match &records[1] {
-                (Operation::Delete, d) => assert_eq!(d.content, "doc2"),
+                Record::Tombstone(id) => assert_eq!(*id, doc2.id),
                 _ => panic!("Expected Delete"),
             }
         }
@@ -147,42 +779,415 @@ mod tests {
         cleanup(&path);
     }
 
+    #[test]
+    fn test_batch_is_replayed_whole_or_not_at_all() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let path = dir.path().to_path_buf();
+        let single = Document::new(vec![9.0], "single".to_string());
+        let batch: Vec<Document> = (0..3)
+            .map(|i| Document::new(vec![i as f32], format!("doc{}", i)))
+            .collect();
+
+        {
+            let wal = WalManager::new(&path, "test_batch").unwrap();
+            wal.write_insert(&single).unwrap();
+            wal.write_batch(&batch).unwrap();
+
+            let records = wal.read().unwrap();
+            let contents: Vec<&str> = records.iter().map(|r| live(r).content.as_str()).collect();
+            assert_eq!(contents, ["single", "doc0", "doc1", "doc2"]);
+        }
+
+        // Cut the batch record in half, as a crash in the middle of its write would
+        let frame_size = |payload: Vec<u8>| FRAME_HEADER_SIZE + payload.len();
+        let single_end = SEGMENT_HEADER_SIZE
+            + frame_size(bincode::serialize(&(Operation::Insert, &single)).unwrap());
+        let batch_size = frame_size(bincode::serialize(&(Operation::Batch, &batch)).unwrap());
+        let segment = segment_path(&path.join("wal"), "test_batch", 0);
+        let mut bytes = std::fs::read(&segment).unwrap();
+        bytes[single_end + batch_size / 2..].fill(0);
+        std::fs::write(&segment, &bytes).unwrap();
+
+        let wal = WalManager::new(&path, "test_batch")
+            .unwrap()
+            .with_recovery_policy(WalRecoveryPolicy::Strict);
+        assert!(matches!(wal.replay(0), Err(WalError::Corrupted(_))));
+
+        let wal = WalManager::new(&path, "test_batch").unwrap();
+        let (records, report) = wal.replay_with_report(0).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(live(&records[0]).content, "single");
+        assert_eq!(
+            report.torn_tails,
+            [TornTail {
+                segment: segment.clone(),
+                offset: single_end as u64,
+            }]
+        );
+        assert_eq!(
+            std::fs::metadata(&segment).unwrap().len(),
+            single_end as u64
+        );
+
+        // The tail is gone for good, even a strict recovery is happy now
+        let wal = WalManager::new(&path, "test_batch")
+            .unwrap()
+            .with_recovery_policy(WalRecoveryPolicy::Strict);
+        let (records, report) = wal.replay_with_report(0).unwrap();
+        assert_eq!(records.len(), 1);
+        assert!(report.is_clean());
+    }
+
+    #[test]
+    fn test_corruption_is_refused_or_skipped() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let path = dir.path().to_path_buf();
+        let documents: Vec<Document> = (0..3)
+            .map(|i| Document::new(vec![i as f32], format!("doc{}", i)))
+            .collect();
+        {
+            let wal = WalManager::new(&path, "test").unwrap();
+            for document in documents.iter() {
+                wal.write_insert(document).unwrap();
+            }
+        }
+
+        // Flip a byte in the payload of the second record
+        let frame_size = FRAME_HEADER_SIZE
+            + bincode::serialize(&(Operation::Insert, &documents[0]))
+                .unwrap()
+                .len();
+        let offset = SEGMENT_HEADER_SIZE + frame_size;
+        let segment = segment_path(&path.join("wal"), "test", 0);
+        let mut bytes = std::fs::read(&segment).unwrap();
+        bytes[offset + FRAME_HEADER_SIZE + 8] ^= 0xff;
+        std::fs::write(&segment, &bytes).unwrap();
+
+        for policy in [
+            WalRecoveryPolicy::Strict,
+            WalRecoveryPolicy::TolerateTornTail,
+        ] {
+            let wal = WalManager::new(&path, "test")
+                .unwrap()
+                .with_recovery_policy(policy);
+            assert!(matches!(wal.replay(0), Err(WalError::Corrupted(_))));
+        }
+
+        let wal = WalManager::new(&path, "test")
+            .unwrap()
+            .with_recovery_policy(WalRecoveryPolicy::SkipCorrupted);
+        let (records, report) = wal.replay_with_report(0).unwrap();
+        let contents: Vec<&str> = records.iter().map(|r| live(r).content.as_str()).collect();
+        assert_eq!(contents, ["doc0", "doc2"]);
+        assert!(report.torn_tails.is_empty());
+        assert_eq!(
+            report.skipped,
+            [SkippedRange {
+                segment: segment.clone(),
+                offset: offset as u64,
+                len: frame_size as u64,
+                records: Some(1),
+            }]
+        );
+
+        // A damaged header makes the whole segment unreadable
+        bytes[4] ^= 0xff;
+        std::fs::write(&segment, &bytes).unwrap();
+        let (records, report) = wal.replay_with_report(0).unwrap();
+        assert!(records.is_empty());
+        assert_eq!(report.skipped[0].records, None);
+    }
+
+    #[test]
+    fn test_group_commit_shares_fsyncs() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let path = dir.path().to_path_buf();
+        let (threads, writes) = (8, 25);
+
+        {
+            let mode = WalSyncMode::GroupCommit {
+                max_delay: Duration::from_millis(20),
+                max_batch: threads,
+            };
+            let wal = WalManager::with_sync_mode(&path, "test", mode).unwrap();
+            thread::scope(|scope| {
+                for t in 0..threads {
+                    let wal = &wal;
+                    scope.spawn(move || {
+                        for i in 0..writes {
+                            let doc = Document::new(vec![t as f32, i as f32], String::new());
+                            let lsn = wal.write_insert(&doc).unwrap();
+                            wal.wait_durable(lsn).unwrap();
+                            assert!(wal.shared.lock_durability().synced >= lsn);
+                        }
+                    });
+                }
+            });
+
+            assert_eq!(wal.read().unwrap().len(), threads * writes);
+            assert!(wal.sync_count() < (threads * writes) as u64 / 2);
+        }
+    }
+
+    #[test]
+    fn test_failed_sync_is_permanent() {
+        let group_commit = WalSyncMode::GroupCommit {
+            max_delay: Duration::from_millis(1),
+            max_batch: 4,
+        };
+        for sync_mode in [WalSyncMode::Always, group_commit] {
+            let dir = tempdir().expect("Failed to create temp dir");
+            let wal = WalManager::with_sync_mode(dir.path(), "test", sync_mode).unwrap();
+            let document = Document::new(vec![1.0], "doc".to_string());
+            let synced = wal.write_insert(&document).unwrap();
+            wal.wait_durable(synced).unwrap();
+            let lsn = wal.write_insert(&document).unwrap();
+
+            // The next fsync might succeed, it would not bring back what the failed one lost
+            wal.inject_failure("Input/output error");
+            assert!(wal.wait_durable(lsn).is_err());
+            wal.wait_durable(synced).unwrap();
+            assert!(wal.sync().is_err());
+            assert!(wal.rotate().is_err());
+            assert!(wal.write_insert(&document).is_err());
+            assert!(wal.failure().unwrap().contains("Input/output error"));
+        }
+    }
+
+    #[test]
+    fn test_interval_and_never_do_not_sync_on_write() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let path = dir.path().to_path_buf();
+
+        {
+            let wal = WalManager::with_sync_mode(&path, "never", WalSyncMode::Never).unwrap();
+            let lsn = wal
+                .write_insert(&Document::new(vec![1.0], String::new()))
+                .unwrap();
+            wal.wait_durable(lsn).unwrap();
+            assert_eq!(wal.sync_count(), 0);
+            // Leaving a segment always syncs it
+            wal.rotate().unwrap();
+            assert_eq!(wal.shared.lock_durability().synced, lsn);
+
+            let interval = WalSyncMode::Interval(Duration::from_millis(10));
+            let wal = WalManager::with_sync_mode(&path, "interval", interval).unwrap();
+            let lsn = wal
+                .write_insert(&Document::new(vec![1.0], String::new()))
+                .unwrap();
+            let start = Instant::now();
+            while wal.shared.lock_durability().synced < lsn {
+                assert!(start.elapsed() < Duration::from_secs(5));
+                thread::sleep(Duration::from_millis(5));
+            }
+        }
+    }
+
     #[test]
     fn test_rotate() {
         let path = get_test_path("./test_wal_rotate");
 
         {
-            let mut wal = WalManager::new(&path, "test_rotate").unwrap();
+            let wal = WalManager::new(&path, "test_rotate").unwrap();
 
             let doc1 = Document::new(vec![1.0], "doc1".to_string());
-            wal.write(Operation::Insert, &doc1).unwrap();
+            wal.write_insert(&doc1).unwrap();
 
             wal.rotate().unwrap();
 
             let doc2 = Document::new(vec![2.0], "doc2".to_string());
-            wal.write(Operation::Insert, &doc2).unwrap();
+            wal.write_insert(&doc2).unwrap();
 
             // After rotate, read() should read the new file (seq_no=1)
             let records = wal.read().unwrap();
             assert_eq!(records.len(), 1);
-            assert_eq!(records[0].1.content, "doc2");
+            assert_eq!(live(&records[0]).content, "doc2");
         }
 
         cleanup(&path);
     }
 
+    #[test]
+    fn test_reopen_resumes_seq_no_and_replays() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let path = dir.path().to_path_buf();
+
+        {
+            let wal = WalManager::new(&path, "test_replay").unwrap();
+            wal.write_insert(&Document::new(vec![1.0], "doc1".to_string()))
+                .unwrap();
+            wal.rotate().unwrap();
+            wal.write_insert(&Document::new(vec![2.0], "doc2".to_string()))
+                .unwrap();
+            wal.write_delete(&7).unwrap();
+        }
+
+        {
+            // A collection sharing the prefix must not be picked up
+            let _other = WalManager::new(&path, "test_replay_other").unwrap();
+
+            let wal = WalManager::new(&path, "test_replay").unwrap();
+            assert_eq!(wal.get_seq_no(), 2);
+
+            let records = wal.replay(0).unwrap();
+            assert_eq!(records.len(), 3);
+            assert_eq!(live(&records[0]).content, "doc1");
+            assert_eq!(live(&records[1]).content, "doc2");
+            assert!(matches!(records[2], Record::Tombstone(7)));
+
+            // Segment 0 is covered by an SST
+            let records = wal.replay(1).unwrap();
+            assert_eq!(records.len(), 2);
+            assert_eq!(live(&records[0]).content, "doc2");
+        }
+    }
+
+    #[test]
+    fn test_replays_segments_without_a_header() {
+        #[derive(Serialize)]
+        struct V0Document<'a> {
+            id: u128,
+            vector: &'a [f32],
+            content: &'a str,
+        }
+
+        let dir = tempdir().expect("Failed to create temp dir");
+        let path = dir.path().to_path_buf();
+        let wal_dir = path.join("wal");
+        std::fs::create_dir_all(&wal_dir).unwrap();
+
+        // Preallocated like the old writer did, reading stops at what does not decode
+        let mut bytes = Vec::new();
+        for (id, content) in [(1, "doc1"), (2, "doc2")] {
+            let document = V0Document {
+                id,
+                vector: &[id as f32],
+                content,
+            };
+            bincode::serialize_into(&mut bytes, &(Operation::Insert, &document)).unwrap();
+        }
+        let document = V0Document {
+            id: 1,
+            vector: &[],
+            content: "",
+        };
+        bincode::serialize_into(&mut bytes, &(Operation::Delete, &document)).unwrap();
+        bytes.extend_from_slice(&7u32.to_le_bytes());
+        bytes.resize(4096, 0);
+        std::fs::write(segment_path(&wal_dir, "test_legacy", 0), &bytes).unwrap();
+
+        for policy in [
+            WalRecoveryPolicy::Strict,
+            WalRecoveryPolicy::TolerateTornTail,
+            WalRecoveryPolicy::SkipCorrupted,
+        ] {
+            let wal = WalManager::new(&path, "test_legacy")
+                .unwrap()
+                .with_recovery_policy(policy);
+            assert_eq!(wal.get_seq_no(), 1);
+
+            let records = wal.replay(0).unwrap();
+            assert_eq!(records.len(), 3);
+            assert_eq!(live(&records[0]).id, 1);
+            assert_eq!(live(&records[0]).content, "doc1");
+            assert_eq!(live(&records[1]).vector, vec![2.0]);
+            assert!(live(&records[1]).payload.is_empty());
+            assert!(matches!(records[2], Record::Tombstone(1)));
+        }
+
+        // New records go to a segment of the current format next to it
+        {
+            let wal = WalManager::new(&path, "test_legacy").unwrap();
+            wal.write_insert(&Document::new(vec![3.0], "doc3".to_string()))
+                .unwrap();
+        }
+        let wal = WalManager::new(&path, "test_legacy").unwrap();
+        assert_eq!(wal.get_seq_no(), 2);
+        let records = wal.replay(0).unwrap();
+        assert_eq!(records.len(), 4);
+        assert_eq!(live(&records[3]).content, "doc3");
+        assert_eq!(
+            std::fs::read(segment_path(&wal_dir, "test_legacy", 0)).unwrap(),
+            bytes
+        );
+    }
+
+    #[test]
+    fn test_idle_reopens_reuse_the_empty_segment() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let path = dir.path().to_path_buf();
+        let segment_size = |seq_no| {
+            std::fs::metadata(segment_path(&path.join("wal"), "test_idle", seq_no))
+                .unwrap()
+                .len()
+        };
+
+        for _ in 0..3 {
+            let wal = WalManager::new(&path, "test_idle").unwrap();
+            assert_eq!(wal.get_seq_no(), 0);
+        }
+        let wal_dir = path.join("wal");
+        assert_eq!(list_segments(&wal_dir, "test_idle").unwrap(), vec![0]);
+        assert_eq!(segment_size(0), SEGMENT_HEADER_SIZE as u64);
+
+        {
+            let wal = WalManager::new(&path, "test_idle").unwrap();
+            wal.write_insert(&Document::new(vec![1.0], "doc1".to_string()))
+                .unwrap();
+        }
+        assert_eq!(segment_size(0), WAL_FILE_SIZE);
+
+        // A segment with records is never written to again
+        for _ in 0..3 {
+            let wal = WalManager::new(&path, "test_idle").unwrap();
+            assert_eq!(wal.get_seq_no(), 1);
+            assert_eq!(wal.replay(0).unwrap().len(), 1);
+        }
+        assert_eq!(list_segments(&wal_dir, "test_idle").unwrap(), vec![0, 1]);
+        assert_eq!(segment_size(1), SEGMENT_HEADER_SIZE as u64);
+    }
+
+    #[test]
+    fn test_remove_segments_below() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let path = dir.path().to_path_buf();
+
+        {
+            let wal = WalManager::new(&path, "test_remove").unwrap();
+            wal.rotate().unwrap();
+            wal.rotate().unwrap();
+            let _other = WalManager::new(&path, "test_remove_other").unwrap();
+
+            let wal_dir = wal.dir().to_path_buf();
+            assert_eq!(
+                remove_segments_below(&wal_dir, "test_remove", 2).unwrap(),
+                2
+            );
+            assert_eq!(list_segments(&wal_dir, "test_remove").unwrap(), vec![2]);
+            assert_eq!(
+                list_segments(&wal_dir, "test_remove_other").unwrap(),
+                vec![0]
+            );
+            assert_eq!(
+                remove_segments_below(&wal_dir, "test_remove", 2).unwrap(),
+                0
+            );
+        }
+    }
+
     #[test]
     fn test_performance() {
         let path = get_test_path("./test_wal_perf");
         let count = 100;
 
         {
-            let mut wal = WalManager::new(&path, "test_perf").unwrap();
+            let wal = WalManager::new(&path, "test_perf").unwrap();
             let doc = Document::new(vec![1.0, 2.0], "data".to_string());
 
             let start = std::time::Instant::now();
             for _ in 0..count {
-                wal.write(Operation::Insert, &doc).unwrap();
+                let lsn = wal.write_insert(&doc).unwrap();
+                wal.wait_durable(lsn).unwrap();
             }
             let duration = start.elapsed();
             println!(
@@ -201,22 +1206,18 @@ mod tests {
         let path = get_test_path("./test_wal_large");
 
         {
-            let mut wal = WalManager::new(&path, "test_large").unwrap();
+            let wal = WalManager::new(&path, "test_large").unwrap();
             // 70KB data to exceed 64KB buffer
             let large_data = vec![1.0; 17500];
             let doc = Document::new(large_data.clone(), "large_doc".to_string());
 
-            wal.write(Operation::Insert, &doc).unwrap();
+            wal.write_insert(&doc).unwrap();
 
             let records = wal.read().unwrap();
             assert_eq!(records.len(), 1);
-            match &records[0] {
-                (Operation::Insert, d) => {
-                    assert_eq!(d.vector.len(), 17500);
-                    assert_eq!(d.content, "large_doc");
-                }
-                _ => panic!("Expected Insert"),
-            }
+            let d = live(&records[0]);
+            assert_eq!(d.vector.len(), 17500);
+            assert_eq!(d.content, "large_doc");
         }
         cleanup(&path);
     }